@@ -4,9 +4,27 @@
 layout(local_size_x = 32, local_size_y = 32, local_size_z = 1) in;
 
 
-layout(set = 0, binding = 0, rgba8) uniform image2D current_image;
+// rgb is the sum of every frame so far, a is the number of frames summed
+layout(set = 0, binding = 0, rgba32f) uniform image2D current_image;
 
-layout(set = 0, binding = 1, rgba8) uniform image2D new_image;
+// rgb is the new frame, a is the distance to the first hit through the pixel centre when reprojecting
+layout(set = 0, binding = 1, rgba32f) uniform image2D new_image;
+
+// the accumulated image from the last frame, the two are swapped every frame so pixels can be read from anywhere
+layout(set = 0, binding = 2, rgba32f) uniform readonly image2D history_image;
+
+// the cameras of this frame and the last one, only read when reprojecting
+layout(set = 0, binding = 3) readonly buffer Reprojection {
+    vec4 cam_pos;
+    mat4 cam_alignment_mat;
+    vec4 prev_cam_pos;
+    mat4 prev_cam_alignment_mat;
+    vec2 sensor_offset;
+    // tan of half the vertical field of view
+    float half_height;
+    // frames of history kept while moving, so lighting seen from the old view fades out
+    uint max_history;
+} reprojection;
 
 
 
@@ -14,9 +32,35 @@ layout(push_constant) uniform PushConstants {
     uint frame;
     uint image_width;
     uint image_height;
+    // start accumulating again from this frame
+    bool reset;
+    bool reproject;
 }push_constants;
 
 
+// the pixel of the last frame that saw the same point, or -1 if it was off screen.
+// the new frame has a distance of -1 for pixels that look at the environment, which is reprojected by direction
+ivec2 previous_pixel(ivec2 pos, float dist) {
+    float aspect = float(push_constants.image_width) / float(push_constants.image_height);
+    vec2 ndc = (vec2(pos) + 0.5) / vec2(push_constants.image_width, push_constants.image_height) * 2.0 - 1.0;
+    float u = (ndc.x + reprojection.sensor_offset.x * 2.0) * aspect * reprojection.half_height;
+    float v = (ndc.y - reprojection.sensor_offset.y * 2.0) * reprojection.half_height;
+    vec3 dir = normalize(mat3(reprojection.cam_alignment_mat) * vec3(1.0, -v, -u));
+
+    // the alignment matrices are rotations, so their inverse is the transpose
+    mat3 to_prev = transpose(mat3(reprojection.prev_cam_alignment_mat));
+    vec3 local = (dist < 0.0)
+        ? to_prev * dir
+        : to_prev * (vec3(reprojection.cam_pos) + dir * dist - vec3(reprojection.prev_cam_pos));
+    if (local.x <= 0.0) {return ivec2(-1);}
+
+    float prev_u = -local.z / local.x;
+    float prev_v = -local.y / local.x;
+    vec2 prev_ndc = vec2(prev_u / (aspect * reprojection.half_height) - reprojection.sensor_offset.x * 2.0, prev_v / reprojection.half_height + reprojection.sensor_offset.y * 2.0);
+    ivec2 prev_pos = ivec2(floor((prev_ndc + 1.0) * 0.5 * vec2(push_constants.image_width, push_constants.image_height)));
+    if (any(lessThan(prev_pos, ivec2(0))) || prev_pos.x >= int(push_constants.image_width) || prev_pos.y >= int(push_constants.image_height)) {return ivec2(-1);}
+    return prev_pos;
+}
 
 
 void main() {
@@ -31,14 +75,31 @@ void main() {
     ivec2 pos = ivec2(x, y);
 
     if (push_constants.frame == 0) {
-        imageStore(current_image, pos, vec4(0, 0, 0, 1));
+        imageStore(current_image, pos, vec4(0));
         return;
     }
 
-    vec3 prev_col = imageLoad(current_image, pos).xyz;
-    vec3 new_col = imageLoad(new_image, pos).xyz;
-    vec3 col = (new_col + prev_col * push_constants.frame) / (push_constants.frame + 1);
+    vec4 new_frame = imageLoad(new_image, pos);
+    vec3 new_col = new_frame.xyz;
 
+    if (push_constants.reset) {
+        imageStore(current_image, pos, vec4(new_col, 1));
+        return;
+    }
+
+    if (push_constants.reproject) {
+        ivec2 prev_pos = previous_pixel(pos, new_frame.w);
+        if (prev_pos.x < 0) {
+            imageStore(current_image, pos, vec4(new_col, 1));
+            return;
+        }
+        vec4 history = imageLoad(history_image, prev_pos);
+        float kept = min(history.w, float(reprojection.max_history));
+        vec3 kept_sum = (history.w > 0.0) ? history.xyz * (kept / history.w) : vec3(0);
+        imageStore(current_image, pos, vec4(kept_sum + new_col, kept + 1));
+        return;
+    }
 
-    imageStore(current_image, pos, vec4(col, 1));
-}
\ No newline at end of file
+    vec4 sum = imageLoad(history_image, pos);
+    imageStore(current_image, pos, vec4(sum.xyz + new_col, sum.w + 1));
+}
@@ -0,0 +1,123 @@
+#version 460
+#define TONE_MAP_CLAMP 0
+#define TONE_MAP_REINHARD 1
+#define TONE_MAP_EXTENDED_REINHARD 2
+#define TONE_MAP_ACES 3
+#define TONE_MAP_AGX 4
+
+
+layout(local_size_x = 32, local_size_y = 32, local_size_z = 1) in;
+
+
+// rgb is the sum of every frame so far, a is the number of frames summed
+layout(set = 0, binding = 0, rgba32f) uniform readonly image2D accumulated_image;
+
+layout(set = 0, binding = 1, rgba8) uniform writeonly image2D display_image;
+
+
+
+layout(push_constant) uniform PushConstants {
+    float exposure; // in stops
+    float white_point;
+    uint tone_map;
+    uint image_width;
+    uint image_height;
+}push_constants;
+
+
+/// TONE MAPPING OPERATORS
+
+vec3 reinhard(vec3 col) {
+    return col / (1.0 + col);
+}
+
+vec3 extended_reinhard(vec3 col, float white_point) {
+    return col * (1.0 + col / (white_point * white_point)) / (1.0 + col);
+}
+
+// ACES fit by Stephen Hill, https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+vec3 aces(vec3 col) {
+    const mat3 aces_input = mat3(
+        0.59719, 0.07600, 0.02840,
+        0.35458, 0.90834, 0.13383,
+        0.04823, 0.01566, 0.83777
+    );
+    const mat3 aces_output = mat3(
+        1.60475, -0.10208, -0.00327,
+        -0.53108, 1.10813, -0.07276,
+        -0.07367, -0.00605, 1.07602
+    );
+
+    col = aces_input * col;
+    vec3 a = col * (col + 0.0245786) - 0.000090537;
+    vec3 b = col * (0.983729 * col + 0.4329510) + 0.238081;
+    return aces_output * (a / b);
+}
+
+// minimal AgX by Benjamin Wrensch, https://iolite-engine.com/blog_posts/minimal_agx_implementation
+vec3 agx(vec3 col) {
+    const mat3 agx_input = mat3(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104
+    );
+    const mat3 agx_output = mat3(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116
+    );
+    const float min_ev = -12.47393;
+    const float max_ev = 4.026069;
+
+    col = agx_input * max(col, vec3(1e-10));
+    col = (clamp(log2(col), min_ev, max_ev) - min_ev) / (max_ev - min_ev);
+
+    // sigmoid contrast curve
+    vec3 x2 = col * col;
+    vec3 x4 = x2 * x2;
+    col = 15.5 * x4 * x2 - 40.14 * x4 * col + 31.96 * x4 - 6.868 * x2 * col + 0.4298 * x2 + 0.1191 * col - 0.00232;
+
+    // the curve outputs display values, take them back to linear so every operator is encoded the same way
+    return pow(max(agx_output * col, vec3(0)), vec3(2.2));
+}
+
+
+vec3 linear_to_srgb(vec3 col) {
+    vec3 low = col * 12.92;
+    vec3 high = 1.055 * pow(col, vec3(1.0 / 2.4)) - 0.055;
+    return mix(low, high, step(vec3(0.0031308), col));
+}
+
+
+void main() {
+
+    uint x = gl_GlobalInvocationID.x;
+    uint y = gl_GlobalInvocationID.y;
+
+    if (x >= push_constants.image_width || y >= push_constants.image_height) {
+        return;
+    }
+
+    ivec2 pos = ivec2(x, y);
+
+    vec4 sum = imageLoad(accumulated_image, pos);
+    vec3 col = sum.xyz / max(sum.w, 1.0) * exp2(push_constants.exposure);
+
+    switch (int(push_constants.tone_map)) {
+        case TONE_MAP_REINHARD:
+            col = reinhard(col);
+            break;
+        case TONE_MAP_EXTENDED_REINHARD:
+            col = extended_reinhard(col, push_constants.white_point);
+            break;
+        case TONE_MAP_ACES:
+            col = aces(col);
+            break;
+        case TONE_MAP_AGX:
+            col = agx(col);
+            break;
+    }
+
+    col = linear_to_srgb(clamp(col, 0.0, 1.0));
+    imageStore(display_image, pos, vec4(col, 1));
+}
@@ -4,6 +4,20 @@
 #define M_PI 3.1415926535897932384626433832795
 #define UINT_MAX 4294967295.0
 #define INVIS_FLAG 1.0
+#define DIELECTRIC_FLAG 2.0
+#define PBR_FLAG 3.0
+#define SAMPLE_BSDF 0u
+#define SAMPLE_LIGHTS 1u
+#define SAMPLE_MIS 2u
+#define ENVIRONMENT_NONE 0u
+#define ENVIRONMENT_GRADIENT 1u
+#define ENVIRONMENT_MAP 2u
+#define ENVIRONMENT_SKY 3u
+#define PROJECTION_PERSPECTIVE 0u
+#define PROJECTION_ORTHOGRAPHIC 1u
+#define PROJECTION_FISHEYE 2u
+#define PROJECTION_EQUIRECTANGULAR 3u
+#define BVH_STACK_SIZE 32
 
 layout(local_size_x = 32, local_size_y = 32, local_size_z = 1) in;
 
@@ -51,23 +65,20 @@ vec3 RandomPointOnHemisphere(inout uint state, vec3 normal) {
 struct RayTracingMaterial {
     vec4 colour;
     vec4 emission; /// vec3 colour, float strength
-    vec4 settings; // specular probability, metalic, fuzz, flag
+    vec4 settings; // specular probability, metalic, fuzz, flag. dielectrics store their index of refraction in x, pbr materials (metallic, roughness, specular, flag)
+    ivec4 textures; // albedo, roughness or smoothness, emission and normal map indices into textures, -1 for none
 };
 
 RayTracingMaterial empty_mat() {
     return RayTracingMaterial (
         vec4(0, 0, 0, 1),
         vec4(0),
-        vec4(0)
+        vec4(0),
+        ivec4(-1)
     );
 }
 
 
-struct Ray {
-    vec4 sample_centre; // relative to line (1, 0, 0)
-};
-
-
 struct Sphere {
     vec3 centre;
     float radius;
@@ -81,15 +92,50 @@ struct Triangle {
     vec4 edge_one; // edge one (b - a)
     vec4 edge_two; // edge two (c - a)
     vec4 normal; // normal (edge_one x edge_two) - DO NOT NORMALISE, normalising scales down the triangles
+    // unit vertex normals, interpolated for smooth shading
+    vec4 normal_a;
+    vec4 normal_b;
+    vec4 normal_c;
+    vec4 uv_ab; // texture coordinates of a in xy and b in zw
+    vec4 uv_c; // texture coordinates of c in xy
+    vec4 tangent; // direction u increases in, w is the sign that turns normal x tangent into the direction v increases in. zero without texture coordinates
 };
 
 
+// triangles and bvh nodes are in object space and shared by every instance of a mesh
 struct Mesh {
-    vec3 min_point;
-    uint first_index;
+    vec3 min_point; // world space bounds
+    uint root_node; // index of the mesh's bvh root in bvh_nodes
     vec3 max_point;
     uint len;
     RayTracingMaterial material;
+    mat4 object_to_world;
+    mat4 world_to_object;
+};
+
+
+// children are stored next to each other, the right child is at left_first + 1
+struct BvhNode {
+    vec3 min_point;
+    uint left_first; // left child for interior nodes, first triangle for leaves
+    vec3 max_point;
+    uint count; // number of triangles, 0 for interior nodes
+};
+
+
+// an emissive sphere or mesh, mesh lights cover triangles first_tri to first_tri + num_tris
+struct Light {
+    uint object; // numbered like top_level_objects
+    uint first_tri;
+    uint num_tris;
+};
+
+
+// an image packed into texels, row by row from the top
+struct Texture {
+    uint offset; // index of the first texel
+    uint width;
+    uint height;
 };
 
 
@@ -98,6 +144,9 @@ struct RayHit {
     vec3 hit_pos;
     float hit_dist;
     RayTracingMaterial hit_mat;
+    uint object; // numbered like top_level_objects
+    uint tri; // triangle hit for meshes
+    vec2 uv; // texture coordinates of the hit
 };
 
 
@@ -106,19 +155,18 @@ RayHit empty_hit() {
         vec3(0),
         vec3(0),
         FLT_MAX,
-        empty_mat()
+        empty_mat(),
+        0,
+        0,
+        vec2(0)
     );
 }
 
 
 /// BUFFERS
 
-layout(set = 0, binding = 0, rgba8) uniform image2D img;
-
+layout(set = 0, binding = 0, rgba32f) uniform image2D img;
 
-layout(set = 0, binding = 1) buffer Rays {
-    Ray[] rays;
-};
 
 layout(set = 0, binding = 2) buffer Spheres {
     Sphere[] spheres;
@@ -132,24 +180,88 @@ layout(set = 0, binding = 4) buffer Meshes {
     Mesh[] meshes;
 };
 
-layout(push_constant) uniform PushConstants {
-    vec4 cam_pos;
-    mat4 cam_alignment_mat;
+layout(set = 0, binding = 5) buffer BvhNodes {
+    BvhNode[] bvh_nodes;
+};
+
+// bvh over every object, leaves index into top_level_objects
+layout(set = 0, binding = 6) buffer TopLevelNodes {
+    BvhNode[] top_level_nodes;
+};
 
-    int num_rays;
+// spheres are numbered first, then meshes
+layout(set = 0, binding = 7) buffer TopLevelObjects {
+    uint[] top_level_objects;
+};
+
+layout(set = 0, binding = 8) buffer Lights {
+    Light[] lights;
+};
+
+// settings that stay the same for every frame
+layout(set = 0, binding = 9) readonly buffer RenderSettings {
     int num_spheres;
     int num_meshes;
+    int num_lights;
 
     int num_samples;
+    // on the image plane one unit in front of the camera, negative for half a pixel
     float jitter_size;
     int max_bounces;
-    bool use_environment_light;
-    uint rng_offset;
+    uint environment;
+    uint sampling_mode;
+
+    // size is 0 when there is no environment map
+    uint env_width;
+    uint env_height;
+    float env_rotation;
+    float env_intensity;
+
+    // preetham sky, w of sun_dir is the cos of the sun's angular radius
+    vec4 sun_dir;
+    vec4 sun_radiance;
+    vec4 sky_perez[5];
+    vec4 sky_zenith;
+} render_settings;
+
+// equirectangular, read with texelFetch so lookups match the pixels the cdf was built from
+layout(set = 0, binding = 10) uniform sampler2D environment_map;
+
+// cdf over the rows of the environment map followed by a cdf over each row
+layout(set = 0, binding = 11) readonly buffer EnvironmentCdf {
+    float[] env_cdf;
+};
+
+layout(set = 0, binding = 12) readonly buffer Textures {
+    Texture[] textures;
+};
+
+// every texture's rgba8 texels, unpacked with unpackUnorm4x8
+layout(set = 0, binding = 13) readonly buffer Texels {
+    uint[] texels;
+};
+
+layout(push_constant) uniform PushConstants {
+    vec4 cam_pos;
+    mat4 cam_alignment_mat;
 
+    uint rng_offset;
     bool init;
     uint width;
     uint height;
 
+    // radius of the lens, 0 for a pinhole camera
+    float aperture_radius;
+    // distance along the camera direction to the plane that is in focus
+    float focus_distance;
+    // shift of the image as a fraction of its width and height
+    vec2 sensor_offset;
+    // half the height of the image plane, the tan of half the fov for perspective, world units for orthographic
+    // and radians for fisheye and equirectangular
+    float half_height;
+    uint projection;
+    // put the distance to the first hit through the pixel centre in the alpha of the image, for reprojection
+    bool write_depth;
 } push_constants;
 
 
@@ -159,13 +271,88 @@ vec3 ray_at(vec3 root_pos, vec3 dir, float dist) {
     return root_pos + dir * dist;
 }
 
-vec3 get_ray_dir(vec3 sample_centre, inout uint state) {
+// the centre of a pixel on the image plane one unit in front of the camera, looking along (1, 0, 0) with y up
+vec3 get_sample_centre(uint x, uint y) {
+    float aspect = float(push_constants.width) / float(push_constants.height);
+    float u = ((float(x) + 0.5) / float(push_constants.width) * 2.0 - 1.0 + push_constants.sensor_offset.x * 2.0) * aspect * push_constants.half_height;
+    float v = ((float(y) + 0.5) / float(push_constants.height) * 2.0 - 1.0 - push_constants.sensor_offset.y * 2.0) * push_constants.half_height;
+    // image x goes along -z and image y goes down along -y
+    return vec3(1.0, -v, -u);
+}
+
+float jitter_size() {
+    if (render_settings.jitter_size < 0.0) {return push_constants.half_height / float(push_constants.height);}
+    return render_settings.jitter_size;
+}
+
+// turns a point on the image plane into a camera space ray, see get_sample_centre.
+// returns false for points outside what the projection covers, like the corners of a fisheye image
+bool project(vec3 point, out vec3 offset, out vec3 dir) {
+    offset = vec3(0);
+    switch (push_constants.projection) {
+        case PROJECTION_ORTHOGRAPHIC:
+            offset = vec3(0, point.y, point.z);
+            dir = vec3(1, 0, 0);
+            return true;
+        case PROJECTION_FISHEYE: {
+            // the angle from the camera direction is the distance from the centre of the image
+            float angle = length(point.yz);
+            if (angle > M_PI) {return false;}
+            vec2 across = (angle > 0.0) ? point.yz / angle * sin(angle) : vec2(0);
+            dir = vec3(cos(angle), across);
+            return true;
+        }
+        case PROJECTION_EQUIRECTANGULAR: {
+            // longitude goes right along -z and latitude goes up along y
+            float longitude = -point.z;
+            float latitude = point.y;
+            dir = vec3(cos(latitude) * cos(longitude), sin(latitude), -cos(latitude) * sin(longitude));
+            return true;
+        }
+        default:
+            dir = point;
+            return true;
+    }
+}
+
+// the ray through a random point within the jitter size of the sample centre, false if the projection misses it
+bool get_ray(vec3 sample_centre, out vec3 pos, out vec3 dir, inout uint state) {
+    float jitter = jitter_size();
     float random = scaleToRange01(hash(state)) * 2 * M_PI;
-    vec3 new_centre = sample_centre + cos(random) * vec3(0, 0, 1) * push_constants.jitter_size * sqrt(scaleToRange01(hash(state))) + sin(random) * vec3(0, 1, 0) * push_constants.jitter_size * sqrt(scaleToRange01(hash(state)));
-    return normalize(mat3(push_constants.cam_alignment_mat) * new_centre);
+    vec3 new_centre = sample_centre + cos(random) * vec3(0, 0, 1) * jitter * sqrt(scaleToRange01(hash(state))) + sin(random) * vec3(0, 1, 0) * jitter * sqrt(scaleToRange01(hash(state)));
+
+    vec3 offset;
+    bool hit = project(new_centre, offset, dir);
+    mat3 cam = mat3(push_constants.cam_alignment_mat);
+    pos = vec3(push_constants.cam_pos) + cam * offset;
+    dir = normalize(cam * dir);
+    return hit;
+}
+
+// moves the ray's start to a random point on the lens, aimed at the point the pinhole ray meets the focus plane.
+// fisheye and equirectangular rays can point sideways or backwards, so they focus on a sphere around the camera instead.
+// a pinhole camera uses no random numbers so its images don't change
+void thin_lens(inout vec3 pos, inout vec3 dir, inout uint state) {
+    if (push_constants.aperture_radius <= 0.0) {return;}
+
+    mat3 cam = mat3(push_constants.cam_alignment_mat);
+    vec3 forward = normalize(cam * vec3(1, 0, 0));
+    bool wide = push_constants.projection == PROJECTION_FISHEYE || push_constants.projection == PROJECTION_EQUIRECTANGULAR;
+    float focus_dist = wide ? push_constants.focus_distance : push_constants.focus_distance / dot(dir, forward);
+    vec3 focus_point = ray_at(pos, dir, focus_dist);
+
+    float angle = scaleToRange01(hash(state)) * 2 * M_PI;
+    float radius = push_constants.aperture_radius * sqrt(scaleToRange01(hash(state)));
+    pos += cam * vec3(0, sin(angle) * radius, cos(angle) * radius);
+    dir = normalize(focus_point - pos);
 }
 
 
+// equirectangular texture coordinates of a point on a sphere, matching the layout of environment maps
+vec2 sphere_uv(vec3 normal) {
+    return vec2(atan(normal.z, normal.x) / (2.0 * M_PI) + 0.5, acos(clamp(normal.y, -1.0, 1.0)) / M_PI);
+}
+
 RayHit intersecting_sphere(Sphere s, vec3 root_pos, vec3 dir) {
     vec3 l = root_pos - s.centre;
     
@@ -177,122 +364,412 @@ RayHit intersecting_sphere(Sphere s, vec3 root_pos, vec3 dir) {
     if (discriminant >= 0) {
 
         float dist = (-half_b - sqrt(discriminant)) / a;
+        // rays inside a dielectric sphere leave through the far side
+        if (dist <= 0.001 && s.material.settings.w == DIELECTRIC_FLAG) {
+            dist = (-half_b + sqrt(discriminant)) / a;
+        }
         vec3 pos = ray_at(root_pos, dir, dist);
+        vec3 normal = normalize(pos - s.centre);
         return RayHit(
-            normalize(pos - s.centre),
+            normal,
             pos,
             dist,
-            s.material
+            s.material,
+            0,
+            0,
+            sphere_uv(normal)
         );
     } else {
         return empty_hit();
     }
 }
 
-bool intersecting_aabb(vec3 min_point, vec3 max_point, vec3 root_pos, vec3 dir) {
-    vec3 inv_dir = vec3(1) / dir;
-    float d_max;
-    float d_min;
-
-    d_max = (((inv_dir.x < 0) ? min_point.x : max_point.x) - root_pos.x) * inv_dir.x;
-    d_min = (((inv_dir.x < 0) ? max_point.x : min_point.x) - root_pos.x) * inv_dir.x;
-    if (d_max > 0 || d_min > 0) {return true;}
+// distances along the ray to where it enters and leaves the box, the entry is clamped to 0 for rays starting inside.
+// the ray misses when the entry is further than the exit
+vec2 intersecting_aabb(vec3 min_point, vec3 max_point, vec3 root_pos, vec3 inv_dir) {
+    float d_min = 0;
+    float d_max = FLT_MAX;
+
+    for (int axis = 0; axis < 3; axis++) {
+        if (isinf(inv_dir[axis])) {
+            // parallel to the slab, the ray is either always between the planes or never
+            if (root_pos[axis] < min_point[axis] || root_pos[axis] > max_point[axis]) {return vec2(FLT_MAX, 0);}
+            continue;
+        }
 
-    d_max = max(d_max, (((inv_dir.y < 0) ? min_point.y : max_point.y) - root_pos.y) * inv_dir.y );
-    d_min = min(d_max, (((inv_dir.y < 0) ? max_point.y : min_point.y) - root_pos.y) * inv_dir.y );
-    if (d_max > 0 || d_min > 0) {return true;}
+        float d_one = (min_point[axis] - root_pos[axis]) * inv_dir[axis];
+        float d_two = (max_point[axis] - root_pos[axis]) * inv_dir[axis];
+        d_min = max(d_min, min(d_one, d_two));
+        d_max = min(d_max, max(d_one, d_two));
+    }
 
-    d_max = max(d_max, (((inv_dir.z < 0) ? min_point.z : max_point.z) - root_pos.z) * inv_dir.z );
-    d_min = max(d_min, (((inv_dir.z < 0) ? max_point.z : min_point.z) - root_pos.z) * inv_dir.z );
-    if (d_max > 0 || d_min > 0) {return true;}
+    return vec2(d_min, d_max);
+}
 
-    return false;
+// distance to where the ray enters the node, FLT_MAX if it misses
+float bvh_node_distance(BvhNode node, vec3 root_pos, vec3 inv_dir) {
+    vec2 dists = intersecting_aabb(node.min_point, node.max_point, root_pos, inv_dir);
+    return (dists.x <= dists.y) ? dists.x : FLT_MAX;
 }
 
-// (hit_normal, hit dist)
-vec4 intersecting_tri(Triangle t, vec3 root_pos, vec3 dir) {
+// (hit_normal, hit dist), the normal always faces out of the front face
+// (hit distance, u, v) where u and v are the barycentric weights of the second and third points
+vec3 intersecting_tri(Triangle t, vec3 root_pos, vec3 dir, bool cull_back_faces) {
 
     vec3 normal = vec3(t.normal);
 
-    if (dot(dir, normal) >= 0) {
-        return vec4(0, 0, 0, FLT_MAX);
+    if (cull_back_faces && dot(dir, normal) >= 0) {
+        return vec3(FLT_MAX, 0, 0);
     }
 
     vec3 ao = root_pos - vec3(t.a);
     vec3 dao = cross(ao, dir);
 
     float det = -dot(dir, normal);
-    if (det == 0) {return vec4(FLT_MAX);}
+    if (det == 0) {return vec3(FLT_MAX, 0, 0);}
 
     float inv_det = 1 / det;
     float dist = dot(ao, normal) * inv_det;
-    if (dist < 0) {return vec4(FLT_MAX);}
+    if (dist < 0) {return vec3(FLT_MAX, 0, 0);}
 
     float u = dot(vec3(t.edge_two), dao) * inv_det;
-    if (u < 0) {return vec4(FLT_MAX);}
+    if (u < 0) {return vec3(FLT_MAX, 0, 0);}
 
     float v = -dot(vec3(t.edge_one), dao) * inv_det;
-    if (v < 0) {return vec4(FLT_MAX);}
+    if (v < 0) {return vec3(FLT_MAX, 0, 0);}
 
     float w = 1 - u - v;
-    if (w < 0) {return vec4(FLT_MAX);}
+    if (w < 0) {return vec3(FLT_MAX, 0, 0);}
+
+    return vec3(dist, u, v);
+}
+
+// object space normals are transformed by the inverse transpose so they stay perpendicular to scaled surfaces
+vec3 normal_to_world(Mesh m, vec3 normal) {
+    return normalize(transpose(mat3(m.world_to_object)) * normal);
+}
+
+// edge_one x edge_two in world space, its length is twice the world space area
+vec3 world_face_normal(Mesh m, Triangle t) {
+    return cross(mat3(m.object_to_world) * vec3(t.edge_one), mat3(m.object_to_world) * vec3(t.edge_two));
+}
+
+// vertex normals interpolated across the triangle, kept on the side of the front face so
+// back face hits on dielectrics are still recognised
+vec3 shading_normal(Triangle t, float u, float v) {
+    vec3 face_normal = normalize(vec3(t.normal));
+    vec3 normal = vec3(t.normal_a) * (1.0 - u - v) + vec3(t.normal_b) * u + vec3(t.normal_c) * v;
+    if (dot(normal, face_normal) <= 0.0) {return face_normal;}
+    return normalize(normal);
+}
 
-    return vec4(normalize(normal), dist);
+// texture coordinates interpolated across the triangle
+vec2 tri_uv(Triangle t, float u, float v) {
+    return t.uv_ab.xy * (1.0 - u - v) + t.uv_ab.zw * u + t.uv_c.xy * v;
 }
 
-RayHit intersecting_mesh(Mesh m, vec3 root_pos, vec3 dir) {
+// only hits closer than max_dist are returned
+RayHit intersecting_mesh(Mesh m, vec3 root_pos, vec3 dir, float max_dist) {
+
+    if (m.len == 0) {return empty_hit();}
+
+    // skip meshes that are missed or further away than the closest hit so far
+    vec2 box_dists = intersecting_aabb(m.min_point, m.max_point, root_pos, vec3(1) / dir);
+    if (box_dists.x > box_dists.y || box_dists.x >= max_dist) {return empty_hit();}
+
+    // the object space direction is left unnormalised so distances along it are the same as in world space
+    vec3 object_pos = vec3(m.world_to_object * vec4(root_pos, 1.0));
+    vec3 object_dir = mat3(m.world_to_object) * dir;
+    vec3 inv_dir = vec3(1) / object_dir;
+
+    vec3 closest = vec3(max_dist, 0, 0);
+    uint closest_tri = 0;
+    // rays have to be able to leave dielectric meshes through their back faces
+    bool cull_back_faces = m.material.settings.w != DIELECTRIC_FLAG;
 
-    if (!intersecting_aabb(m.min_point, m.max_point, root_pos, dir)) {return empty_hit();}
+    uint stack[BVH_STACK_SIZE];
+    uint stack_size = 0;
+    stack[stack_size++] = m.root_node;
 
-    vec4 closest = vec4(FLT_MAX);
+    while (stack_size > 0) {
+        BvhNode node = bvh_nodes[stack[--stack_size]];
+        if (bvh_node_distance(node, object_pos, inv_dir) >= closest.x) {continue;}
 
-    for (uint i = 0; i < m.len; i++) {
-        vec4 hit_info = intersecting_tri(triangles[i + m.first_index], root_pos, dir);
-        if (hit_info.w > 0.001 && hit_info.w < closest.w) {
-            closest = hit_info;
+        if (node.count > 0) {
+            for (uint i = node.left_first; i < node.left_first + node.count; i++) {
+                vec3 hit_info = intersecting_tri(triangles[i], object_pos, object_dir, cull_back_faces);
+                if (hit_info.x > 0.001 && hit_info.x < closest.x) {
+                    closest = hit_info;
+                    closest_tri = i;
+                }
+            }
+            continue;
+        }
+
+        // push the further child first so the nearer one is visited first
+        uint near = node.left_first;
+        uint far = node.left_first + 1;
+        float near_dist = bvh_node_distance(bvh_nodes[near], object_pos, inv_dir);
+        float far_dist = bvh_node_distance(bvh_nodes[far], object_pos, inv_dir);
+        if (far_dist < near_dist) {
+            uint temp = near;
+            near = far;
+            far = temp;
+            float temp_dist = near_dist;
+            near_dist = far_dist;
+            far_dist = temp_dist;
         }
+        if (far_dist < closest.x && stack_size < BVH_STACK_SIZE) {stack[stack_size++] = far;}
+        if (near_dist < closest.x && stack_size < BVH_STACK_SIZE) {stack[stack_size++] = near;}
     }
 
-    if (closest.w == FLT_MAX) {return empty_hit();}
+    if (closest.x >= max_dist) {return empty_hit();}
 
+    Triangle t = triangles[closest_tri];
     return RayHit(
-        vec3(closest),
-        ray_at(root_pos, dir, closest.w),
-        closest.w,
-        m.material
+        normal_to_world(m, shading_normal(t, closest.y, closest.z)),
+        ray_at(root_pos, dir, closest.x),
+        closest.x,
+        m.material,
+        0,
+        closest_tri,
+        tri_uv(t, closest.y, closest.z)
     );
 }
 
 
 RayHit world_hit(vec3 root_pos, vec3 dir) {
     RayHit closest = empty_hit();
+    if (render_settings.num_spheres + render_settings.num_meshes == 0) {return closest;}
 
-    // check spheres
-    for (int i = 0; i < push_constants.num_spheres; i++) {
-        RayHit hit_info = intersecting_sphere(spheres[i], root_pos, dir);
-        if (hit_info.hit_dist > 0.001 && hit_info.hit_dist < closest.hit_dist) {
-            closest = hit_info;
+    vec3 inv_dir = vec3(1) / dir;
+    uint stack[BVH_STACK_SIZE];
+    uint stack_size = 0;
+    stack[stack_size++] = 0;
+
+    while (stack_size > 0) {
+        BvhNode node = top_level_nodes[stack[--stack_size]];
+        if (bvh_node_distance(node, root_pos, inv_dir) >= closest.hit_dist) {continue;}
+
+        if (node.count > 0) {
+            for (uint i = node.left_first; i < node.left_first + node.count; i++) {
+                uint object = top_level_objects[i];
+                uint num_spheres = uint(render_settings.num_spheres);
+                RayHit hit_info;
+                if (object < num_spheres) {
+                    hit_info = intersecting_sphere(spheres[object], root_pos, dir);
+                } else {
+                    hit_info = intersecting_mesh(meshes[object - num_spheres], root_pos, dir, closest.hit_dist);
+                }
+                if (hit_info.hit_dist > 0.001 && hit_info.hit_dist < closest.hit_dist) {
+                    closest = hit_info;
+                    closest.object = object;
+                }
+            }
+            continue;
         }
-    }
 
-    // check meshes
-    for (int i = 0; i < push_constants.num_meshes; i++) {
-        RayHit hit_info = intersecting_mesh(meshes[i], root_pos, dir);
-        if (hit_info.hit_dist > 0.001 && hit_info.hit_dist < closest.hit_dist) {
-            closest = hit_info;
+        // push the further child first so the nearer one is visited first
+        uint near = node.left_first;
+        uint far = node.left_first + 1;
+        float near_dist = bvh_node_distance(top_level_nodes[near], root_pos, inv_dir);
+        float far_dist = bvh_node_distance(top_level_nodes[far], root_pos, inv_dir);
+        if (far_dist < near_dist) {
+            uint temp = near;
+            near = far;
+            far = temp;
+            float temp_dist = near_dist;
+            near_dist = far_dist;
+            far_dist = temp_dist;
         }
+        if (far_dist < closest.hit_dist && stack_size < BVH_STACK_SIZE) {stack[stack_size++] = far;}
+        if (near_dist < closest.hit_dist && stack_size < BVH_STACK_SIZE) {stack[stack_size++] = near;}
     }
-   
 
     return closest;
 }
 
+/// TEXTURES
+
+vec3 srgb_to_linear(vec3 col) {
+    return mix(col / 12.92, pow((col + 0.055) / 1.055, vec3(2.4)), greaterThan(col, vec3(0.04045)));
+}
+
+// x and y are at most one texel outside the image and wrap around to the other side
+vec4 texture_texel(Texture t, int x, int y, bool srgb) {
+    uint wrapped_x = uint(x + int(t.width)) % t.width;
+    uint wrapped_y = uint(y + int(t.height)) % t.height;
+    vec4 texel = unpackUnorm4x8(texels[t.offset + wrapped_y * t.width + wrapped_x]);
+    if (srgb) {texel.rgb = srgb_to_linear(texel.rgb);}
+    return texel;
+}
+
+// bilinear filtered and repeating, colour textures are stored in srgb and converted before filtering
+vec4 sample_texture(int index, vec2 uv, bool srgb) {
+    Texture t = textures[index];
+    vec2 pos = fract(uv) * vec2(t.width, t.height) - 0.5;
+    vec2 base = floor(pos);
+    vec2 f = pos - base;
+    int x = int(base.x);
+    int y = int(base.y);
+
+    vec4 top = mix(texture_texel(t, x, y, srgb), texture_texel(t, x + 1, y, srgb), f.x);
+    vec4 bottom = mix(texture_texel(t, x, y + 1, srgb), texture_texel(t, x + 1, y + 1, srgb), f.x);
+    return mix(top, bottom, f.y);
+}
+
+// the material at uv with its albedo, roughness and emission textures multiplied in.
+// roughness textures use the gltf layout, green scales roughness or smoothness and blue scales metallic
+RayTracingMaterial textured_material(RayTracingMaterial mat, vec2 uv) {
+    if (mat.textures.x >= 0) {
+        mat.colour.rgb *= sample_texture(mat.textures.x, uv, true).rgb;
+    }
+    if (mat.textures.y >= 0) {
+        vec4 texel = sample_texture(mat.textures.y, uv, false);
+        mat.settings.y *= texel.g;
+        if (mat.settings.w == PBR_FLAG) {mat.settings.x *= texel.b;}
+    }
+    if (mat.textures.z >= 0) {
+        mat.emission.rgb *= sample_texture(mat.textures.z, uv, true).rgb;
+    }
+    return mat;
+}
+
+// textures the material of a hit and bends the normal of triangles with a tangent space normal map
+void apply_textures(inout RayHit hit) {
+    hit.hit_mat = textured_material(hit.hit_mat, hit.uv);
+    if (hit.hit_mat.textures.w < 0 || hit.object < uint(render_settings.num_spheres)) {return;}
+
+    Mesh m = meshes[hit.object - uint(render_settings.num_spheres)];
+    Triangle t = triangles[hit.tri];
+    vec3 normal = hit.hit_normal;
+    vec3 world_tangent = mat3(m.object_to_world) * vec3(t.tangent);
+    vec3 tangent = world_tangent - normal * dot(normal, world_tangent);
+    if (t.tangent.w == 0.0 || dot(tangent, tangent) <= 0.0) {return;}
+    tangent = normalize(tangent);
+    vec3 bitangent = cross(normal, tangent) * t.tangent.w;
+
+    vec3 mapped = vec3(sample_texture(hit.hit_mat.textures.w, hit.uv, false)) * 2.0 - 1.0;
+    vec3 mapped_normal = normalize(tangent * mapped.x + bitangent * mapped.y + normal * mapped.z);
+    // kept on the side of the front face like the interpolated normal
+    if (dot(mapped_normal, normal_to_world(m, vec3(t.normal))) > 0.0) {hit.hit_normal = mapped_normal;}
+}
+
+/// ENVIRONMENT
+
+// whether light sampling can pick directions from the environment, the whole map or the sun of the sky
+bool sampled_environment() {
+    return render_settings.environment == ENVIRONMENT_MAP || render_settings.environment == ENVIRONMENT_SKY;
+}
+
+vec2 environment_uv(vec3 dir) {
+    float phi = atan(dir.z, dir.x) + render_settings.env_rotation;
+    return vec2(fract(phi / (2.0 * M_PI) + 0.5), acos(clamp(dir.y, -1.0, 1.0)) / M_PI);
+}
+
+vec3 environment_dir(vec2 uv) {
+    float phi = (uv.x - 0.5) * 2.0 * M_PI - render_settings.env_rotation;
+    float theta = uv.y * M_PI;
+    return vec3(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+}
+
+uvec2 environment_texel(vec2 uv) {
+    return uvec2(
+        min(uint(uv.x * render_settings.env_width), render_settings.env_width - 1),
+        min(uint(uv.y * render_settings.env_height), render_settings.env_height - 1)
+    );
+}
+
+vec3 environment_map_light(vec3 dir) {
+    return vec3(texelFetch(environment_map, ivec2(environment_texel(environment_uv(dir))), 0)) * render_settings.env_intensity;
+}
+
+// solid angle pdf of sample_environment_map picking dir
+float environment_map_pdf(vec3 dir) {
+    vec2 uv = environment_uv(dir);
+    uvec2 texel = environment_texel(uv);
+    float sin_theta = sin(uv.y * M_PI);
+    if (sin_theta <= 0.0) {return 0.0;}
+
+    uint width = render_settings.env_width;
+    uint height = render_settings.env_height;
+    float row_prob = env_cdf[texel.y] - ((texel.y > 0) ? env_cdf[texel.y - 1] : 0.0);
+    uint row_start = height + texel.y * width;
+    float pixel_prob = env_cdf[row_start + texel.x] - ((texel.x > 0) ? env_cdf[row_start + texel.x - 1] : 0.0);
+    return row_prob * pixel_prob * width * height / (2.0 * M_PI * M_PI * sin_theta);
+}
+
+// first index in the len values from start that is above u
+uint search_cdf(uint start, uint len, float u) {
+    uint low = 0;
+    uint high = len - 1;
+    while (low < high) {
+        uint mid = (low + high) / 2;
+        if (env_cdf[start + mid] > u) {high = mid;}
+        else {low = mid + 1;}
+    }
+    return low;
+}
+
+// picks a direction in proportion to the brightness of the map, returns (direction, solid angle pdf)
+vec4 sample_environment_map(inout uint state) {
+    uint width = render_settings.env_width;
+    uint height = render_settings.env_height;
+    uint row = search_cdf(0, height, scaleToRange01(hash(state)));
+    uint column = search_cdf(height + row * width, width, scaleToRange01(hash(state)));
+
+    float u = (column + scaleToRange01(hash(state))) / width;
+    float v = (row + scaleToRange01(hash(state))) / height;
+    vec3 dir = environment_dir(vec2(u, v));
+    return vec4(dir, environment_map_pdf(dir));
+}
+
+// perez et al's sky luminance distribution for Y, x and y
+vec3 perez(float cos_theta, float gamma, float cos_gamma) {
+    vec3 a = vec3(render_settings.sky_perez[0]);
+    vec3 b = vec3(render_settings.sky_perez[1]);
+    vec3 c = vec3(render_settings.sky_perez[2]);
+    vec3 d = vec3(render_settings.sky_perez[3]);
+    vec3 e = vec3(render_settings.sky_perez[4]);
+    return (1.0 + a * exp(b / cos_theta)) * (1.0 + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+}
+
+vec3 sky_light(vec3 dir) {
+    // the model only covers the upper half, the horizon colour is carried on below it
+    float cos_theta = max(dir.y, 0.001);
+    float cos_gamma = clamp(dot(dir, vec3(render_settings.sun_dir)), -1.0, 1.0);
+    vec3 yxy = vec3(render_settings.sky_zenith) * perez(cos_theta, acos(cos_gamma), cos_gamma);
+
+    vec3 xyz = vec3(yxy.y / yxy.z * yxy.x, yxy.x, (1.0 - yxy.y - yxy.z) / yxy.z * yxy.x);
+    vec3 rgb = mat3(
+        3.2406, -0.9689, 0.0557,
+        -1.5372, 1.8758, -0.2040,
+        -0.4986, 0.0415, 1.0570
+    ) * xyz;
+    return max(rgb, vec3(0));
+}
+
+vec3 sun_light(vec3 dir) {
+    if (dot(dir, vec3(render_settings.sun_dir)) < render_settings.sun_dir.w) {return vec3(0);}
+    return vec3(render_settings.sun_radiance);
+}
+
+
 vec3 environment_light(vec3 dir) {
-    if (!push_constants.use_environment_light) {return vec3(0);}
+    if (render_settings.environment == ENVIRONMENT_MAP) {return environment_map_light(dir);}
+    if (render_settings.environment == ENVIRONMENT_SKY) {return sky_light(dir) + sun_light(dir);}
+    if (render_settings.environment == ENVIRONMENT_NONE) {return vec3(0);}
     float a = 0.5*(dir.y + 1.0);
     return (1.0-a)*vec3(1.0) + a*vec3(0.5, 0.7, 1.0);
 }
 
+// the part of the environment light that light sampling can find
+vec3 sampled_environment_light(vec3 dir) {
+    if (render_settings.environment == ENVIRONMENT_MAP) {return environment_map_light(dir);}
+    if (render_settings.environment == ENVIRONMENT_SKY) {return sun_light(dir);}
+    return vec3(0);
+}
+
 
 vec3 adjust_dir(vec3 dir, vec3 normal, RayTracingMaterial mat, bool specular, inout uint state) {
 
@@ -305,18 +782,305 @@ vec3 adjust_dir(vec3 dir, vec3 normal, RayTracingMaterial mat, bool specular, in
 }
 
 
+// exact fresnel reflectance of unpolarised light, eta is the incident index of refraction over the transmitted one
+float fresnel(float cos_i, float eta) {
+    float sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if (sin2_t >= 1.0) {return 1.0;} // total internal reflection
+
+    float cos_t = sqrt(1.0 - sin2_t);
+    float r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    float r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return 0.5 * (r_s * r_s + r_p * r_p);
+}
+
+// reflects or refracts through a dielectric surface, picking between them by the fresnel reflectance
+vec3 dielectric_dir(vec3 dir, vec3 normal, float ior, inout uint state) {
+    bool front_face = dot(dir, normal) < 0;
+    vec3 facing_normal = front_face ? normal : -normal;
+    float eta = front_face ? 1.0 / ior : ior;
+
+    float cos_i = min(-dot(dir, facing_normal), 1.0);
+    float reflectance = fresnel(cos_i, eta);
+    if (reflectance >= 1.0 || scaleToRange01(hash(state)) < reflectance) {
+        return reflect(dir, facing_normal);
+    }
+    return normalize(refract(dir, facing_normal, eta));
+}
+
+
+/// MICROFACET BRDF
+
+float luminance(vec3 col) {
+    return dot(col, vec3(0.2126, 0.7152, 0.0722));
+}
+
+vec3 fresnel_schlick(vec3 f0, float cos_theta) {
+    return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+float ggx_distribution(float n_dot_h, float alpha) {
+    float a2 = alpha * alpha;
+    float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (M_PI * d * d);
+}
+
+// smith masking for one direction, the masking-shadowing term is the product for the view and light directions
+float smith_g1(float n_dot_x, float alpha) {
+    float a2 = alpha * alpha;
+    return 2.0 * n_dot_x / (n_dot_x + sqrt(a2 + (1.0 - a2) * n_dot_x * n_dot_x));
+}
+
+// basis with the given direction as z
+mat3 basis_around(vec3 z) {
+    vec3 x = normalize(cross(abs(z.x) > 0.9 ? vec3(0, 1, 0) : vec3(1, 0, 0), z));
+    return mat3(x, cross(z, x), z);
+}
+
+// microfacet normal sampled in proportion to the ggx distribution
+vec3 ggx_half_vector(vec3 normal, float alpha, inout uint state) {
+    float u = scaleToRange01(hash(state));
+    float phi = 2.0 * M_PI * scaleToRange01(hash(state));
+    float cos_theta = sqrt((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u));
+    float sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+
+    return normalize(basis_around(normal) * vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta));
+}
+
+// chance of sampling the specular lobe, roughly how much of the light it reflects
+float pbr_specular_prob(RayTracingMaterial mat, float n_dot_v) {
+    vec3 f0 = mix(vec3(0.08 * mat.settings.z), vec3(mat.colour), mat.settings.x);
+    float specular_amount = luminance(fresnel_schlick(f0, n_dot_v));
+    float diffuse_amount = luminance(vec3(mat.colour) * (1.0 - mat.settings.x)) * (1.0 - specular_amount);
+    return clamp(specular_amount / max(specular_amount + diffuse_amount, 0.000001), 0.1, 1.0);
+}
+
+// (brdf * cos, pdf of pbr_dir picking new_dir) of the metallic roughness brdf, 0 if either direction is below the surface
+vec4 pbr_eval(vec3 dir, vec3 normal, RayTracingMaterial mat, vec3 new_dir) {
+    vec3 base_colour = vec3(mat.colour);
+    float metallic = mat.settings.x;
+    float alpha = max(mat.settings.y * mat.settings.y, 0.001);
+    vec3 f0 = mix(vec3(0.08 * mat.settings.z), base_colour, metallic);
+    vec3 diffuse_colour = base_colour * (1.0 - metallic);
+
+    vec3 view = -dir;
+    float n_dot_v = dot(normal, view);
+    float n_dot_l = dot(normal, new_dir);
+    if (n_dot_v <= 0.0 || n_dot_l <= 0.0) {return vec4(0);}
+
+    vec3 half_vector = normalize(view + new_dir);
+    float n_dot_h = max(dot(normal, half_vector), 0.0);
+    float v_dot_h = max(dot(view, half_vector), 0.0);
+
+    float d = ggx_distribution(n_dot_h, alpha);
+    vec3 f = fresnel_schlick(f0, v_dot_h);
+    float g = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+    vec3 brdf = diffuse_colour * (1.0 - f) / M_PI + f * d * g / (4.0 * n_dot_v * n_dot_l);
+
+    // pdf of picking new_dir through either lobe
+    float specular_prob = pbr_specular_prob(mat, n_dot_v);
+    float pdf = specular_prob * d * n_dot_h / (4.0 * max(v_dot_h, 0.000001)) + (1.0 - specular_prob) * n_dot_l / M_PI;
+    return vec4(brdf * n_dot_l, pdf);
+}
+
+// samples the metallic roughness brdf, either the ggx specular lobe or the diffuse lobe.
+// weight is set to brdf * cos / pdf, so 0 when the new direction goes below the surface
+vec3 pbr_dir(vec3 dir, vec3 normal, RayTracingMaterial mat, out vec3 weight, out float pdf, inout uint state) {
+    weight = vec3(0);
+    pdf = 0;
+    float n_dot_v = -dot(normal, dir);
+    if (n_dot_v <= 0.0) {return reflect(dir, normal);}
+
+    vec3 new_dir;
+    if (scaleToRange01(hash(state)) < pbr_specular_prob(mat, n_dot_v)) {
+        new_dir = reflect(dir, ggx_half_vector(normal, max(mat.settings.y * mat.settings.y, 0.001), state));
+    } else {
+        new_dir = normalize(normal + RandomPointOnUnitSphere(state));
+    }
+
+    vec4 brdf_pdf = pbr_eval(dir, normal, mat, new_dir);
+    pdf = brdf_pdf.w;
+    weight = vec3(brdf_pdf) / max(pdf, 0.000001);
+    return new_dir;
+}
+
+// (brdf * cos, pdf) for the materials that lights can be sampled from, pbr and lambertian
+vec4 eval_bsdf(vec3 dir, vec3 normal, RayTracingMaterial mat, vec3 new_dir) {
+    if (mat.settings.w == PBR_FLAG) {return pbr_eval(dir, normal, mat, new_dir);}
+
+    float n_dot_l = dot(normal, new_dir);
+    if (n_dot_l <= 0.0) {return vec4(0);}
+    return vec4(vec3(mat.colour) * n_dot_l / M_PI, n_dot_l / M_PI);
+}
+
+
+/// LIGHT SAMPLING
+
+float power_heuristic(float pdf, float other_pdf) {
+    float a = pdf * pdf;
+    return a / (a + other_pdf * other_pdf);
+}
+
+// 1 - cos of the half angle of the cone the sphere covers seen from pos, 0 if pos is inside.
+// written this way to keep precision for small or far lights
+float sphere_cone_size(Sphere s, vec3 pos) {
+    vec3 to_centre = s.centre - pos;
+    float dist2 = dot(to_centre, to_centre);
+    float radius2 = s.radius * s.radius;
+    if (dist2 <= radius2) {return 0;}
+
+    float cos_max = sqrt(1.0 - radius2 / dist2);
+    return radius2 / dist2 / (1.0 + cos_max);
+}
+
+// picks a point on the part of the sphere facing pos, returns (direction, solid angle pdf)
+vec4 sample_sphere_light(Sphere s, vec3 pos, inout uint state) {
+    float one_minus_cos_max = sphere_cone_size(s, pos);
+    if (one_minus_cos_max <= 0) {return vec4(0);}
+
+    // uniform over the cone
+    float cos_theta = 1.0 - scaleToRange01(hash(state)) * one_minus_cos_max;
+    float sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    float phi = 2.0 * M_PI * scaleToRange01(hash(state));
+
+    vec3 dir = basis_around(normalize(s.centre - pos)) * vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+    return vec4(normalize(dir), 1.0 / (2.0 * M_PI * one_minus_cos_max));
+}
+
+// solid angle pdf of picking a point on a mesh light triangle at dist along dir,
+// each of the num_tris triangles is picked with the same probability
+float mesh_light_pdf(vec3 face_normal, uint num_tris, vec3 dir, float dist) {
+    // triangles only emit from their front face
+    float area = length(face_normal) * 0.5;
+    float cos_light = -dot(dir, normalize(face_normal));
+    if (cos_light <= 0 || area == 0) {return 0;}
+    return dist * dist / (cos_light * area * float(num_tris));
+}
+
+// picks a point on a random triangle of the mesh, returns (direction, solid angle pdf) and sets dist to the point
+vec4 sample_mesh_light(Light l, Mesh m, vec3 pos, out float dist, out vec2 uv, inout uint state) {
+    uint tri_index = l.first_tri + min(uint(scaleToRange01(hash(state)) * l.num_tris), l.num_tris - 1);
+    Triangle t = triangles[tri_index];
+
+    float root = sqrt(scaleToRange01(hash(state)));
+    float v = scaleToRange01(hash(state));
+    vec3 object_point = vec3(t.a) + vec3(t.edge_one) * root * (1.0 - v) + vec3(t.edge_two) * root * v;
+    vec3 point = vec3(m.object_to_world * vec4(object_point, 1.0));
+    uv = tri_uv(t, root * (1.0 - v), root * v);
+
+    dist = length(point - pos);
+    vec3 dir = (point - pos) / dist;
+    return vec4(dir, mesh_light_pdf(world_face_normal(m, t), l.num_tris, dir, dist));
+}
+
+// uniform over the cone of the sun disk, returns (direction, solid angle pdf)
+vec4 sample_sun(inout uint state) {
+    float one_minus_cos_max = 1.0 - render_settings.sun_dir.w;
+    float cos_theta = 1.0 - scaleToRange01(hash(state)) * one_minus_cos_max;
+    float sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    float phi = 2.0 * M_PI * scaleToRange01(hash(state));
+
+    vec3 dir = basis_around(vec3(render_settings.sun_dir)) * vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+    return vec4(normalize(dir), 1.0 / (2.0 * M_PI * one_minus_cos_max));
+}
+
+vec4 sample_environment(inout uint state) {
+    if (render_settings.environment == ENVIRONMENT_MAP) {return sample_environment_map(state);}
+    return sample_sun(state);
+}
+
+// solid angle pdf of sample_environment picking dir
+float environment_pdf(vec3 dir) {
+    if (render_settings.environment == ENVIRONMENT_MAP) {return environment_map_pdf(dir);}
+    if (dot(dir, vec3(render_settings.sun_dir)) < render_settings.sun_dir.w) {return 0.0;}
+    return 1.0 / (2.0 * M_PI * (1.0 - render_settings.sun_dir.w));
+}
+
+// the emitters and, if it can be sampled, the environment
+uint num_light_choices() {
+    return uint(render_settings.num_lights) + uint(sampled_environment());
+}
+
+// pdf of light sampling choosing the direction that reached an emitter from pos, including the choice of light
+float light_pdf(vec3 pos, vec3 dir, RayHit hit) {
+    uint num_spheres = uint(render_settings.num_spheres);
+    float pdf;
+    if (hit.object < num_spheres) {
+        float one_minus_cos_max = sphere_cone_size(spheres[hit.object], pos);
+        pdf = (one_minus_cos_max > 0) ? 1.0 / (2.0 * M_PI * one_minus_cos_max) : 0;
+    } else {
+        Mesh m = meshes[hit.object - num_spheres];
+        pdf = mesh_light_pdf(world_face_normal(m, triangles[hit.tri]), m.len, dir, hit.hit_dist);
+    }
+    return pdf / num_light_choices();
+}
+
+// light arriving directly from one randomly chosen emitter and reflected along -dir by the surface.
+// with mis the sample is weighted against the bsdf sampling the same direction
+vec3 sample_lights(vec3 pos, vec3 normal, vec3 dir, RayTracingMaterial surface_mat, bool use_mis, inout uint state) {
+    uint num_choices = num_light_choices();
+    if (num_choices == 0) {return vec3(0);}
+
+    uint light_index = min(uint(scaleToRange01(hash(state)) * num_choices), num_choices - 1);
+    uint num_spheres = uint(render_settings.num_spheres);
+
+    vec4 dir_pdf;
+    float dist;
+    vec3 emitted_light;
+    if (light_index == uint(render_settings.num_lights)) {
+        // the environment is the one choice past the emitters
+        dir_pdf = sample_environment(state);
+        dist = FLT_MAX;
+        emitted_light = sampled_environment_light(vec3(dir_pdf));
+    } else {
+        Light l = lights[light_index];
+        RayTracingMaterial mat;
+        vec2 uv;
+        if (l.object < num_spheres) {
+            Sphere s = spheres[l.object];
+            dir_pdf = sample_sphere_light(s, pos, state);
+            RayHit light_hit = intersecting_sphere(s, pos, vec3(dir_pdf));
+            dist = light_hit.hit_dist;
+            uv = light_hit.uv;
+            mat = s.material;
+        } else {
+            Mesh m = meshes[l.object - num_spheres];
+            dir_pdf = sample_mesh_light(l, m, pos, dist, uv, state);
+            mat = m.material;
+        }
+        mat = textured_material(mat, uv);
+        emitted_light = vec3(mat.emission) * mat.emission.w;
+    }
+
+    vec3 light_dir = vec3(dir_pdf);
+    float pdf = dir_pdf.w / num_choices;
+    vec4 brdf_pdf = eval_bsdf(dir, normal, surface_mat, light_dir);
+    if (pdf <= 0 || brdf_pdf.w <= 0) {return vec3(0);}
+
+    // anything closer than the light blocks it
+    RayHit shadow = world_hit(pos, light_dir);
+    if (shadow.hit_dist < dist * 0.999) {return vec3(0);}
+
+    float mis_weight = use_mis ? power_heuristic(pdf, brdf_pdf.w) : 1.0;
+    return emitted_light * vec3(brdf_pdf) / pdf * mis_weight;
+}
+
+
 vec3 trace_ray(vec3 root_pos, vec3 dir, inout uint state) {
     vec3 light = vec3(0);
     vec3 colour = vec3(1);
     bool has_not_hit_visible_object = true;
 
+    // pdf the last bounce chose the ray with, 0 when lights can't be sampled from it
+    float last_bsdf_pdf = 0;
+    vec3 last_pos = root_pos;
+
     vec3 ray_pos = root_pos;
     vec3 ray_dir = dir;
 
-    for (int i = 0; i <= push_constants.max_bounces; i++) {
+    for (int i = 0; i <= render_settings.max_bounces; i++) {
         RayHit hit = world_hit(ray_pos, ray_dir);
         if (hit.hit_dist < FLT_MAX) {
-            
+            apply_textures(hit);
 
             ray_pos = hit.hit_pos + float(hit.hit_mat.settings.w == INVIS_FLAG) * 0.001;
             
@@ -326,13 +1090,39 @@ vec3 trace_ray(vec3 root_pos, vec3 dir, inout uint state) {
             } else {has_not_hit_visible_object = false;}
             
 
-            bool is_specular = scaleToRange01(hash(state)) < hit.hit_mat.settings.x;
-            ray_dir = adjust_dir(ray_dir, hit.hit_normal, hit.hit_mat, is_specular, state);
-            
-
+            // emitters found by a bounce that also sampled the lights are weighted against, or replaced by, that sample
             vec3 emitted_light = vec3(hit.hit_mat.emission) * hit.hit_mat.emission.w;
+            // light sampling never reaches the back of a mesh light or the inside of a sphere light, so those are kept
+            if (last_bsdf_pdf > 0 && hit.hit_mat.emission.w > 0 && render_settings.sampling_mode != SAMPLE_BSDF) {
+                float emitter_pdf = light_pdf(last_pos, ray_dir, hit);
+                if (render_settings.sampling_mode == SAMPLE_LIGHTS && emitter_pdf > 0) {emitted_light = vec3(0);}
+                if (render_settings.sampling_mode == SAMPLE_MIS) {emitted_light *= power_heuristic(last_bsdf_pdf, emitter_pdf);}
+            }
             light += emitted_light * colour;
-            colour *= vec3(hit.hit_mat.colour);
+
+
+            vec3 in_dir = ray_dir;
+            vec3 weight = vec3(hit.hit_mat.colour);
+            float bsdf_pdf = 0;
+            if (hit.hit_mat.settings.w == DIELECTRIC_FLAG) {
+                ray_dir = dielectric_dir(ray_dir, hit.hit_normal, hit.hit_mat.settings.x, state);
+                ray_pos = hit.hit_pos + ray_dir * 0.001;
+            } else if (hit.hit_mat.settings.w == PBR_FLAG) {
+                ray_dir = pbr_dir(ray_dir, hit.hit_normal, hit.hit_mat, weight, bsdf_pdf, state);
+            } else {
+                bool is_specular = scaleToRange01(hash(state)) < hit.hit_mat.settings.x;
+                ray_dir = adjust_dir(ray_dir, hit.hit_normal, hit.hit_mat, is_specular, state);
+                // the fuzzy metal model has no known pdf, only purely lambertian bounces can sample lights
+                bool is_diffuse = hit.hit_mat.settings.z == 0 && (!is_specular || hit.hit_mat.settings.y == 0);
+                if (is_diffuse) {bsdf_pdf = max(dot(hit.hit_normal, ray_dir), 0.000001) / M_PI;}
+            }
+
+            if (bsdf_pdf > 0 && render_settings.sampling_mode != SAMPLE_BSDF) {
+                light += sample_lights(hit.hit_pos, hit.hit_normal, in_dir, hit.hit_mat, render_settings.sampling_mode == SAMPLE_MIS, state) * colour;
+            }
+            last_bsdf_pdf = bsdf_pdf;
+            last_pos = hit.hit_pos;
+            colour *= weight;
 
             float p = max(colour.x, max(colour.y, colour.z));
             if (scaleToRange01(hash(state)) >= p) {
@@ -341,14 +1131,22 @@ vec3 trace_ray(vec3 root_pos, vec3 dir, inout uint state) {
             colour /= p;
         }
         else {
-            light += environment_light(ray_dir);
+            // the part of the environment that light sampling can find is weighted the same way as emitters
+            vec3 env_light = environment_light(ray_dir);
+            if (last_bsdf_pdf > 0 && sampled_environment()) {
+                float weight = 1.0;
+                if (render_settings.sampling_mode == SAMPLE_LIGHTS) {weight = 0.0;}
+                if (render_settings.sampling_mode == SAMPLE_MIS) {weight = power_heuristic(last_bsdf_pdf, environment_pdf(ray_dir) / num_light_choices());}
+                env_light -= sampled_environment_light(ray_dir) * (1.0 - weight);
+            }
+            light += env_light * colour;
             break;
         }
     }
 
     // light = environment_light(ray_dir);
 
-    return light * colour;
+    return light;
 }
 
 
@@ -374,16 +1172,28 @@ void main() {
     // }
 
     uint id = x + y * push_constants.width;
+    vec3 sample_centre = get_sample_centre(x, y);
 
     vec3 colour = vec3(0);
     uint state = push_constants.rng_offset * 719393 + id;
-    for (int i = 0; i < push_constants.num_samples; i++) {
+    for (int i = 0; i < render_settings.num_samples; i++) {
         
-        vec3 dir = get_ray_dir(vec3(rays[id].sample_centre), state);
+        vec3 pos;
+        vec3 dir;
+        if (!get_ray(sample_centre, pos, dir, state)) {continue;}
+        thin_lens(pos, dir, state);
 
-        colour += trace_ray(vec3(push_constants.cam_pos), normalize(dir), state);
+        colour += trace_ray(pos, normalize(dir), state);
     }
 
-    colour /= push_constants.num_samples;
-    imageStore(img, ivec2(x, y), vec4(colour, 1.0));
+    colour /= render_settings.num_samples;
+
+    // -1 for pixels that look at the environment
+    float depth = 1.0;
+    if (push_constants.write_depth) {
+        vec3 dir = normalize(mat3(push_constants.cam_alignment_mat) * sample_centre);
+        RayHit hit = world_hit(vec3(push_constants.cam_pos), dir);
+        depth = (hit.hit_dist < FLT_MAX) ? hit.hit_dist : -1.0;
+    }
+    imageStore(img, ivec2(x, y), vec4(colour, depth));
 }
\ No newline at end of file
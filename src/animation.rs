@@ -0,0 +1,449 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::Deserialize;
+use graphics::Camera;
+use super::raytrace_pipeline::Projection;
+
+
+/// Errors produced while loading a camera path file
+#[derive(Debug)]
+pub enum CameraPathError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for CameraPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CameraPathError::Io(path, e) => write!(f, "could not read camera path {}: {e}", path.display()),
+            CameraPathError::Parse(path, e) => write!(f, "could not parse camera path {}: {e}", path.display()),
+            CameraPathError::Invalid(msg) => write!(f, "invalid camera path: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for CameraPathError {}
+
+
+/// How the camera moves between keyframes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    /// straight at a constant speed from one keyframe to the next
+    Linear,
+    /// a smooth curve through every keyframe with no sudden changes of speed at them
+    CatmullRom,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "linear" => Ok(Interpolation::Linear),
+            "catmull_rom" | "smooth" => Ok(Interpolation::CatmullRom),
+            _ => Err(format!("unknown interpolation {name}, expected linear or catmull_rom"))
+        }
+    }
+}
+
+
+/// Where the camera is at a point in time
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keyframe {
+    /// seconds
+    pub time: f32,
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    /// vertical, in degrees. the scene's fov is used when this isn't given
+    pub fov: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraPathFile {
+    #[serde(default = "default_frame_rate")]
+    frame_rate: f32,
+    #[serde(default = "default_interpolation")]
+    interpolation: String,
+    keyframes: Vec<Keyframe>,
+}
+
+fn default_frame_rate() -> f32 {
+    24.0
+}
+
+fn default_interpolation() -> String {
+    "catmull_rom".to_string()
+}
+
+
+/// A camera moving through keyframes, sampled at a fixed frame rate from the first keyframe to the last
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+    pub interpolation: Interpolation,
+    pub frame_rate: f32,
+}
+
+impl CameraPath {
+    /// load and validate a camera path from a toml file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CameraPathError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| CameraPathError::Io(path.to_path_buf(), e))?;
+        let file: CameraPathFile = toml::from_str(&text).map_err(|e| CameraPathError::Parse(path.to_path_buf(), e))?;
+
+        let interpolation = file.interpolation.parse().map_err(CameraPathError::Invalid)?;
+        if file.frame_rate <= 0.0 {
+            return Err(CameraPathError::Invalid("frame_rate must be positive".to_string()));
+        }
+        if file.keyframes.is_empty() {
+            return Err(CameraPathError::Invalid("a camera path needs at least one keyframe".to_string()));
+        }
+
+        let mut keyframes = file.keyframes;
+        for i in 0..keyframes.len() {
+            if i > 0 && keyframes[i].time <= keyframes[i - 1].time {
+                return Err(CameraPathError::Invalid(format!("keyframe {i} must come after keyframe {}", i - 1)));
+            }
+            if keyframes[i].fov.map_or(false, |fov| fov <= 0.0) {
+                return Err(CameraPathError::Invalid(format!("keyframe {i} needs a positive fov")));
+            }
+            // so that every keyframe has the same weight when directions are blended
+            keyframes[i].direction = normalised(keyframes[i].direction)
+                .ok_or(CameraPathError::Invalid(format!("keyframe {i} has no direction")))?;
+            // blending opposite directions gives no direction at all halfway between them
+            if i > 0 && dot(keyframes[i].direction, keyframes[i - 1].direction) < -0.999 {
+                return Err(CameraPathError::Invalid(format!(
+                    "keyframe {i} turns the camera all the way around from keyframe {}, add a keyframe between them", i - 1
+                )));
+            }
+        }
+
+        Ok(CameraPath {
+            keyframes,
+            interpolation,
+            frame_rate: file.frame_rate,
+        })
+    }
+
+    /// checks every keyframe's fov can be used with the projection
+    pub fn check_fov(&self, projection: Projection) -> Result<(), String> {
+        for (i, keyframe) in self.keyframes.iter().enumerate() {
+            if let Some(fov) = keyframe.fov {
+                projection.check_fov(fov).map_err(|e| format!("keyframe {i}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the number of frames from the first keyframe to the last, including both
+    pub fn num_frames(&self) -> usize {
+        let duration = self.keyframes[self.keyframes.len() - 1].time - self.keyframes[0].time;
+        // a little slack so a path lasting a whole number of frames still ends on its last keyframe
+        (duration * self.frame_rate + 1e-3).floor() as usize + 1
+    }
+
+    /// the time of a frame in seconds
+    pub fn frame_time(&self, frame: usize) -> f32 {
+        self.keyframes[0].time + frame as f32 / self.frame_rate
+    }
+
+    /// the camera and its vertical fov at a time, keyframes without a fov use default_fov.
+    /// times before the first keyframe or after the last hold the camera there
+    pub fn camera_at(
+        &self,
+        time: f32,
+        default_fov: f32
+    ) -> (Camera, f32) {
+        let position = self.interpolate(time, |keyframe| keyframe.position);
+        (Camera::new(Some(position), Some(self.direction_at(time)), None, None), self.fov_at(time, default_fov))
+    }
+
+    /// the normalised camera direction at a time
+    fn direction_at(&self, time: f32) -> [f32; 3] {
+        // a curve can still pass close to no direction between keyframes that turn a long way,
+        // the camera keeps looking the way the last keyframe did then
+        normalised(self.interpolate(time, |keyframe| keyframe.direction)).unwrap_or_else(|| {
+            self.keyframes.iter().rev().find(|keyframe| keyframe.time <= time).unwrap_or(&self.keyframes[0]).direction
+        })
+    }
+
+    /// the vertical fov at a time, kept within the fovs of the keyframes so that catmull rom curves
+    /// can't overshoot to one the projection can't use
+    fn fov_at(&self, time: f32, default_fov: f32) -> f32 {
+        let fovs = self.keyframes.iter().map(|keyframe| keyframe.fov.unwrap_or(default_fov));
+        let (min_fov, max_fov) = fovs.fold((f32::MAX, f32::MIN), |(min, max), fov| (min.min(fov), max.max(fov)));
+        let [fov] = self.interpolate(time, |keyframe| [keyframe.fov.unwrap_or(default_fov)]);
+        fov.clamp(min_fov, max_fov)
+    }
+
+    fn interpolate<const N: usize>(
+        &self,
+        time: f32,
+        value: impl Fn(&Keyframe) -> [f32; N]
+    ) -> [f32; N] {
+        let keyframes = &self.keyframes;
+        if time <= keyframes[0].time {
+            return value(&keyframes[0]);
+        }
+        // the keyframes either side of the time are k and k + 1
+        let Some(k) = (0..keyframes.len() - 1).find(|&k| time <= keyframes[k + 1].time) else {
+            return value(&keyframes[keyframes.len() - 1]);
+        };
+
+        let length = keyframes[k + 1].time - keyframes[k].time;
+        let s = (time - keyframes[k].time) / length;
+        let (start, end) = (value(&keyframes[k]), value(&keyframes[k + 1]));
+
+        match self.interpolation {
+            Interpolation::Linear => std::array::from_fn(|i| start[i] + (end[i] - start[i]) * s),
+            Interpolation::CatmullRom => {
+                // a cubic hermite curve with the catmull rom tangents, scaled by the length of the segment
+                // so that the speed doesn't jump at keyframes that aren't evenly spaced in time
+                let (start_tangent, end_tangent) = (self.tangent(k, &value), self.tangent(k + 1, &value));
+                let (s2, s3) = (s * s, s * s * s);
+                std::array::from_fn(|i| {
+                    (2.0 * s3 - 3.0 * s2 + 1.0) * start[i]
+                        + (s3 - 2.0 * s2 + s) * length * start_tangent[i]
+                        + (-2.0 * s3 + 3.0 * s2) * end[i]
+                        + (s3 - s2) * length * end_tangent[i]
+                })
+            }
+        }
+    }
+
+    /// the rate of change of a value at keyframe k, from the keyframes either side of it.
+    /// the first and last keyframes only have one neighbour so use the slope towards it
+    fn tangent<const N: usize>(
+        &self,
+        k: usize,
+        value: &impl Fn(&Keyframe) -> [f32; N]
+    ) -> [f32; N] {
+        let before = &self.keyframes[k.saturating_sub(1)];
+        let after = &self.keyframes[(k + 1).min(self.keyframes.len() - 1)];
+        let (before_value, after_value) = (value(before), value(after));
+        std::array::from_fn(|i| (after_value[i] - before_value[i]) / (after.time - before.time))
+    }
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// None for vectors too short to have a direction
+fn normalised(v: [f32; 3]) -> Option<[f32; 3]> {
+    let length = dot(v, v).sqrt();
+    if length < 1e-6 {return None;}
+    Some([v[0] / length, v[1] / length, v[2] / length])
+}
+
+
+/// where a frame of an animation is saved in the output directory
+pub fn frame_path(dir: &Path, frame: usize) -> PathBuf {
+    dir.join(format!("frame_{frame:04}.png"))
+}
+
+/// the first frame that hasn't been saved. frames are only given their name once they are complete,
+/// so an interrupted render starts again from here
+pub fn first_unfinished_frame(dir: &Path, num_frames: usize) -> usize {
+    (0..num_frames).find(|&frame| !frame_path(dir, frame).exists()).unwrap_or(num_frames)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(time: f32, position: [f32; 3], fov: Option<f32>) -> Keyframe {
+        Keyframe {time, position, direction: [1.0, 0.0, 0.0], fov}
+    }
+
+    fn path(interpolation: Interpolation, keyframes: Vec<Keyframe>) -> CameraPath {
+        CameraPath {keyframes, interpolation, frame_rate: 24.0}
+    }
+
+    fn uneven_path(interpolation: Interpolation) -> CameraPath {
+        path(interpolation, vec![
+            keyframe(0.0, [0.0, 0.0, 0.0], Some(60.0)),
+            keyframe(1.0, [1.0, 2.0, 0.0], Some(170.0)),
+            keyframe(3.0, [3.0, 2.0, -4.0], Some(170.0)),
+        ])
+    }
+
+    fn assert_close<const N: usize>(a: [f32; N], b: [f32; N]) {
+        assert!((0..N).all(|i| (a[i] - b[i]).abs() < 1e-4), "{a:?} != {b:?}");
+    }
+
+    /// a directory for the test that starts empty
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lighting_models_{}_{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn load_str(name: &str, text: &str) -> Result<CameraPath, CameraPathError> {
+        let dir = test_dir(name);
+        let file = dir.join("path.toml");
+        fs::write(&file, text).unwrap();
+        let path = CameraPath::load(&file);
+        fs::remove_dir_all(&dir).unwrap();
+        path
+    }
+
+    #[test]
+    fn num_frames_includes_both_ends() {
+        assert_eq!(uneven_path(Interpolation::Linear).num_frames(), 73);
+        assert_eq!(path(Interpolation::Linear, vec![keyframe(2.0, [0.0; 3], None)]).num_frames(), 1);
+
+        let mut path = path(Interpolation::Linear, vec![keyframe(0.0, [0.0; 3], None), keyframe(0.25, [0.0; 3], None)]);
+        path.frame_rate = 10.0;
+        assert_eq!(path.num_frames(), 3);
+    }
+
+    #[test]
+    fn frame_time_starts_at_the_first_keyframe() {
+        let path = path(Interpolation::Linear, vec![keyframe(2.0, [0.0; 3], None), keyframe(4.0, [0.0; 3], None)]);
+        assert_eq!(path.frame_time(0), 2.0);
+        assert_eq!(path.frame_time(24), 3.0);
+        assert_eq!(path.frame_time(path.num_frames() - 1), 4.0);
+    }
+
+    #[test]
+    fn interpolation_passes_through_keyframes() {
+        for interpolation in [Interpolation::Linear, Interpolation::CatmullRom] {
+            let path = uneven_path(interpolation);
+            for keyframe in &path.keyframes {
+                assert_close(path.interpolate(keyframe.time, |keyframe| keyframe.position), keyframe.position);
+            }
+            // the ends are held outside the path
+            assert_close(path.interpolate(-1.0, |keyframe| keyframe.position), [0.0, 0.0, 0.0]);
+            assert_close(path.interpolate(5.0, |keyframe| keyframe.position), [3.0, 2.0, -4.0]);
+        }
+    }
+
+    #[test]
+    fn linear_interpolation_between_keyframes() {
+        let path = uneven_path(Interpolation::Linear);
+        assert_close(path.interpolate(0.5, |keyframe| keyframe.position), [0.5, 1.0, 0.0]);
+        assert_close(path.interpolate(2.0, |keyframe| keyframe.position), [2.0, 2.0, -2.0]);
+    }
+
+    #[test]
+    fn catmull_rom_keeps_a_constant_speed() {
+        // keyframes a camera moving at one unit a second passes, unevenly spaced in time
+        let path = path(Interpolation::CatmullRom, vec![
+            keyframe(0.0, [0.0, 0.0, 0.0], None),
+            keyframe(1.0, [1.0, 0.0, 0.0], None),
+            keyframe(3.0, [3.0, 0.0, 0.0], None),
+        ]);
+        for time in [0.25, 0.5, 1.5, 2.0, 2.75] {
+            assert_close(path.interpolate(time, |keyframe| keyframe.position), [time, 0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn catmull_rom_speed_is_smooth_at_keyframes() {
+        let path = uneven_path(Interpolation::CatmullRom);
+        let position = |time: f32| path.interpolate(time, |keyframe| keyframe.position);
+        let step = 1e-3;
+        let before = position(1.0);
+        let (earlier, later) = (position(1.0 - step), position(1.0 + step));
+        for i in 0..3 {
+            let speed_before = (before[i] - earlier[i]) / step;
+            let speed_after = (later[i] - before[i]) / step;
+            assert!((speed_before - speed_after).abs() < 0.05, "{speed_before} != {speed_after}");
+        }
+    }
+
+    #[test]
+    fn catmull_rom_fov_stays_within_the_keyframes() {
+        let path = uneven_path(Interpolation::CatmullRom);
+        // the curve itself overshoots the last two keyframes
+        let [overshoot] = path.interpolate(1.7, |keyframe| [keyframe.fov.unwrap()]);
+        assert!(overshoot > 170.0);
+
+        for frame in 0..path.num_frames() {
+            let fov = path.fov_at(path.frame_time(frame), 90.0);
+            assert!((60.0..=170.0).contains(&fov), "frame {frame} has a fov of {fov}");
+            assert!(Projection::Perspective.check_fov(fov).is_ok());
+        }
+    }
+
+    #[test]
+    fn missing_fovs_use_the_default() {
+        let path = path(Interpolation::Linear, vec![keyframe(0.0, [0.0; 3], None), keyframe(1.0, [0.0; 3], Some(40.0))]);
+        assert_eq!(path.fov_at(0.0, 90.0), 90.0);
+        assert_eq!(path.fov_at(0.5, 90.0), 65.0);
+    }
+
+    #[test]
+    fn directions_never_vanish() {
+        let mut keyframes = vec![keyframe(0.0, [0.0; 3], None), keyframe(1.0, [0.0; 3], None)];
+        keyframes[1].direction = [-1.0, 0.0, 0.0];
+        let path = path(Interpolation::Linear, keyframes);
+        assert_close(path.direction_at(0.5), [1.0, 0.0, 0.0]);
+        assert_close(path.direction_at(0.75), [-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn load_normalises_directions() {
+        let path = load_str("load", "
+            interpolation = \"linear\"
+
+            [[keyframes]]
+            time = 0.0
+            position = [0.0, 1.0, 0.0]
+            direction = [0.0, 0.0, -2.0]
+
+            [[keyframes]]
+            time = 1.0
+            position = [1.0, 1.0, 0.0]
+            direction = [3.0, 0.0, -3.0]
+            fov = 60.0
+        ").unwrap();
+        assert_eq!(path.interpolation, Interpolation::Linear);
+        assert_eq!(path.frame_rate, 24.0);
+        assert_close(path.keyframes[0].direction, [0.0, 0.0, -1.0]);
+        assert_close(path.keyframes[1].direction, [0.5f32.sqrt(), 0.0, -(0.5f32.sqrt())]);
+    }
+
+    #[test]
+    fn load_rejects_bad_keyframes() {
+        let keyframes = |second_time: f32, second_direction: &str| format!("
+            [[keyframes]]
+            time = 0.0
+            position = [0.0, 0.0, 0.0]
+            direction = [1.0, 0.0, 0.0]
+
+            [[keyframes]]
+            time = {second_time:?}
+            position = [0.0, 0.0, 0.0]
+            direction = {second_direction}
+        ");
+        assert!(load_str("valid", &keyframes(1.0, "[0.0, 1.0, 0.0]")).is_ok());
+        assert!(matches!(load_str("opposite", &keyframes(1.0, "[-1.0, 0.0, 0.0]")), Err(CameraPathError::Invalid(_))));
+        assert!(matches!(load_str("no_direction", &keyframes(1.0, "[0.0, 0.0, 0.0]")), Err(CameraPathError::Invalid(_))));
+        assert!(matches!(load_str("out_of_order", &keyframes(0.0, "[0.0, 1.0, 0.0]")), Err(CameraPathError::Invalid(_))));
+        assert!(matches!(load_str("empty", "keyframes = []"), Err(CameraPathError::Invalid(_))));
+    }
+
+    #[test]
+    fn resumes_after_the_last_finished_frame() {
+        let dir = test_dir("resume");
+        assert_eq!(first_unfinished_frame(&dir, 10), 0);
+
+        for frame in [0, 1, 3] {
+            fs::write(frame_path(&dir, frame), b"").unwrap();
+        }
+        // a frame that was being saved when the render stopped doesn't count
+        fs::write(frame_path(&dir, 2).with_extension("partial.png"), b"").unwrap();
+        assert_eq!(first_unfinished_frame(&dir, 10), 2);
+        assert_eq!(first_unfinished_frame(&dir, 2), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,419 @@
+use super::raytrace_pipeline::raytrace_shader;
+
+
+/// number of buckets centroids are sorted into when looking for a split
+const NUM_BINS: usize = 12;
+/// cost of visiting a node relative to testing one primitive
+const TRAVERSAL_COST: f32 = 1.0;
+/// matches the traversal stack in the shader
+pub const MAX_DEPTH: usize = 32;
+
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Aabb {
+    /// a box containing nothing, growing it by anything gives that thing's bounds
+    pub fn empty() -> Self {
+        Aabb {
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+        }
+    }
+
+    pub fn from_points(points: &[[f32; 3]]) -> Self {
+        let mut aabb = Aabb::empty();
+        for point in points {
+            aabb.grow_point(*point);
+        }
+        aabb
+    }
+
+    pub fn grow_point(&mut self, point: [f32; 3]) {
+        for axis in 0..3 {
+            self.min[axis] = self.min[axis].min(point[axis]);
+            self.max[axis] = self.max[axis].max(point[axis]);
+        }
+    }
+
+    pub fn grow(&mut self, other: &Aabb) {
+        self.grow_point(other.min);
+        self.grow_point(other.max);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| self.min[axis] > self.max[axis])
+    }
+
+    pub fn centre(&self) -> [f32; 3] {
+        [
+            (self.min[0] + self.max[0]) * 0.5,
+            (self.min[1] + self.max[1]) * 0.5,
+            (self.min[2] + self.max[2]) * 0.5,
+        ]
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {return 0.0;}
+        let size = [self.max[0] - self.min[0], self.max[1] - self.min[1], self.max[2] - self.min[2]];
+        2.0 * (size[0] * size[1] + size[1] * size[2] + size[2] * size[0])
+    }
+
+    /// distances along the ray to where it enters and leaves the box, the entry is clamped to 0 for rays starting inside.
+    /// the ray misses when the entry is further than the exit. a port of intersecting_aabb in the shader
+    pub fn intersect(&self, root_pos: [f32; 3], inv_dir: [f32; 3]) -> (f32, f32) {
+        let mut d_min: f32 = 0.0;
+        let mut d_max = f32::MAX;
+
+        for axis in 0..3 {
+            if inv_dir[axis].is_infinite() {
+                // parallel to the slab, the ray is either always between the planes or never
+                if root_pos[axis] < self.min[axis] || root_pos[axis] > self.max[axis] {return (f32::MAX, 0.0);}
+                continue;
+            }
+
+            let d_one = (self.min[axis] - root_pos[axis]) * inv_dir[axis];
+            let d_two = (self.max[axis] - root_pos[axis]) * inv_dir[axis];
+            d_min = d_min.max(d_one.min(d_two));
+            d_max = d_max.min(d_one.max(d_two));
+        }
+
+        (d_min, d_max)
+    }
+
+    /// distance to where the ray enters the box, None if it misses
+    pub fn hit_distance(&self, root_pos: [f32; 3], inv_dir: [f32; 3]) -> Option<f32> {
+        let (d_min, d_max) = self.intersect(root_pos, inv_dir);
+        if d_min <= d_max {Some(d_min)} else {None}
+    }
+}
+
+/// Bounding volume hierarchy flattened into the layout used by the shader.
+/// Children of a node are always next to each other so only the left one is stored
+pub struct Bvh {
+    pub nodes: Vec<raytrace_shader::BvhNode>,
+    /// the primitive indices in the order the leaves reference them
+    pub order: Vec<u32>,
+}
+
+impl Bvh {
+    /// builds a bvh over the given primitive bounds using the surface area heuristic with binned splits.
+    /// node indices are offset by node_offset and leaf primitive indices by prim_offset so
+    /// many bvhs can share one buffer
+    pub fn build(
+        bounds: &[Aabb],
+        node_offset: u32,
+        prim_offset: u32,
+    ) -> Self {
+        let centres: Vec<[f32; 3]> = bounds.iter().map(|aabb| aabb.centre()).collect();
+        let mut order: Vec<u32> = (0..bounds.len() as u32).collect();
+        let mut nodes = vec![BuildNode {
+            bounds: Aabb::empty(),
+            left_first: 0,
+            count: bounds.len() as u32,
+        }];
+
+        let mut to_split = vec![(0, 1)];
+        while let Some((index, depth)) = to_split.pop() {
+            let node = &mut nodes[index];
+            let (first, count) = (node.left_first as usize, node.count as usize);
+            for prim in order[first..first + count].iter() {
+                node.bounds.grow(&bounds[*prim as usize]);
+            }
+
+            // the shader stack can only hold so many nodes
+            if count <= 1 || depth >= MAX_DEPTH {continue;}
+
+            let Some((axis, split)) = find_split(node, &order[first..first + count], bounds, &centres) else {continue;};
+
+            // partition the primitives around the split
+            let mut i = first;
+            let mut j = first + count;
+            while i < j {
+                if centres[order[i] as usize][axis] < split {
+                    i += 1;
+                } else {
+                    j -= 1;
+                    order.swap(i, j);
+                }
+            }
+            let left_count = i - first;
+            if left_count == 0 || left_count == count {continue;}
+
+            let left = nodes.len();
+            nodes[index].left_first = left as u32;
+            nodes[index].count = 0;
+            nodes.push(BuildNode {bounds: Aabb::empty(), left_first: first as u32, count: left_count as u32});
+            nodes.push(BuildNode {bounds: Aabb::empty(), left_first: i as u32, count: (count - left_count) as u32});
+            to_split.push((left, depth + 1));
+            to_split.push((left + 1, depth + 1));
+        }
+
+        let nodes = nodes.iter().map(|node| {
+            let offset = if node.count == 0 {node_offset} else {prim_offset};
+            raytrace_shader::BvhNode {
+                min_point: node.bounds.min,
+                left_first: node.left_first + offset,
+                max_point: node.bounds.max,
+                count: node.count,
+            }
+        }).collect();
+
+        Bvh {
+            nodes,
+            order,
+        }
+    }
+}
+
+
+struct BuildNode {
+    bounds: Aabb,
+    left_first: u32,
+    count: u32,
+}
+
+/// finds the cheapest axis and centroid position to split a node at, None if a leaf is cheaper
+fn find_split(
+    node: &BuildNode,
+    prims: &[u32],
+    bounds: &[Aabb],
+    centres: &[[f32; 3]],
+) -> Option<(usize, f32)> {
+    let mut centre_bounds = Aabb::empty();
+    for prim in prims {
+        centre_bounds.grow_point(centres[*prim as usize]);
+    }
+
+    let mut best: Option<(usize, f32)> = None;
+    let mut best_cost = prims.len() as f32 * node.bounds.surface_area();
+
+    for axis in 0..3 {
+        let (low, high) = (centre_bounds.min[axis], centre_bounds.max[axis]);
+        if high <= low {continue;}
+
+        let mut bins = [(Aabb::empty(), 0u32); NUM_BINS];
+        let scale = NUM_BINS as f32 / (high - low);
+        for prim in prims {
+            let bin = (((centres[*prim as usize][axis] - low) * scale) as usize).min(NUM_BINS - 1);
+            bins[bin].0.grow(&bounds[*prim as usize]);
+            bins[bin].1 += 1;
+        }
+
+        // sweep from the left and right to get the cost of splitting after each bin
+        let mut left_costs = [0.0; NUM_BINS - 1];
+        let mut left_bounds = Aabb::empty();
+        let mut left_count = 0;
+        for i in 0..NUM_BINS - 1 {
+            left_bounds.grow(&bins[i].0);
+            left_count += bins[i].1;
+            left_costs[i] = left_count as f32 * left_bounds.surface_area();
+        }
+        let mut right_bounds = Aabb::empty();
+        let mut right_count = 0;
+        for i in (1..NUM_BINS).rev() {
+            right_bounds.grow(&bins[i].0);
+            right_count += bins[i].1;
+            let cost = TRAVERSAL_COST * node.bounds.surface_area() + left_costs[i - 1] + right_count as f32 * right_bounds.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some((axis, low + i as f32 / scale));
+            }
+        }
+    }
+
+    best
+}
+
+
+/// walks the bvh from root the same way the shader does, calling hit_prim for every primitive in a leaf the ray reaches.
+/// hit_prim is given the primitive index and the closest distance so far and returns the distance it was hit at.
+/// returns the closest distance and primitive
+pub fn traverse(
+    nodes: &[raytrace_shader::BvhNode],
+    root: u32,
+    root_pos: [f32; 3],
+    dir: [f32; 3],
+    max_dist: f32,
+    mut hit_prim: impl FnMut(u32, f32) -> Option<f32>,
+) -> Option<(f32, u32)> {
+    let inv_dir = [1.0 / dir[0], 1.0 / dir[1], 1.0 / dir[2]];
+    let node_distance = |index: u32| {
+        let node = &nodes[index as usize];
+        Aabb {min: node.min_point, max: node.max_point}.hit_distance(root_pos, inv_dir).unwrap_or(f32::MAX)
+    };
+
+    let mut closest: Option<(f32, u32)> = None;
+    let mut closest_dist = max_dist;
+    let mut stack = vec![root];
+    while let Some(index) = stack.pop() {
+        if node_distance(index) >= closest_dist {continue;}
+        let node = &nodes[index as usize];
+
+        if node.count > 0 {
+            for prim in node.left_first..node.left_first + node.count {
+                if let Some(dist) = hit_prim(prim, closest_dist) {
+                    if dist < closest_dist {
+                        closest_dist = dist;
+                        closest = Some((dist, prim));
+                    }
+                }
+            }
+            continue;
+        }
+
+        // push the further child first so the nearer one is visited first
+        let (left, right) = (node.left_first, node.left_first + 1);
+        let (left_dist, right_dist) = (node_distance(left), node_distance(right));
+        let (near, near_dist, far, far_dist) = if left_dist <= right_dist {(left, left_dist, right, right_dist)} else {(right, right_dist, left, left_dist)};
+        if far_dist < closest_dist {stack.push(far);}
+        if near_dist < closest_dist {stack.push(near);}
+    }
+
+    closest
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb {min: [-1.0; 3], max: [1.0; 3]}
+    }
+
+    fn inverse(dir: [f32; 3]) -> [f32; 3] {
+        dir.map(|c| 1.0 / c)
+    }
+
+    #[test]
+    fn axis_parallel_rays() {
+        let aabb = unit_box();
+        assert_eq!(aabb.hit_distance([-3.0, 0.5, 0.0], inverse([1.0, 0.0, 0.0])), Some(2.0));
+        // negative zero gives an infinity of the other sign
+        assert_eq!(aabb.hit_distance([-3.0, 0.5, 0.0], inverse([1.0, -0.0, 0.0])), Some(2.0));
+        assert_eq!(aabb.hit_distance([-3.0, 1.5, 0.0], inverse([1.0, 0.0, 0.0])), None);
+        assert_eq!(aabb.hit_distance([-3.0, 0.0, -1.5], inverse([1.0, 0.0, 0.0])), None);
+        // along a face of the box, where the slab distances would be 0 * inf = NaN
+        assert_eq!(aabb.hit_distance([-3.0, 1.0, 1.0], inverse([1.0, 0.0, 0.0])), Some(2.0));
+        assert_eq!(aabb.hit_distance([-3.0, -3.0, 0.0], inverse([1.0, 1.0, 0.0])), Some(2.0));
+    }
+
+    #[test]
+    fn rays_starting_inside() {
+        let aabb = unit_box();
+        assert_eq!(aabb.intersect([0.0; 3], inverse([1.0, 2.0, -0.5])), (0.0, 0.5));
+        assert_eq!(aabb.intersect([0.5, 0.0, 0.0], inverse([1.0, 0.0, 0.0])), (0.0, 0.5));
+        assert_eq!(aabb.intersect([0.5, 0.0, 0.0], inverse([-1.0, 0.0, 0.0])), (0.0, 1.5));
+        assert_eq!(aabb.hit_distance([0.5, 0.0, 0.0], inverse([-1.0, 0.0, 0.0])), Some(0.0));
+    }
+
+    #[test]
+    fn boxes_behind_the_ray() {
+        let aabb = unit_box();
+        assert_eq!(aabb.hit_distance([3.0, 0.0, 0.0], inverse([1.0, 0.0, 0.0])), None);
+        assert_eq!(aabb.hit_distance([3.0, 2.0, 0.0], inverse([1.0, 1.0, 0.0])), None);
+        assert_eq!(aabb.hit_distance([0.0, 0.0, 5.0], inverse([0.1, -0.1, 1.0])), None);
+        // the same box is hit looking the other way
+        assert_eq!(aabb.hit_distance([3.0, 0.0, 0.0], inverse([-1.0, 0.0, 0.0])), Some(2.0));
+    }
+
+    /// xorshift, enough to scatter triangles and rays around
+    fn random(state: &mut u32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state as f32 / u32::MAX as f32
+    }
+
+    type Triangle = [[f32; 3]; 3];
+
+    /// small triangles spread through a box 20 units across
+    fn random_triangles(count: usize, state: &mut u32) -> Vec<Triangle> {
+        (0..count).map(|_| {
+            let centre: [f32; 3] = std::array::from_fn(|_| random(state) * 20.0 - 10.0);
+            std::array::from_fn(|_| std::array::from_fn(|axis| centre[axis] + random(state) * 2.0 - 1.0))
+        }).collect()
+    }
+
+    /// distance along the ray to the triangle, from either side
+    fn hit_triangle(tri: &Triangle, pos: [f32; 3], dir: [f32; 3]) -> Option<f32> {
+        let sub = |a: [f32; 3], b: [f32; 3]| [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+        let cross = |a: [f32; 3], b: [f32; 3]| [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+        let dot = |a: [f32; 3], b: [f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+
+        let (edge_one, edge_two) = (sub(tri[1], tri[0]), sub(tri[2], tri[0]));
+        let p = cross(dir, edge_two);
+        let det = dot(edge_one, p);
+        if det.abs() < 1e-8 {return None;}
+        let to_pos = sub(pos, tri[0]);
+        let u = dot(to_pos, p) / det;
+        if !(0.0..=1.0).contains(&u) {return None;}
+        let q = cross(to_pos, edge_one);
+        let v = dot(dir, q) / det;
+        if v < 0.0 || u + v > 1.0 {return None;}
+        let dist = dot(edge_two, q) / det;
+        if dist > 0.0 {Some(dist)} else {None}
+    }
+
+    #[test]
+    fn build_keeps_every_primitive() {
+        let triangles = random_triangles(300, &mut 7);
+        let bounds: Vec<Aabb> = triangles.iter().map(|tri| Aabb::from_points(&tri[..])).collect();
+        let bvh = Bvh::build(&bounds, 0, 0);
+
+        let mut order = bvh.order.clone();
+        order.sort();
+        assert_eq!(order, (0..300).collect::<Vec<u32>>());
+
+        // every primitive is in exactly one leaf, inside the bounds of that leaf
+        let mut seen = vec![0; 300];
+        for node in bvh.nodes.iter().filter(|node| node.count > 0) {
+            let leaf = Aabb {min: node.min_point, max: node.max_point};
+            for prim in node.left_first..node.left_first + node.count {
+                let prim = bvh.order[prim as usize] as usize;
+                seen[prim] += 1;
+                let mut grown = leaf;
+                grown.grow(&bounds[prim]);
+                assert_eq!(grown, leaf);
+            }
+        }
+        assert!(seen.iter().all(|count| *count == 1));
+    }
+
+    #[test]
+    fn traverse_finds_the_closest_hit() {
+        let mut state = 12345;
+        let triangles = random_triangles(500, &mut state);
+        let bounds: Vec<Aabb> = triangles.iter().map(|tri| Aabb::from_points(&tri[..])).collect();
+        let bvh = Bvh::build(&bounds, 0, 0);
+        assert!(bvh.nodes.len() > 1);
+
+        let mut num_hits = 0;
+        for i in 0..1000 {
+            let pos: [f32; 3] = std::array::from_fn(|_| random(&mut state) * 30.0 - 15.0);
+            let dir = match i % 4 {
+                // axis parallel rays have infinite inverse directions
+                0 => [1.0, 0.0, 0.0],
+                1 => [0.0, -1.0, 0.0],
+                _ => std::array::from_fn(|_| random(&mut state) * 2.0 - 1.0),
+            };
+
+            let brute_force = triangles.iter().enumerate()
+                .filter_map(|(i, tri)| hit_triangle(tri, pos, dir).map(|dist| (dist, i as u32)))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            let traversed = traverse(&bvh.nodes, 0, pos, dir, f32::MAX, |prim, _| {
+                hit_triangle(&triangles[bvh.order[prim as usize] as usize], pos, dir)
+            }).map(|(dist, prim)| (dist, bvh.order[prim as usize]));
+
+            assert_eq!(traversed, brute_force, "ray {i} from {pos:?} along {dir:?}");
+            num_hits += brute_force.is_some() as u32;
+        }
+        // enough rays hit something for the comparison to mean anything
+        assert!(num_hits > 50);
+    }
+}
@@ -0,0 +1,314 @@
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use graphics::PositionVertex;
+use super::raytracing_app::RayTracerSettings;
+use super::post_process::ToneMapOperator;
+use super::raytrace_pipeline::{SamplingMode, Projection};
+use super::environment::Environment;
+
+
+pub const USAGE: &str = "\
+Usage: lighting_models [OPTIONS] [SCENE]
+
+Renders the given scene file (default: scenes/island.toml)
+
+Options:
+  --width <PIXELS>      image width (default: 1080)
+  --height <PIXELS>     image height (default: 720)
+  --samples <N>         samples per pixel per frame, overrides the scene
+  --bounces <N>         maximum ray bounces, overrides the scene
+  --fov <DEGREES>       vertical field of view, overrides the scene
+  --projection <NAME>   perspective, orthographic, fisheye or equirectangular, overrides the scene
+  --realtime [FRAMES]   render progressively in the window, stopping after FRAMES frames (0 for no limit)
+  --batch <FRAMES>      compute FRAMES frames and then display the result
+  --fps <FPS>           target frame rate in realtime mode (default: 60)
+  --env / --no-env      turn environment lighting on or off, --env uses the sky gradient if the scene has no environment
+  --sampling <MODE>     bsdf, light or mis, how light reaching each bounce is found, overrides the scene
+  --exposure <STOPS>    exposure adjustment in stops, overrides the scene
+  --tone-map <NAME>     clamp, reinhard, extended_reinhard, aces or agx, overrides the scene
+  --controllable        allow the camera to be moved in realtime mode
+  --reproject           keep the image while the camera moves a little instead of starting again, overrides the scene
+  --headless            render without a window, needs --batch and --output
+  --cpu                 render on the cpu with the reference path tracer, needs --batch and --output
+  -o, --output <PATH>   save the final image to PATH, .exr files keep the hdr values
+  --camera-path <FILE>  render every frame of a camera path without a window, accumulating --batch frames for each,
+                        and save them as numbered pngs in the --output directory
+  --resume              with --camera-path, carry on after the frames already in the output directory
+  -v, --verbose         print the size of the scene and how long its bvhs took to build
+  --check-golden        render the bundled scenes on the cpu and compare them to the images in assets/golden
+  --bless-golden        render the bundled scenes on the cpu and save them as the new images in assets/golden
+  -h, --help            print this message";
+
+pub const COMMANDS: &str = "\
+Commands, typed into the terminal while rendering in realtime:
+  exposure <STOPS>      set the exposure
+  brighter / darker     change the exposure by one stop
+  tone-map [NAME]       set the tone mapping operator, or move on to the next one
+  aperture <RADIUS>     set the lens aperture, 0 for a pinhole camera
+  focus <DISTANCE>      set the focus distance
+  autofocus             focus on whatever is in the centre of the image
+  fov <DEGREES>         set the vertical field of view to zoom in or out
+  help                  print this message";
+
+
+/// How frames are produced
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    /// render and present one frame at a time, 0 frames means no limit
+    Realtime {num_frames: usize},
+    /// compute all frames and then present once
+    Batch {num_frames: usize},
+}
+
+
+/// What to do with the golden images of the bundled scenes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GoldenMode {
+    Check,
+    Bless,
+}
+
+
+/// Command line options
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub scene: PathBuf,
+    pub image_size: [u32; 2],
+    pub num_samples: Option<u32>,
+    pub max_bounces: Option<u32>,
+    pub fov: Option<f32>,
+    pub projection: Option<Projection>,
+    pub mode: RenderMode,
+    pub target_fps: f32,
+    pub environment_lighting: Option<bool>,
+    pub sampling_mode: Option<SamplingMode>,
+    pub exposure: Option<f32>,
+    pub tone_map: Option<ToneMapOperator>,
+    pub controllable: bool,
+    pub reproject: bool,
+    pub headless: bool,
+    pub cpu: bool,
+    pub output: Option<PathBuf>,
+    pub camera_path: Option<PathBuf>,
+    pub resume: bool,
+    pub verbose: bool,
+    pub golden: Option<GoldenMode>,
+    pub help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            scene: PathBuf::from("scenes/island.toml"),
+            image_size: [1080, 720],
+            num_samples: None,
+            max_bounces: None,
+            fov: None,
+            projection: None,
+            mode: RenderMode::Realtime {num_frames: 5000},
+            target_fps: 60.0,
+            environment_lighting: None,
+            sampling_mode: None,
+            exposure: None,
+            tone_map: None,
+            controllable: false,
+            reproject: false,
+            headless: false,
+            cpu: false,
+            output: None,
+            camera_path: None,
+            resume: false,
+            verbose: false,
+            golden: None,
+            help: false,
+        }
+    }
+}
+
+impl Args {
+    /// parse the arguments, not including the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut scene = None;
+        let mut args = args.into_iter().peekable();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "--width" => parsed.image_size[0] = parse_value(&arg, args.next())?,
+                "--height" => parsed.image_size[1] = parse_value(&arg, args.next())?,
+                "--samples" => parsed.num_samples = Some(parse_value(&arg, args.next())?),
+                "--bounces" => parsed.max_bounces = Some(parse_value(&arg, args.next())?),
+                "--fov" => parsed.fov = Some(parse_value(&arg, args.next())?),
+                "--projection" => parsed.projection = Some(args.next().ok_or(format!("{arg} needs a value"))?.parse()?),
+                "--fps" => parsed.target_fps = parse_value(&arg, args.next())?,
+                "--batch" => parsed.mode = RenderMode::Batch {num_frames: parse_value(&arg, args.next())?},
+                "--realtime" => {
+                    // the frame limit is optional, only take the next argument if it is a number
+                    let num_frames = match args.peek().map(|next| next.parse::<usize>()) {
+                        Some(Ok(num_frames)) => {
+                            args.next();
+                            num_frames
+                        }
+                        _ => 0
+                    };
+                    parsed.mode = RenderMode::Realtime {num_frames};
+                }
+                "--env" => parsed.environment_lighting = Some(true),
+                "--no-env" => parsed.environment_lighting = Some(false),
+                "--sampling" => parsed.sampling_mode = Some(args.next().ok_or(format!("{arg} needs a value"))?.parse()?),
+                "--exposure" => parsed.exposure = Some(parse_value(&arg, args.next())?),
+                "--tone-map" => parsed.tone_map = Some(args.next().ok_or(format!("{arg} needs a value"))?.parse()?),
+                "--controllable" => parsed.controllable = true,
+                "--reproject" => parsed.reproject = true,
+                "--headless" => parsed.headless = true,
+                "--cpu" => parsed.cpu = true,
+                "-o" | "--output" => parsed.output = Some(PathBuf::from(args.next().ok_or(format!("{arg} needs a value"))?)),
+                "--camera-path" => parsed.camera_path = Some(PathBuf::from(args.next().ok_or(format!("{arg} needs a value"))?)),
+                "--resume" => parsed.resume = true,
+                "-v" | "--verbose" => parsed.verbose = true,
+                "--check-golden" => parsed.golden = Some(GoldenMode::Check),
+                "--bless-golden" => parsed.golden = Some(GoldenMode::Bless),
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ => {
+                    if scene.is_some() {
+                        return Err(format!("unexpected argument {arg}, only one scene can be rendered"));
+                    }
+                    scene = Some(PathBuf::from(arg));
+                }
+            }
+        }
+
+        if let Some(scene) = scene {
+            parsed.scene = scene;
+        }
+        if parsed.image_size[0] == 0 || parsed.image_size[1] == 0 {
+            return Err("image width and height must be at least 1".to_string());
+        }
+        if parsed.target_fps <= 0.0 {
+            return Err("target fps must be positive".to_string());
+        }
+        if let RenderMode::Batch {num_frames: 0} = parsed.mode {
+            return Err("batch mode needs at least 1 frame".to_string());
+        }
+        if parsed.cpu && parsed.headless {
+            return Err("--cpu renders without a window on the cpu and --headless on the gpu, use one of them".to_string());
+        }
+        if parsed.cpu && parsed.camera_path.is_some() {
+            return Err("camera paths are rendered on the gpu, --cpu can't be used with --camera-path".to_string());
+        }
+        if parsed.resume && parsed.camera_path.is_none() {
+            return Err("--resume needs a --camera-path".to_string());
+        }
+        if (parsed.headless || parsed.cpu || parsed.camera_path.is_some()) && parsed.golden.is_none() && !parsed.help {
+            let name = if parsed.cpu {"cpu"} else if parsed.camera_path.is_some() {"camera path"} else {"headless"};
+            if let RenderMode::Realtime {..} = parsed.mode {
+                return Err(format!("{name} rendering needs a frame count, use --batch"));
+            }
+            if parsed.output.is_none() {
+                return Err(format!("{name} rendering needs an output path"));
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// apply the overrides given on the command line to the scene settings,
+    /// fails if the fov doesn't suit the projection they end up with
+    pub fn apply(&self, settings: &mut RayTracerSettings<PositionVertex>) -> Result<(), String> {
+        if let Some(num_samples) = self.num_samples {
+            settings.num_samples = num_samples;
+        }
+        if let Some(max_bounces) = self.max_bounces {
+            settings.max_bounces = max_bounces;
+        }
+        if let Some(fov) = self.fov {
+            settings.fov = fov;
+        }
+        if let Some(projection) = self.projection {
+            settings.projection = projection;
+        }
+        match (self.environment_lighting, &settings.environment) {
+            (Some(false), _) => settings.environment = Environment::None,
+            (Some(true), Environment::None) => settings.environment = Environment::Gradient,
+            _ => {}
+        }
+        if let Some(sampling_mode) = self.sampling_mode {
+            settings.sampling_mode = sampling_mode;
+        }
+        if let Some(exposure) = self.exposure {
+            settings.exposure = exposure;
+        }
+        if let Some(tone_map) = self.tone_map {
+            settings.tone_map = tone_map;
+        }
+        if self.reproject {
+            settings.temporal_reprojection = true;
+        }
+        settings.projection.check_fov(settings.fov)
+    }
+}
+
+/// A change to the render typed into the terminal while rendering in realtime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// set the exposure in stops
+    Exposure(f32),
+    /// change the exposure by a number of stops
+    ChangeExposure(f32),
+    ToneMap(ToneMapOperator),
+    NextToneMap,
+    Aperture(f32),
+    Focus(f32),
+    Autofocus,
+    /// set the vertical field of view in degrees
+    Fov(f32),
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["exposure", stops] => Ok(Command::Exposure(parse_value("exposure", Some(stops.to_string()))?)),
+            ["brighter"] => Ok(Command::ChangeExposure(1.0)),
+            ["darker"] => Ok(Command::ChangeExposure(-1.0)),
+            ["tone-map"] => Ok(Command::NextToneMap),
+            ["tone-map", name] => Ok(Command::ToneMap(name.parse()?)),
+            ["aperture", radius] => match parse_value("aperture", Some(radius.to_string()))? {
+                radius if radius < 0.0 => Err("the aperture radius cannot be negative".to_string()),
+                radius => Ok(Command::Aperture(radius)),
+            },
+            ["focus", distance] => match parse_value("focus", Some(distance.to_string()))? {
+                distance if distance <= 0.0 => Err("the focus distance must be positive".to_string()),
+                distance => Ok(Command::Focus(distance)),
+            },
+            ["autofocus"] => Ok(Command::Autofocus),
+            ["fov", degrees] => Ok(Command::Fov(parse_value("fov", Some(degrees.to_string()))?)),
+            ["help"] => Ok(Command::Help),
+            _ => Err(format!("unknown command {}, type help to see the commands", line.trim())),
+        }
+    }
+}
+
+/// reads commands from the terminal on another thread, so the render loop never waits for them
+pub fn read_commands() -> Receiver<Result<Command, String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else {break;};
+            if line.trim().is_empty() {continue;}
+            if sender.send(line.parse()).is_err() {break;}
+        }
+    });
+    receiver
+}
+
+fn parse_value<V: FromStr>(arg: &str, value: Option<String>) -> Result<V, String> {
+    let value = value.ok_or(format!("{arg} needs a value"))?;
+    value.parse().map_err(|_| format!("invalid value for {arg}: {value}"))
+}
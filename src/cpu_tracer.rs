@@ -0,0 +1,1491 @@
+use std::thread;
+use graphics::Camera;
+use graphics::all_vulkano::buffer::BufferContents;
+use super::bvh::{self, Aabb, Bvh};
+use super::raytrace_pipeline::{raytrace_shader, SamplingMode, Projection, build_lights, build_top_level, default_jitter, get_sample_centre, get_view_matrix, transform_meshes};
+use super::raytracing_app::RayTracerSettings;
+use super::environment::{Environment, EnvironmentMap, Sky};
+use super::textures::TextureAtlas;
+
+const UINT_MAX: f32 = 4294967295.0;
+const INVIS_FLAG: f32 = 1.0;
+const DIELECTRIC_FLAG: f32 = 2.0;
+const PBR_FLAG: f32 = 3.0;
+
+
+/// Path tracer that runs on the cpu with the same logic as assets/raytracing.glsl,
+/// so scenes can be rendered and checked on machines without a gpu.
+/// Each function mirrors the shader function of the same name
+pub struct CpuRayTracer {
+    image_size: [u32; 2],
+    half_height: f32,
+    sensor_offset: [f32; 2],
+    projection: Projection,
+
+    spheres: Vec<raytrace_shader::Sphere>,
+    triangles: Vec<raytrace_shader::Triangle>,
+    meshes: Vec<raytrace_shader::Mesh>,
+    bvh_nodes: Vec<raytrace_shader::BvhNode>,
+    top_level: Bvh,
+    lights: Vec<raytrace_shader::Light>,
+    textures: TextureAtlas,
+
+    num_samples: u32,
+    jitter_size: f32,
+    aperture_radius: f32,
+    focus_distance: f32,
+    max_bounces: u32,
+    sampling_mode: SamplingMode,
+    environment: Environment,
+}
+
+#[derive(Debug, Clone)]
+struct RayHit {
+    hit_normal: [f32; 3],
+    hit_pos: [f32; 3],
+    hit_dist: f32,
+    hit_mat: raytrace_shader::RayTracingMaterial,
+    /// numbered like the top level bvh
+    object: u32,
+    /// triangle hit for meshes
+    tri: u32,
+    uv: [f32; 2],
+}
+
+
+impl CpuRayTracer {
+    /// builds the same scene data the raytrace pipeline sends to the gpu
+    pub fn new<T: graphics::Position + BufferContents + Copy + Clone>(
+        image_size: [u32; 2],
+        settings: &RayTracerSettings<T>,
+    ) -> Self {
+        let spheres: Vec<raytrace_shader::Sphere> = settings.sphere_data.iter().map(|sphere| sphere.clone().into()).collect();
+        let (triangles, meshes, bvh_nodes, first_tris) = transform_meshes(&settings.mesh_data, &settings.instances);
+        let mesh_bounds: Vec<Aabb> = meshes.iter().map(|mesh| Aabb {min: mesh.min_point, max: mesh.max_point}).collect();
+        let top_level = build_top_level(&settings.sphere_data, &mesh_bounds);
+        let lights = build_lights(&settings.sphere_data, &meshes, &first_tris);
+        let half_height = settings.projection.half_height(settings.fov, settings.ortho_height);
+
+        CpuRayTracer {
+            image_size,
+            half_height,
+            sensor_offset: settings.sensor_offset,
+            projection: settings.projection,
+            spheres,
+            triangles,
+            meshes,
+            bvh_nodes,
+            top_level,
+            lights,
+            textures: settings.textures.clone(),
+            num_samples: settings.num_samples.max(1),
+            jitter_size: settings.sample_jitter.unwrap_or(default_jitter(image_size, half_height)),
+            aperture_radius: settings.aperture_radius,
+            focus_distance: settings.focus_distance,
+            max_bounces: settings.max_bounces,
+            sampling_mode: settings.sampling_mode,
+            environment: settings.environment.clone(),
+        }
+    }
+
+    /// renders one frame, the same as one dispatch of the shader with the given rng_offset.
+    /// rows are shared out between threads so each thread gets a mix of cheap and expensive scanlines
+    pub fn render_frame(
+        &self,
+        camera: &Camera,
+        rng_offset: u32,
+    ) -> Vec<[f32; 3]> {
+        let width = self.image_size[0] as usize;
+        let cam_pos: [f32; 3] = camera.position.into();
+        let view = get_view_matrix(camera);
+
+        let mut pixels = vec![[0.0; 3]; (self.image_size[0] * self.image_size[1]) as usize];
+        if pixels.is_empty() {return pixels;}
+
+        let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut thread_rows: Vec<Vec<(usize, &mut [[f32; 3]])>> = (0..num_threads).map(|_| Vec::new()).collect();
+        for (y, row) in pixels.chunks_mut(width).enumerate() {
+            thread_rows[y % num_threads].push((y, row));
+        }
+
+        thread::scope(|scope| {
+            for rows in thread_rows {
+                let view = &view;
+                scope.spawn(move || {
+                    for (y, row) in rows {
+                        for (x, pixel) in row.iter_mut().enumerate() {
+                            *pixel = self.render_pixel((x + y * width) as u32, cam_pos, view, rng_offset);
+                        }
+                    }
+                });
+            }
+        });
+
+        pixels
+    }
+
+    /// sums frames the same way the diffuse pass does, rgb is the sum and a is the number of frames.
+    /// frames are numbered from 1 like in the app, where frame 0 only clears the image
+    pub fn accumulate(
+        &self,
+        camera: &Camera,
+        num_frames: u32,
+    ) -> Vec<[f32; 4]> {
+        let mut accumulated = vec![[0.0; 4]; (self.image_size[0] * self.image_size[1]) as usize];
+        for frame in 1..=num_frames {
+            let pixels = self.render_frame(camera, frame);
+            for (sum, col) in accumulated.iter_mut().zip(pixels) {
+                *sum = [sum[0] + col[0], sum[1] + col[1], sum[2] + col[2], sum[3] + 1.0];
+            }
+        }
+        accumulated
+    }
+
+    fn render_pixel(
+        &self,
+        id: u32,
+        cam_pos: [f32; 3],
+        view: &[[f32; 4]; 4],
+        rng_offset: u32,
+    ) -> [f32; 3] {
+        let mut colour = [0.0; 3];
+        let mut state = rng_offset.wrapping_mul(719393).wrapping_add(id);
+        let pixel = [id % self.image_size[0], id / self.image_size[0]];
+        let sample_centre = get_sample_centre(pixel, self.image_size, self.half_height, self.sensor_offset);
+        for _ in 0..self.num_samples {
+            let Some((pos, dir)) = self.get_ray(sample_centre, cam_pos, view, &mut state) else {continue};
+            let (pos, dir) = self.thin_lens(pos, dir, view, &mut state);
+            colour = add(colour, self.trace_ray(pos, normalize(dir), &mut state));
+        }
+
+        scale(colour, 1.0 / self.num_samples as f32)
+    }
+
+    /// the ray through a random point within the jitter size of the sample centre, None if the projection misses it
+    fn get_ray(
+        &self,
+        sample_centre: [f32; 3],
+        cam_pos: [f32; 3],
+        view: &[[f32; 4]; 4],
+        state: &mut u32,
+    ) -> Option<([f32; 3], [f32; 3])> {
+        let random = scale_to_range01(hash(state)) * 2.0 * std::f32::consts::PI;
+        let z_offset = random.cos() * self.jitter_size * scale_to_range01(hash(state)).sqrt();
+        let y_offset = random.sin() * self.jitter_size * scale_to_range01(hash(state)).sqrt();
+        let new_centre = add(sample_centre, [0.0, y_offset, z_offset]);
+
+        let (offset, dir) = project(self.projection, new_centre)?;
+        // the glsl mat3(cam_alignment_mat), each inner array is a column
+        Some((add(cam_pos, transform_dir(view, offset)), normalize(transform_dir(view, dir))))
+    }
+
+    /// moves the ray's start to a random point on the lens, aimed at the point the pinhole ray meets the focus plane.
+    /// fisheye and equirectangular rays can point sideways or backwards, so they focus on a sphere around the camera instead.
+    /// a pinhole camera uses no random numbers so its images don't change
+    fn thin_lens(
+        &self,
+        pos: [f32; 3],
+        dir: [f32; 3],
+        view: &[[f32; 4]; 4],
+        state: &mut u32,
+    ) -> ([f32; 3], [f32; 3]) {
+        if self.aperture_radius <= 0.0 {return (pos, dir);}
+
+        let forward = normalize(transform_dir(view, [1.0, 0.0, 0.0]));
+        let focus_dist = if self.wide_projection() {self.focus_distance} else {self.focus_distance / dot(dir, forward)};
+        let focus_point = ray_at(pos, dir, focus_dist);
+
+        let angle = scale_to_range01(hash(state)) * 2.0 * std::f32::consts::PI;
+        let radius = self.aperture_radius * scale_to_range01(hash(state)).sqrt();
+        let lens_pos = add(pos, transform_dir(view, [0.0, angle.sin() * radius, angle.cos() * radius]));
+        (lens_pos, normalize(sub(focus_point, lens_pos)))
+    }
+
+    fn wide_projection(&self) -> bool {
+        matches!(self.projection, Projection::Fisheye | Projection::Equirectangular)
+    }
+
+    /// distance to whatever is in the centre of the image, for setting focus_distance. it is measured along the
+    /// camera direction, or along the ray for fisheye and equirectangular projections like thin_lens expects.
+    /// None when the centre of the image looks at the environment
+    pub fn focus_distance(
+        &self,
+        camera: &Camera,
+    ) -> Option<f32> {
+        let view = get_view_matrix(camera);
+        let forward = normalize(transform_dir(&view, [1.0, 0.0, 0.0]));
+        // a one pixel image has its pixel in the centre, including any sensor offset
+        let centre = get_sample_centre([0, 0], [1, 1], self.half_height, self.sensor_offset);
+        let (offset, dir) = project(self.projection, centre)?;
+        let dir = normalize(transform_dir(&view, dir));
+        let hit = self.world_hit(add(camera.position.into(), transform_dir(&view, offset)), dir)?;
+        Some(if self.wide_projection() {hit.hit_dist} else {hit.hit_dist * dot(dir, forward)})
+    }
+
+    fn world_hit(
+        &self,
+        root_pos: [f32; 3],
+        dir: [f32; 3],
+    ) -> Option<RayHit> {
+        if self.top_level.order.is_empty() {return None;}
+
+        let num_spheres = self.spheres.len() as u32;
+        let mut closest: Option<RayHit> = None;
+        bvh::traverse(&self.top_level.nodes, 0, root_pos, dir, f32::MAX, |index, closest_dist| {
+            let object = self.top_level.order[index as usize];
+            let hit = if object < num_spheres {
+                intersecting_sphere(&self.spheres[object as usize], root_pos, dir)
+            } else {
+                self.intersecting_mesh(&self.meshes[(object - num_spheres) as usize], root_pos, dir, closest_dist)
+            }?;
+            if hit.hit_dist > 0.001 && hit.hit_dist < closest_dist {
+                let dist = hit.hit_dist;
+                closest = Some(RayHit {object, ..hit});
+                return Some(dist);
+            }
+            None
+        });
+
+        closest
+    }
+
+    /// only hits closer than max_dist are returned
+    fn intersecting_mesh(
+        &self,
+        mesh: &raytrace_shader::Mesh,
+        root_pos: [f32; 3],
+        dir: [f32; 3],
+        max_dist: f32,
+    ) -> Option<RayHit> {
+        if mesh.len == 0 {return None;}
+
+        let mut closest_barycentrics = (0.0, 0.0);
+        // rays have to be able to leave dielectric meshes through their back faces
+        let cull_back_faces = mesh.material.settings[3] != DIELECTRIC_FLAG;
+        // the object space direction is left unnormalised so distances along it are the same as in world space
+        let object_pos = transform_point(&mesh.world_to_object, root_pos);
+        let object_dir = transform_dir(&mesh.world_to_object, dir);
+        let (dist, tri) = bvh::traverse(&self.bvh_nodes, mesh.root_node, object_pos, object_dir, max_dist, |index, closest_dist| {
+            let (dist, u, v) = intersecting_tri(&self.triangles[index as usize], object_pos, object_dir, cull_back_faces)?;
+            if dist > 0.001 && dist < closest_dist {
+                closest_barycentrics = (u, v);
+                return Some(dist);
+            }
+            None
+        })?;
+
+        let (u, v) = closest_barycentrics;
+        let triangle = &self.triangles[tri as usize];
+        Some(RayHit {
+            hit_normal: normal_to_world(mesh, shading_normal(triangle, u, v)),
+            hit_pos: ray_at(root_pos, dir, dist),
+            hit_dist: dist,
+            hit_mat: mesh.material.clone(),
+            object: 0,
+            tri,
+            uv: tri_uv(triangle, u, v),
+        })
+    }
+
+    /// the material at uv with its albedo, roughness and emission textures multiplied in.
+    /// roughness textures use the gltf layout, green scales roughness or smoothness and blue scales metallic
+    fn textured_material(
+        &self,
+        mat: &raytrace_shader::RayTracingMaterial,
+        uv: [f32; 2],
+    ) -> raytrace_shader::RayTracingMaterial {
+        let mut mat = mat.clone();
+        if mat.textures[0] >= 0 {
+            let texel = sample_texture(&self.textures, mat.textures[0], uv, true);
+            (0..3).for_each(|i| mat.colour[i] *= texel[i]);
+        }
+        if mat.textures[1] >= 0 {
+            let texel = sample_texture(&self.textures, mat.textures[1], uv, false);
+            mat.settings[1] *= texel[1];
+            if mat.settings[3] == PBR_FLAG {mat.settings[0] *= texel[2];}
+        }
+        if mat.textures[2] >= 0 {
+            let texel = sample_texture(&self.textures, mat.textures[2], uv, true);
+            (0..3).for_each(|i| mat.emission[i] *= texel[i]);
+        }
+        mat
+    }
+
+    /// textures the material of a hit and bends the normal of triangles with a tangent space normal map
+    fn apply_textures(
+        &self,
+        hit: &mut RayHit,
+    ) {
+        hit.hit_mat = self.textured_material(&hit.hit_mat, hit.uv);
+        if hit.hit_mat.textures[3] < 0 || hit.object < self.spheres.len() as u32 {return;}
+
+        let mesh = &self.meshes[(hit.object - self.spheres.len() as u32) as usize];
+        let tri = &self.triangles[hit.tri as usize];
+        let normal = hit.hit_normal;
+        let tri_tangent = transform_dir(&mesh.object_to_world, [tri.tangent[0], tri.tangent[1], tri.tangent[2]]);
+        let tangent = sub(tri_tangent, scale(normal, dot(normal, tri_tangent)));
+        if tri.tangent[3] == 0.0 || dot(tangent, tangent) <= 0.0 {return;}
+        let tangent = normalize(tangent);
+        let bitangent = scale(cross(normal, tangent), tri.tangent[3]);
+
+        let texel = sample_texture(&self.textures, hit.hit_mat.textures[3], hit.uv, false);
+        let mapped = [0, 1, 2].map(|i| texel[i] * 2.0 - 1.0);
+        let mapped_normal = normalize(add(add(scale(tangent, mapped[0]), scale(bitangent, mapped[1])), scale(normal, mapped[2])));
+        // kept on the side of the front face like the interpolated normal
+        if dot(mapped_normal, normal_to_world(mesh, [tri.normal[0], tri.normal[1], tri.normal[2]])) > 0.0 {hit.hit_normal = mapped_normal;}
+    }
+
+    fn environment_light(
+        &self,
+        dir: [f32; 3],
+    ) -> [f32; 3] {
+        match &self.environment {
+            Environment::None => [0.0; 3],
+            Environment::Gradient => {
+                let a = 0.5 * (dir[1] + 1.0);
+                add(scale([1.0; 3], 1.0 - a), scale([0.5, 0.7, 1.0], a))
+            }
+            Environment::Map(map) => environment_map_light(map, dir),
+            Environment::Sky(sky) => add(sky_light(sky, dir), sun_light(sky, dir)),
+        }
+    }
+
+    /// whether light sampling can pick directions from the environment, the whole map or the sun of the sky
+    fn sampled_environment(&self) -> bool {
+        matches!(self.environment, Environment::Map(_) | Environment::Sky(_))
+    }
+
+    /// the part of the environment light that light sampling can find
+    fn sampled_environment_light(
+        &self,
+        dir: [f32; 3],
+    ) -> [f32; 3] {
+        match &self.environment {
+            Environment::Map(map) => environment_map_light(map, dir),
+            Environment::Sky(sky) => sun_light(sky, dir),
+            _ => [0.0; 3],
+        }
+    }
+
+    /// (direction, solid angle pdf)
+    fn sample_environment(
+        &self,
+        state: &mut u32,
+    ) -> ([f32; 3], f32) {
+        match &self.environment {
+            Environment::Map(map) => sample_environment_map(map, state),
+            Environment::Sky(sky) => sample_sun(sky, state),
+            _ => ([0.0; 3], 0.0),
+        }
+    }
+
+    /// solid angle pdf of sample_environment picking dir
+    fn environment_pdf(
+        &self,
+        dir: [f32; 3],
+    ) -> f32 {
+        match &self.environment {
+            Environment::Map(map) => environment_map_pdf(map, dir),
+            Environment::Sky(sky) if dot(dir, sky.sun_dir) >= sky.cos_sun_radius => 1.0 / (2.0 * std::f32::consts::PI * (1.0 - sky.cos_sun_radius)),
+            _ => 0.0,
+        }
+    }
+
+    /// the emitters and, if it can be sampled, the environment
+    fn num_light_choices(&self) -> u32 {
+        self.lights.len() as u32 + self.sampled_environment() as u32
+    }
+
+    /// (direction, solid angle pdf, distance to the sampled point, texture coordinates of the sampled point)
+    fn sample_mesh_light(
+        &self,
+        light: &raytrace_shader::Light,
+        mesh: &raytrace_shader::Mesh,
+        pos: [f32; 3],
+        state: &mut u32,
+    ) -> ([f32; 3], f32, f32, [f32; 2]) {
+        let tri_index = light.first_tri + ((scale_to_range01(hash(state)) * light.num_tris as f32) as u32).min(light.num_tris - 1);
+        let tri = &self.triangles[tri_index as usize];
+
+        let root = scale_to_range01(hash(state)).sqrt();
+        let v = scale_to_range01(hash(state));
+        let edge_one = [tri.edge_one[0], tri.edge_one[1], tri.edge_one[2]];
+        let edge_two = [tri.edge_two[0], tri.edge_two[1], tri.edge_two[2]];
+        let object_point = add(add([tri.a[0], tri.a[1], tri.a[2]], scale(edge_one, root * (1.0 - v))), scale(edge_two, root * v));
+        let point = transform_point(&mesh.object_to_world, object_point);
+        let uv = tri_uv(tri, root * (1.0 - v), root * v);
+
+        let to_light = sub(point, pos);
+        let dist = dot(to_light, to_light).sqrt();
+        let dir = scale(to_light, 1.0 / dist);
+        (dir, mesh_light_pdf(world_face_normal(mesh, tri), light.num_tris, dir, dist), dist, uv)
+    }
+
+    /// pdf of light sampling choosing the direction that reached an emitter from pos, including the choice of light
+    fn light_pdf(
+        &self,
+        pos: [f32; 3],
+        dir: [f32; 3],
+        hit: &RayHit,
+    ) -> f32 {
+        let num_spheres = self.spheres.len() as u32;
+        let pdf = if hit.object < num_spheres {
+            let one_minus_cos_max = sphere_cone_size(&self.spheres[hit.object as usize], pos);
+            if one_minus_cos_max > 0.0 {1.0 / (2.0 * std::f32::consts::PI * one_minus_cos_max)} else {0.0}
+        } else {
+            let mesh = &self.meshes[(hit.object - num_spheres) as usize];
+            mesh_light_pdf(world_face_normal(mesh, &self.triangles[hit.tri as usize]), mesh.len, dir, hit.hit_dist)
+        };
+        pdf / self.num_light_choices() as f32
+    }
+
+    /// light arriving directly from one randomly chosen emitter and reflected along -dir by the surface.
+    /// with mis the sample is weighted against the bsdf sampling the same direction
+    fn sample_lights(
+        &self,
+        pos: [f32; 3],
+        normal: [f32; 3],
+        dir: [f32; 3],
+        surface_mat: &raytrace_shader::RayTracingMaterial,
+        use_mis: bool,
+        state: &mut u32,
+    ) -> [f32; 3] {
+        let num_choices = self.num_light_choices();
+        if num_choices == 0 {return [0.0; 3];}
+
+        let light_index = ((scale_to_range01(hash(state)) * num_choices as f32) as u32).min(num_choices - 1);
+        let num_spheres = self.spheres.len() as u32;
+
+        // the environment is the one choice past the emitters
+        let (light_dir, pdf, dist, emitted_light) = if light_index == self.lights.len() as u32 {
+            let (light_dir, pdf) = self.sample_environment(state);
+            (light_dir, pdf, f32::MAX, self.sampled_environment_light(light_dir))
+        } else {
+            let light = &self.lights[light_index as usize];
+            let (light_dir, pdf, dist, uv, mat) = if light.object < num_spheres {
+                let sphere = &self.spheres[light.object as usize];
+                let (light_dir, pdf) = sample_sphere_light(sphere, pos, state);
+                let (dist, uv) = intersecting_sphere(sphere, pos, light_dir).map_or((f32::MAX, [0.0; 2]), |hit| (hit.hit_dist, hit.uv));
+                (light_dir, pdf, dist, uv, &sphere.material)
+            } else {
+                let mesh = &self.meshes[(light.object - num_spheres) as usize];
+                let (light_dir, pdf, dist, uv) = self.sample_mesh_light(light, mesh, pos, state);
+                (light_dir, pdf, dist, uv, &mesh.material)
+            };
+            let mat = self.textured_material(mat, uv);
+            (light_dir, pdf, dist, scale([mat.emission[0], mat.emission[1], mat.emission[2]], mat.emission[3]))
+        };
+
+        let pdf = pdf / num_choices as f32;
+        let (brdf, brdf_pdf) = eval_bsdf(dir, normal, surface_mat, light_dir);
+        if pdf <= 0.0 || brdf_pdf <= 0.0 {return [0.0; 3];}
+
+        // anything closer than the light blocks it
+        if self.world_hit(pos, light_dir).map_or(f32::MAX, |hit| hit.hit_dist) < dist * 0.999 {return [0.0; 3];}
+
+        let mis_weight = if use_mis {power_heuristic(pdf, brdf_pdf)} else {1.0};
+        scale(mul(emitted_light, brdf), mis_weight / pdf)
+    }
+
+    fn trace_ray(
+        &self,
+        root_pos: [f32; 3],
+        dir: [f32; 3],
+        state: &mut u32,
+    ) -> [f32; 3] {
+        let mut light = [0.0; 3];
+        let mut colour = [1.0; 3];
+        let mut has_not_hit_visible_object = true;
+
+        // pdf the last bounce chose the ray with, 0 when lights can't be sampled from it
+        let mut last_bsdf_pdf = 0.0;
+        let mut last_pos = root_pos;
+
+        let mut ray_pos = root_pos;
+        let mut ray_dir = dir;
+
+        for _ in 0..=self.max_bounces {
+            let Some(mut hit) = self.world_hit(ray_pos, ray_dir) else {
+                // the part of the environment that light sampling can find is weighted the same way as emitters
+                let mut env_light = self.environment_light(ray_dir);
+                if last_bsdf_pdf > 0.0 && self.sampled_environment() {
+                    let weight = match self.sampling_mode {
+                        SamplingMode::Lights => 0.0,
+                        SamplingMode::Mis => power_heuristic(last_bsdf_pdf, self.environment_pdf(ray_dir) / self.num_light_choices() as f32),
+                        SamplingMode::Bsdf => 1.0,
+                    };
+                    env_light = sub(env_light, scale(self.sampled_environment_light(ray_dir), 1.0 - weight));
+                }
+                light = add(light, mul(env_light, colour));
+                break;
+            };
+            self.apply_textures(&mut hit);
+
+            let is_invis = hit.hit_mat.settings[3] == INVIS_FLAG;
+            ray_pos = hit.hit_pos.map(|c| c + if is_invis {0.001} else {0.0});
+
+            if is_invis && has_not_hit_visible_object {
+                ray_pos = add(hit.hit_pos, scale(ray_dir, 0.001));
+                continue;
+            } else {has_not_hit_visible_object = false;}
+
+            // emitters found by a bounce that also sampled the lights are weighted against, or replaced by, that sample
+            let emission = hit.hit_mat.emission;
+            let mut emitted_light = scale([emission[0], emission[1], emission[2]], emission[3]);
+            // light sampling never reaches the back of a mesh light or the inside of a sphere light, so those are kept
+            if last_bsdf_pdf > 0.0 && emission[3] > 0.0 && self.sampling_mode != SamplingMode::Bsdf {
+                let emitter_pdf = self.light_pdf(last_pos, ray_dir, &hit);
+                match self.sampling_mode {
+                    SamplingMode::Lights if emitter_pdf > 0.0 => emitted_light = [0.0; 3],
+                    SamplingMode::Mis => emitted_light = scale(emitted_light, power_heuristic(last_bsdf_pdf, emitter_pdf)),
+                    _ => {}
+                }
+            }
+            light = add(light, mul(emitted_light, colour));
+
+            let in_dir = ray_dir;
+            let mut weight = [hit.hit_mat.colour[0], hit.hit_mat.colour[1], hit.hit_mat.colour[2]];
+            let mut bsdf_pdf = 0.0;
+            if hit.hit_mat.settings[3] == DIELECTRIC_FLAG {
+                ray_dir = dielectric_dir(ray_dir, hit.hit_normal, hit.hit_mat.settings[0], state);
+                ray_pos = add(hit.hit_pos, scale(ray_dir, 0.001));
+            } else if hit.hit_mat.settings[3] == PBR_FLAG {
+                (ray_dir, weight, bsdf_pdf) = pbr_dir(ray_dir, hit.hit_normal, &hit.hit_mat, state);
+            } else {
+                let is_specular = scale_to_range01(hash(state)) < hit.hit_mat.settings[0];
+                ray_dir = adjust_dir(ray_dir, hit.hit_normal, &hit.hit_mat, is_specular, state);
+                // the fuzzy metal model has no known pdf, only purely lambertian bounces can sample lights
+                let is_diffuse = hit.hit_mat.settings[2] == 0.0 && (!is_specular || hit.hit_mat.settings[1] == 0.0);
+                if is_diffuse {bsdf_pdf = dot(hit.hit_normal, ray_dir).max(0.000001) / std::f32::consts::PI;}
+            }
+
+            if bsdf_pdf > 0.0 && self.sampling_mode != SamplingMode::Bsdf {
+                let direct_light = self.sample_lights(hit.hit_pos, hit.hit_normal, in_dir, &hit.hit_mat, self.sampling_mode == SamplingMode::Mis, state);
+                light = add(light, mul(direct_light, colour));
+            }
+            last_bsdf_pdf = bsdf_pdf;
+            last_pos = hit.hit_pos;
+            colour = mul(colour, weight);
+
+            let p = colour[0].max(colour[1].max(colour[2]));
+            if scale_to_range01(hash(state)) >= p {
+                break;
+            }
+            colour = scale(colour, 1.0 / p);
+        }
+
+        light
+    }
+}
+
+
+/// RANDOM FUNCTIONS
+
+fn hash(state: &mut u32) -> u32 {
+    *state ^= 2747636419;
+    *state = state.wrapping_mul(2654435769);
+    *state ^= *state >> 16;
+    *state = state.wrapping_mul(2654435769);
+    *state ^= *state >> 16;
+    *state = state.wrapping_mul(2654435769);
+    *state
+}
+
+fn scale_to_range01(state: u32) -> f32 {
+    state as f32 / UINT_MAX
+}
+
+fn random_value_normal_distribution(state: &mut u32) -> f32 {
+    let theta = 2.0 * 3.1415926 * scale_to_range01(hash(state));
+    let rho = (-2.0 * scale_to_range01(hash(state)).ln()).sqrt();
+    rho * theta.cos()
+}
+
+fn random_point_on_unit_sphere(state: &mut u32) -> [f32; 3] {
+    let x = random_value_normal_distribution(state);
+    let y = random_value_normal_distribution(state);
+    let z = random_value_normal_distribution(state);
+    normalize([x, y, z])
+}
+
+
+/// INTERSECTIONS
+
+fn intersecting_sphere(
+    sphere: &raytrace_shader::Sphere,
+    root_pos: [f32; 3],
+    dir: [f32; 3],
+) -> Option<RayHit> {
+    let l = sub(root_pos, sphere.centre);
+
+    let a = dot(dir, dir);
+    let half_b = dot(dir, l);
+    let c = dot(l, l) - sphere.radius * sphere.radius;
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {return None;}
+
+    let mut dist = (-half_b - discriminant.sqrt()) / a;
+    // rays inside a dielectric sphere leave through the far side
+    if dist <= 0.001 && sphere.material.settings[3] == DIELECTRIC_FLAG {
+        dist = (-half_b + discriminant.sqrt()) / a;
+    }
+    let pos = ray_at(root_pos, dir, dist);
+    let normal = normalize(sub(pos, sphere.centre));
+    Some(RayHit {
+        hit_normal: normal,
+        hit_pos: pos,
+        hit_dist: dist,
+        hit_mat: sphere.material.clone(),
+        object: 0,
+        tri: 0,
+        uv: sphere_uv(normal),
+    })
+}
+
+/// equirectangular texture coordinates of a point on a sphere, matching the layout of environment maps
+fn sphere_uv(normal: [f32; 3]) -> [f32; 2] {
+    [normal[2].atan2(normal[0]) / (2.0 * std::f32::consts::PI) + 0.5, normal[1].clamp(-1.0, 1.0).acos() / std::f32::consts::PI]
+}
+
+/// (hit distance, u, v) where u and v are the barycentric weights of the second and third points
+fn intersecting_tri(
+    tri: &raytrace_shader::Triangle,
+    root_pos: [f32; 3],
+    dir: [f32; 3],
+    cull_back_faces: bool,
+) -> Option<(f32, f32, f32)> {
+    let normal = [tri.normal[0], tri.normal[1], tri.normal[2]];
+    if cull_back_faces && dot(dir, normal) >= 0.0 {return None;}
+
+    let ao = sub(root_pos, [tri.a[0], tri.a[1], tri.a[2]]);
+    let dao = cross(ao, dir);
+
+    let det = -dot(dir, normal);
+    if det == 0.0 {return None;}
+
+    let inv_det = 1.0 / det;
+    let dist = dot(ao, normal) * inv_det;
+    if dist < 0.0 {return None;}
+
+    let u = dot([tri.edge_two[0], tri.edge_two[1], tri.edge_two[2]], dao) * inv_det;
+    if u < 0.0 {return None;}
+
+    let v = -dot([tri.edge_one[0], tri.edge_one[1], tri.edge_one[2]], dao) * inv_det;
+    if v < 0.0 {return None;}
+
+    if 1.0 - u - v < 0.0 {return None;}
+
+    Some((dist, u, v))
+}
+
+/// turns a point on the image plane into a camera space (offset, direction), see get_sample_centre.
+/// None for points outside what the projection covers, like the corners of a fisheye image
+fn project(
+    projection: Projection,
+    point: [f32; 3],
+) -> Option<([f32; 3], [f32; 3])> {
+    match projection {
+        Projection::Perspective => Some(([0.0; 3], point)),
+        Projection::Orthographic => Some(([0.0, point[1], point[2]], [1.0, 0.0, 0.0])),
+        Projection::Fisheye => {
+            // the angle from the camera direction is the distance from the centre of the image
+            let angle = (point[1] * point[1] + point[2] * point[2]).sqrt();
+            if angle > std::f32::consts::PI {return None;}
+            let across = if angle > 0.0 {angle.sin() / angle} else {0.0};
+            Some(([0.0; 3], [angle.cos(), point[1] * across, point[2] * across]))
+        }
+        Projection::Equirectangular => {
+            // longitude goes right along -z and latitude goes up along y
+            let longitude = -point[2];
+            let latitude = point[1];
+            Some(([0.0; 3], [latitude.cos() * longitude.cos(), latitude.sin(), -latitude.cos() * longitude.sin()]))
+        }
+    }
+}
+
+/// object space normals are transformed by the inverse transpose so they stay perpendicular to scaled surfaces
+fn normal_to_world(
+    mesh: &raytrace_shader::Mesh,
+    normal: [f32; 3],
+) -> [f32; 3] {
+    let m = &mesh.world_to_object;
+    normalize([0, 1, 2].map(|row| m[row][0] * normal[0] + m[row][1] * normal[1] + m[row][2] * normal[2]))
+}
+
+/// edge_one x edge_two in world space, its length is twice the world space area
+fn world_face_normal(
+    mesh: &raytrace_shader::Mesh,
+    tri: &raytrace_shader::Triangle,
+) -> [f32; 3] {
+    let edge_one = transform_dir(&mesh.object_to_world, [tri.edge_one[0], tri.edge_one[1], tri.edge_one[2]]);
+    let edge_two = transform_dir(&mesh.object_to_world, [tri.edge_two[0], tri.edge_two[1], tri.edge_two[2]]);
+    cross(edge_one, edge_two)
+}
+
+/// vertex normals interpolated across the triangle, kept on the side of the front face so
+/// back face hits on dielectrics are still recognised
+fn shading_normal(
+    tri: &raytrace_shader::Triangle,
+    u: f32,
+    v: f32,
+) -> [f32; 3] {
+    let face_normal = normalize([tri.normal[0], tri.normal[1], tri.normal[2]]);
+    let normal = [0, 1, 2].map(|i| tri.normal_a[i] * (1.0 - u - v) + tri.normal_b[i] * u + tri.normal_c[i] * v);
+    if dot(normal, face_normal) <= 0.0 {return face_normal;}
+    normalize(normal)
+}
+
+/// texture coordinates interpolated across the triangle
+fn tri_uv(
+    tri: &raytrace_shader::Triangle,
+    u: f32,
+    v: f32,
+) -> [f32; 2] {
+    [0, 1].map(|i| tri.uv_ab[i] * (1.0 - u - v) + tri.uv_ab[i + 2] * u + tri.uv_c[i] * v)
+}
+
+
+fn adjust_dir(
+    dir: [f32; 3],
+    normal: [f32; 3],
+    mat: &raytrace_shader::RayTracingMaterial,
+    specular: bool,
+    state: &mut u32,
+) -> [f32; 3] {
+    let diffuse_dir = normalize(add(normal, random_point_on_unit_sphere(state))); // lambertian
+    let specular_dir = reflect(dir, normal); // metal
+    let fuzz = scale(random_point_on_unit_sphere(state), mat.settings[2]); // metal fuzz
+
+    let t = mat.settings[1] * specular as u32 as f32;
+    let mixed = add(scale(diffuse_dir, 1.0 - t), scale(specular_dir, t));
+    normalize(add(mixed, fuzz))
+}
+
+
+/// exact fresnel reflectance of unpolarised light, eta is the incident index of refraction over the transmitted one
+fn fresnel(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {return 1.0;} // total internal reflection
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_s * r_s + r_p * r_p)
+}
+
+fn dielectric_dir(
+    dir: [f32; 3],
+    normal: [f32; 3],
+    ior: f32,
+    state: &mut u32,
+) -> [f32; 3] {
+    let front_face = dot(dir, normal) < 0.0;
+    let facing_normal = if front_face {normal} else {scale(normal, -1.0)};
+    let eta = if front_face {1.0 / ior} else {ior};
+
+    let cos_i = (-dot(dir, facing_normal)).min(1.0);
+    let reflectance = fresnel(cos_i, eta);
+    if reflectance >= 1.0 || scale_to_range01(hash(state)) < reflectance {
+        return reflect(dir, facing_normal);
+    }
+    normalize(refract(dir, facing_normal, eta))
+}
+
+
+
+/// MICROFACET BRDF
+
+fn luminance(col: [f32; 3]) -> f32 {
+    dot(col, [0.2126, 0.7152, 0.0722])
+}
+
+fn fresnel_schlick(f0: [f32; 3], cos_theta: f32) -> [f32; 3] {
+    f0.map(|f| f + (1.0 - f) * (1.0 - cos_theta).powf(5.0))
+}
+
+fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (std::f32::consts::PI * d * d)
+}
+
+fn smith_g1(n_dot_x: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    2.0 * n_dot_x / (n_dot_x + (a2 + (1.0 - a2) * n_dot_x * n_dot_x).sqrt())
+}
+
+/// columns of a basis with the given direction as z
+fn basis_around(z: [f32; 3]) -> [[f32; 3]; 3] {
+    let x = normalize(cross(if z[0].abs() > 0.9 {[0.0, 1.0, 0.0]} else {[1.0, 0.0, 0.0]}, z));
+    [x, cross(z, x), z]
+}
+
+/// the direction with the given coordinates in the basis
+fn from_basis(basis: [[f32; 3]; 3], coords: [f32; 3]) -> [f32; 3] {
+    add(add(scale(basis[0], coords[0]), scale(basis[1], coords[1])), scale(basis[2], coords[2]))
+}
+
+fn ggx_half_vector(
+    normal: [f32; 3],
+    alpha: f32,
+    state: &mut u32,
+) -> [f32; 3] {
+    let u = scale_to_range01(hash(state));
+    let phi = 2.0 * std::f32::consts::PI * scale_to_range01(hash(state));
+    let cos_theta = ((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+    normalize(from_basis(basis_around(normal), [phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta]))
+}
+
+fn pbr_specular_prob(
+    mat: &raytrace_shader::RayTracingMaterial,
+    n_dot_v: f32,
+) -> f32 {
+    let base_colour = [mat.colour[0], mat.colour[1], mat.colour[2]];
+    let f0 = base_colour.map(|c| 0.08 * mat.settings[2] * (1.0 - mat.settings[0]) + c * mat.settings[0]);
+    let specular_amount = luminance(fresnel_schlick(f0, n_dot_v));
+    let diffuse_amount = luminance(scale(base_colour, 1.0 - mat.settings[0])) * (1.0 - specular_amount);
+    (specular_amount / (specular_amount + diffuse_amount).max(0.000001)).clamp(0.1, 1.0)
+}
+
+/// (brdf * cos, pdf of pbr_dir picking new_dir)
+fn pbr_eval(
+    dir: [f32; 3],
+    normal: [f32; 3],
+    mat: &raytrace_shader::RayTracingMaterial,
+    new_dir: [f32; 3],
+) -> ([f32; 3], f32) {
+    let base_colour = [mat.colour[0], mat.colour[1], mat.colour[2]];
+    let metallic = mat.settings[0];
+    let alpha = (mat.settings[1] * mat.settings[1]).max(0.001);
+    let f0 = base_colour.map(|c| 0.08 * mat.settings[2] * (1.0 - metallic) + c * metallic);
+    let diffuse_colour = scale(base_colour, 1.0 - metallic);
+
+    let view = scale(dir, -1.0);
+    let n_dot_v = dot(normal, view);
+    let n_dot_l = dot(normal, new_dir);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {return ([0.0; 3], 0.0);}
+
+    let half_vector = normalize(add(view, new_dir));
+    let n_dot_h = dot(normal, half_vector).max(0.0);
+    let v_dot_h = dot(view, half_vector).max(0.0);
+
+    let d = ggx_distribution(n_dot_h, alpha);
+    let f = fresnel_schlick(f0, v_dot_h);
+    let g = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+    let specular = d * g / (4.0 * n_dot_v * n_dot_l);
+    let brdf = [0, 1, 2].map(|i| diffuse_colour[i] * (1.0 - f[i]) / std::f32::consts::PI + f[i] * specular);
+
+    let specular_prob = pbr_specular_prob(mat, n_dot_v);
+    let pdf = specular_prob * d * n_dot_h / (4.0 * v_dot_h.max(0.000001)) + (1.0 - specular_prob) * n_dot_l / std::f32::consts::PI;
+    (scale(brdf, n_dot_l), pdf)
+}
+
+/// (new direction, brdf * cos / pdf, pdf)
+fn pbr_dir(
+    dir: [f32; 3],
+    normal: [f32; 3],
+    mat: &raytrace_shader::RayTracingMaterial,
+    state: &mut u32,
+) -> ([f32; 3], [f32; 3], f32) {
+    let n_dot_v = -dot(normal, dir);
+    if n_dot_v <= 0.0 {return (reflect(dir, normal), [0.0; 3], 0.0);}
+
+    let new_dir = if scale_to_range01(hash(state)) < pbr_specular_prob(mat, n_dot_v) {
+        reflect(dir, ggx_half_vector(normal, (mat.settings[1] * mat.settings[1]).max(0.001), state))
+    } else {
+        normalize(add(normal, random_point_on_unit_sphere(state)))
+    };
+
+    let (brdf, pdf) = pbr_eval(dir, normal, mat, new_dir);
+    (new_dir, scale(brdf, 1.0 / pdf.max(0.000001)), pdf)
+}
+
+/// (brdf * cos, pdf) for the materials that lights can be sampled from, pbr and lambertian
+fn eval_bsdf(
+    dir: [f32; 3],
+    normal: [f32; 3],
+    mat: &raytrace_shader::RayTracingMaterial,
+    new_dir: [f32; 3],
+) -> ([f32; 3], f32) {
+    if mat.settings[3] == PBR_FLAG {return pbr_eval(dir, normal, mat, new_dir);}
+
+    let n_dot_l = dot(normal, new_dir);
+    if n_dot_l <= 0.0 {return ([0.0; 3], 0.0);}
+    (scale([mat.colour[0], mat.colour[1], mat.colour[2]], n_dot_l / std::f32::consts::PI), n_dot_l / std::f32::consts::PI)
+}
+
+
+/// TEXTURES
+
+fn srgb_to_linear(col: f32) -> f32 {
+    if col > 0.04045 {((col + 0.055) / 1.055).powf(2.4)} else {col / 12.92}
+}
+
+/// x and y are at most one texel outside the image and wrap around to the other side
+fn texture_texel(
+    texture: &raytrace_shader::Texture,
+    texels: &[u32],
+    x: i32,
+    y: i32,
+    srgb: bool,
+) -> [f32; 4] {
+    let wrapped_x = (x + texture.width as i32) as u32 % texture.width;
+    let wrapped_y = (y + texture.height as i32) as u32 % texture.height;
+    let texel = texels[(texture.offset + wrapped_y * texture.width + wrapped_x) as usize].to_le_bytes();
+    let mut texel = texel.map(|c| c as f32 / 255.0);
+    if srgb {
+        (0..3).for_each(|i| texel[i] = srgb_to_linear(texel[i]));
+    }
+    texel
+}
+
+/// bilinear filtered and repeating, colour textures are stored in srgb and converted before filtering
+fn sample_texture(
+    atlas: &TextureAtlas,
+    index: i32,
+    uv: [f32; 2],
+    srgb: bool,
+) -> [f32; 4] {
+    let texture = &atlas.textures[index as usize];
+    let pos = [
+        (uv[0] - uv[0].floor()) * texture.width as f32 - 0.5,
+        (uv[1] - uv[1].floor()) * texture.height as f32 - 0.5,
+    ];
+    let base = pos.map(|p| p.floor());
+    let f = [pos[0] - base[0], pos[1] - base[1]];
+    let (x, y) = (base[0] as i32, base[1] as i32);
+
+    let texel = |x, y| texture_texel(texture, &atlas.texels, x, y, srgb);
+    let mix = |a: [f32; 4], b: [f32; 4], t: f32| [0, 1, 2, 3].map(|i| a[i] * (1.0 - t) + b[i] * t);
+    let top = mix(texel(x, y), texel(x + 1, y), f[0]);
+    let bottom = mix(texel(x, y + 1), texel(x + 1, y + 1), f[0]);
+    mix(top, bottom, f[1])
+}
+
+
+/// ENVIRONMENT
+
+fn environment_uv(
+    map: &EnvironmentMap,
+    dir: [f32; 3],
+) -> [f32; 2] {
+    let phi = dir[2].atan2(dir[0]) + map.rotation;
+    let u = phi / (2.0 * std::f32::consts::PI) + 0.5;
+    [u - u.floor(), dir[1].clamp(-1.0, 1.0).acos() / std::f32::consts::PI]
+}
+
+fn environment_dir(
+    map: &EnvironmentMap,
+    uv: [f32; 2],
+) -> [f32; 3] {
+    let phi = (uv[0] - 0.5) * 2.0 * std::f32::consts::PI - map.rotation;
+    let theta = uv[1] * std::f32::consts::PI;
+    [theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()]
+}
+
+fn environment_texel(
+    map: &EnvironmentMap,
+    uv: [f32; 2],
+) -> [u32; 2] {
+    [
+        ((uv[0] * map.size[0] as f32) as u32).min(map.size[0] - 1),
+        ((uv[1] * map.size[1] as f32) as u32).min(map.size[1] - 1),
+    ]
+}
+
+fn environment_map_light(
+    map: &EnvironmentMap,
+    dir: [f32; 3],
+) -> [f32; 3] {
+    let [x, y] = environment_texel(map, environment_uv(map, dir));
+    let pixel = map.pixels[(x + y * map.size[0]) as usize];
+    scale([pixel[0], pixel[1], pixel[2]], map.intensity)
+}
+
+/// solid angle pdf of sample_environment_map picking dir
+fn environment_map_pdf(
+    map: &EnvironmentMap,
+    dir: [f32; 3],
+) -> f32 {
+    let uv = environment_uv(map, dir);
+    let [x, y] = environment_texel(map, uv);
+    let sin_theta = (uv[1] * std::f32::consts::PI).sin();
+    if sin_theta <= 0.0 {return 0.0;}
+
+    let [width, height] = map.size;
+    let row_prob = map.cdf[y as usize] - if y > 0 {map.cdf[y as usize - 1]} else {0.0};
+    let row_start = (height + y * width) as usize;
+    let pixel_prob = map.cdf[row_start + x as usize] - if x > 0 {map.cdf[row_start + x as usize - 1]} else {0.0};
+    row_prob * pixel_prob * (width * height) as f32 / (2.0 * std::f32::consts::PI * std::f32::consts::PI * sin_theta)
+}
+
+/// first index in the len values from start that is above u
+fn search_cdf(
+    cdf: &[f32],
+    start: u32,
+    len: u32,
+    u: f32,
+) -> u32 {
+    let mut low = 0;
+    let mut high = len - 1;
+    while low < high {
+        let mid = (low + high) / 2;
+        if cdf[(start + mid) as usize] > u {high = mid;}
+        else {low = mid + 1;}
+    }
+    low
+}
+
+/// (direction, solid angle pdf) picked in proportion to the brightness of the map
+fn sample_environment_map(
+    map: &EnvironmentMap,
+    state: &mut u32,
+) -> ([f32; 3], f32) {
+    let [width, height] = map.size;
+    let row = search_cdf(&map.cdf, 0, height, scale_to_range01(hash(state)));
+    let column = search_cdf(&map.cdf, height + row * width, width, scale_to_range01(hash(state)));
+
+    let u = (column as f32 + scale_to_range01(hash(state))) / width as f32;
+    let v = (row as f32 + scale_to_range01(hash(state))) / height as f32;
+    let dir = environment_dir(map, [u, v]);
+    (dir, environment_map_pdf(map, dir))
+}
+
+/// perez et al's sky luminance distribution for Y, x and y
+fn perez(
+    sky: &Sky,
+    cos_theta: f32,
+    gamma: f32,
+    cos_gamma: f32,
+) -> [f32; 3] {
+    let [a, b, c, d, e] = sky.perez;
+    [0, 1, 2].map(|i| (1.0 + a[i] * (b[i] / cos_theta).exp()) * (1.0 + c[i] * (d[i] * gamma).exp() + e[i] * cos_gamma * cos_gamma))
+}
+
+fn sky_light(
+    sky: &Sky,
+    dir: [f32; 3],
+) -> [f32; 3] {
+    // the model only covers the upper half, the horizon colour is carried on below it
+    let cos_theta = dir[1].max(0.001);
+    let cos_gamma = dot(dir, sky.sun_dir).clamp(-1.0, 1.0);
+    let [luminance, x, y] = mul(sky.zenith, perez(sky, cos_theta, cos_gamma.acos(), cos_gamma));
+
+    let xyz = [x / y * luminance, luminance, (1.0 - x - y) / y * luminance];
+    [
+        3.2406 * xyz[0] - 1.5372 * xyz[1] - 0.4986 * xyz[2],
+        -0.9689 * xyz[0] + 1.8758 * xyz[1] + 0.0415 * xyz[2],
+        0.0557 * xyz[0] - 0.2040 * xyz[1] + 1.0570 * xyz[2],
+    ].map(|c| c.max(0.0))
+}
+
+fn sun_light(
+    sky: &Sky,
+    dir: [f32; 3],
+) -> [f32; 3] {
+    if dot(dir, sky.sun_dir) < sky.cos_sun_radius {return [0.0; 3];}
+    sky.sun_radiance
+}
+
+
+/// LIGHT SAMPLING
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    a / (a + other_pdf * other_pdf)
+}
+
+/// 1 - cos of the half angle of the cone the sphere covers seen from pos, 0 if pos is inside
+fn sphere_cone_size(
+    sphere: &raytrace_shader::Sphere,
+    pos: [f32; 3],
+) -> f32 {
+    let to_centre = sub(sphere.centre, pos);
+    let dist2 = dot(to_centre, to_centre);
+    let radius2 = sphere.radius * sphere.radius;
+    if dist2 <= radius2 {return 0.0;}
+
+    let cos_max = (1.0 - radius2 / dist2).sqrt();
+    radius2 / dist2 / (1.0 + cos_max)
+}
+
+/// (direction, solid angle pdf) of a point on the part of the sphere facing pos, the pdf is 0 if pos is inside
+fn sample_sphere_light(
+    sphere: &raytrace_shader::Sphere,
+    pos: [f32; 3],
+    state: &mut u32,
+) -> ([f32; 3], f32) {
+    let one_minus_cos_max = sphere_cone_size(sphere, pos);
+    if one_minus_cos_max <= 0.0 {return ([0.0; 3], 0.0);}
+
+    // uniform over the cone
+    let cos_theta = 1.0 - scale_to_range01(hash(state)) * one_minus_cos_max;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * scale_to_range01(hash(state));
+
+    let dir = from_basis(basis_around(normalize(sub(sphere.centre, pos))), [phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta]);
+    (normalize(dir), 1.0 / (2.0 * std::f32::consts::PI * one_minus_cos_max))
+}
+
+/// (direction, solid angle pdf) uniform over the cone of the sun disk
+fn sample_sun(
+    sky: &Sky,
+    state: &mut u32,
+) -> ([f32; 3], f32) {
+    let one_minus_cos_max = 1.0 - sky.cos_sun_radius;
+    let cos_theta = 1.0 - scale_to_range01(hash(state)) * one_minus_cos_max;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * scale_to_range01(hash(state));
+
+    let dir = from_basis(basis_around(sky.sun_dir), [phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta]);
+    (normalize(dir), 1.0 / (2.0 * std::f32::consts::PI * one_minus_cos_max))
+}
+
+/// solid angle pdf of picking a point on a mesh light triangle at dist along dir,
+/// each of the num_tris triangles is picked with the same probability
+fn mesh_light_pdf(
+    normal: [f32; 3],
+    num_tris: u32,
+    dir: [f32; 3],
+    dist: f32,
+) -> f32 {
+    // triangles only emit from their front face
+    let area = dot(normal, normal).sqrt() * 0.5;
+    let cos_light = -dot(dir, normalize(normal));
+    if cos_light <= 0.0 || area == 0.0 {return 0.0;}
+    dist * dist / (cos_light * area * num_tris as f32)
+}
+
+
+/// VECTOR FUNCTIONS
+
+fn reflect(dir: [f32; 3], normal: [f32; 3]) -> [f32; 3] {
+    sub(dir, scale(normal, 2.0 * dot(normal, dir)))
+}
+
+/// the glsl refract, a zero vector for total internal reflection
+fn refract(dir: [f32; 3], normal: [f32; 3], eta: f32) -> [f32; 3] {
+    let n_dot_i = dot(normal, dir);
+    let k = 1.0 - eta * eta * (1.0 - n_dot_i * n_dot_i);
+    if k < 0.0 {return [0.0; 3];}
+    sub(scale(dir, eta), scale(normal, eta * n_dot_i + k.sqrt()))
+}
+
+fn ray_at(root_pos: [f32; 3], dir: [f32; 3], dist: f32) -> [f32; 3] {
+    add(root_pos, scale(dir, dist))
+}
+
+/// multiplies a point by a glsl style matrix of columns
+fn transform_point(m: &[[f32; 4]; 4], p: [f32; 3]) -> [f32; 3] {
+    add(transform_dir(m, p), [m[3][0], m[3][1], m[3][2]])
+}
+
+/// multiplies a direction by the upper 3x3 of a glsl style matrix of columns, ignoring the translation
+fn transform_dir(m: &[[f32; 4]; 4], d: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|row| m[0][row] * d[0] + m[1][row] * d[1] + m[2][row] * d[2])
+}
+
+fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn mul(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] * b[0], a[1] * b[1], a[2] * b[2]]
+}
+
+fn scale(a: [f32; 3], s: f32) -> [f32; 3] {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn normalize(a: [f32; 3]) -> [f32; 3] {
+    scale(a, 1.0 / dot(a, a).sqrt())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::materials::{LambertianMaterial, LightMaterial};
+    use super::super::objects::{RayTracingMesh, Sphere, Transform};
+    use super::super::post_process::ToneMapOperator;
+    use graphics::{Mesh, PositionVertex};
+
+    /// the camera space (offset, normalised direction) of the ray through the centre of a pixel
+    fn pixel_ray(
+        projection: Projection,
+        fov: f32,
+        pixel: [u32; 2],
+        image_size: [u32; 2],
+    ) -> Option<([f32; 3], [f32; 3])> {
+        let half_height = projection.half_height(fov, 2.0);
+        let (offset, dir) = project(projection, get_sample_centre(pixel, image_size, half_height, [0.0; 2]))?;
+        Some((offset, normalize(dir)))
+    }
+
+    fn degrees_between(a: [f32; 3], b: [f32; 3]) -> f32 {
+        dot(normalize(a), normalize(b)).clamp(-1.0, 1.0).acos().to_degrees()
+    }
+
+    const FORWARD: [f32; 3] = [1.0, 0.0, 0.0];
+    const UP: [f32; 3] = [0.0, 1.0, 0.0];
+    /// image right is -z in camera space
+    const RIGHT: [f32; 3] = [0.0, 0.0, -1.0];
+
+    // the images have an odd number of pixels so one is in the centre, and the edge pixels are
+    // half a pixel in from the edge, which the tolerances allow for
+
+    #[test]
+    fn perspective_projection() {
+        let size = [1001, 1001];
+        let (offset, centre) = pixel_ray(Projection::Perspective, 90.0, [500, 500], size).unwrap();
+        assert_eq!(offset, [0.0; 3]);
+        assert!(degrees_between(centre, FORWARD) < 0.01);
+
+        let (_, top) = pixel_ray(Projection::Perspective, 90.0, [500, 0], size).unwrap();
+        assert!((degrees_between(top, FORWARD) - 45.0).abs() < 0.1);
+        assert!(degrees_between(top, UP) < 45.1);
+
+        let (_, right) = pixel_ray(Projection::Perspective, 90.0, [1000, 500], size).unwrap();
+        assert!((degrees_between(right, FORWARD) - 45.0).abs() < 0.1);
+        assert!(degrees_between(right, RIGHT) < 45.1);
+    }
+
+    #[test]
+    fn orthographic_projection() {
+        let size = [1001, 501];
+        let aspect = 1001.0 / 501.0;
+        let rays: Vec<([f32; 3], [f32; 3])> = [[500, 250], [500, 0], [1000, 250], [0, 500]].into_iter()
+            .map(|pixel| pixel_ray(Projection::Orthographic, 90.0, pixel, size).unwrap())
+            .collect();
+
+        // every ray is parallel to the camera direction and starts on the image plane
+        assert!(rays.iter().all(|(offset, dir)| *dir == FORWARD && offset[0] == 0.0));
+        let close = |a: [f32; 3], b: [f32; 3]| (0..3).all(|i| (a[i] - b[i]).abs() < 0.01);
+        assert!(close(rays[0].0, [0.0, 0.0, 0.0]));
+        // ortho_height is 2 so the image is 2 units tall
+        assert!(close(rays[1].0, [0.0, 1.0, 0.0]));
+        assert!(close(rays[2].0, [0.0, 0.0, -aspect]));
+        assert!(close(rays[3].0, [0.0, -1.0, aspect]));
+    }
+
+    #[test]
+    fn fisheye_projection() {
+        let size = [1001, 1001];
+        let (_, centre) = pixel_ray(Projection::Fisheye, 180.0, [500, 500], size).unwrap();
+        assert!(degrees_between(centre, FORWARD) < 0.01);
+
+        // the edges are fov / 2 from the centre
+        let (_, top) = pixel_ray(Projection::Fisheye, 180.0, [500, 0], size).unwrap();
+        assert!(degrees_between(top, UP) < 0.2);
+        let (_, right) = pixel_ray(Projection::Fisheye, 180.0, [1000, 500], size).unwrap();
+        assert!(degrees_between(right, RIGHT) < 0.2);
+        let (_, narrow) = pixel_ray(Projection::Fisheye, 120.0, [500, 0], size).unwrap();
+        assert!((degrees_between(narrow, FORWARD) - 60.0).abs() < 0.2);
+
+        // a full circle fisheye looks backwards at its edge and sees nothing in its corners
+        let (_, behind) = pixel_ray(Projection::Fisheye, 360.0, [500, 0], size).unwrap();
+        assert!(degrees_between(behind, FORWARD) > 179.5);
+        assert!(pixel_ray(Projection::Fisheye, 360.0, [0, 0], size).is_none());
+    }
+
+    #[test]
+    fn equirectangular_projection() {
+        let size = [2001, 1001];
+        let (_, centre) = pixel_ray(Projection::Equirectangular, 90.0, [1000, 500], size).unwrap();
+        assert!(degrees_between(centre, FORWARD) < 0.01);
+
+        // the poles are at the top and bottom
+        let (_, top) = pixel_ray(Projection::Equirectangular, 90.0, [1000, 0], size).unwrap();
+        assert!(degrees_between(top, UP) < 0.2);
+        let (_, bottom) = pixel_ray(Projection::Equirectangular, 90.0, [1000, 1000], size).unwrap();
+        assert!(degrees_between(bottom, [0.0, -1.0, 0.0]) < 0.2);
+
+        // the sides look backwards and the quarters look left and right
+        for x in [0, 2000] {
+            let (_, side) = pixel_ray(Projection::Equirectangular, 90.0, [x, 500], size).unwrap();
+            assert!(degrees_between(side, FORWARD) > 179.5);
+        }
+        let (_, left) = pixel_ray(Projection::Equirectangular, 90.0, [500, 500], size).unwrap();
+        assert!(degrees_between(left, scale(RIGHT, -1.0)) < 0.3);
+        let (_, right) = pixel_ray(Projection::Equirectangular, 90.0, [1500, 500], size).unwrap();
+        assert!(degrees_between(right, RIGHT) < 0.3);
+    }
+
+    fn test_settings(
+        sampling_mode: SamplingMode,
+        environment: Environment,
+        sphere_data: Vec<Sphere>,
+        mesh_data: Vec<RayTracingMesh<PositionVertex>>,
+    ) -> RayTracerSettings<PositionVertex> {
+        RayTracerSettings {
+            sample_jitter: None,
+            num_samples: 32,
+            max_bounces: 8,
+            sampling_mode,
+            environment,
+            sphere_data,
+            mesh_data,
+            instances: Vec::new(),
+            textures: TextureAtlas::default(),
+            fov: 30.0,
+            sensor_offset: [0.0; 2],
+            projection: Projection::Perspective,
+            ortho_height: 2.0,
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            tone_map: ToneMapOperator::Clamp,
+            exposure: 0.0,
+            temporal_reprojection: false,
+        }
+    }
+
+    /// looks along +x at the unit sphere at the origin, which fills most of the image
+    fn test_camera() -> Camera {
+        Camera::new(Some([-4.0, 0.0, 0.0]), Some([1.0, 0.0, 0.0]), None, None)
+    }
+
+    fn grey_sphere(albedo: f32) -> Sphere {
+        Sphere {centre: [0.0; 3], radius: 1.0, material: LambertianMaterial {colour: [albedo; 3]}.into()}
+    }
+
+    /// a sphere inside a uniform environment, which it reflects all of when it is white
+    fn furnace(
+        sampling_mode: SamplingMode,
+        albedo: f32,
+    ) -> RayTracerSettings<PositionVertex> {
+        let environment = Environment::Map(EnvironmentMap::from_pixels([16, 8], vec![[1.0; 4]; 128], 0.0, 1.0));
+        test_settings(sampling_mode, environment, vec![grey_sphere(albedo)], Vec::new())
+    }
+
+    fn white_furnace(sampling_mode: SamplingMode) -> RayTracerSettings<PositionVertex> {
+        furnace(sampling_mode, 1.0)
+    }
+
+    /// a square of n by n cells at height y, so light sampling has 2 n² triangles to pick from when it emits
+    fn square(
+        n: u32,
+        size: f32,
+        y: f32,
+        facing_down: bool,
+        material: raytrace_shader::RayTracingMaterial,
+    ) -> RayTracingMesh<PositionVertex> {
+        let mut vertices = Vec::new();
+        for i in 0..=n {
+            for j in 0..=n {
+                vertices.push(PositionVertex {position: [size * (i as f32 / n as f32 - 0.5), y, size * (j as f32 / n as f32 - 0.5)]});
+            }
+        }
+        let mut indices = Vec::new();
+        for i in 0..n {
+            for j in 0..n {
+                // i steps along x and j along z, so both triangles wind to face -y unless they are flipped
+                let corner = i * (n + 1) + j;
+                let [a, b, c, d] = [corner, corner + n + 1, corner + 1, corner + n + 2];
+                if facing_down {indices.extend([a, b, c, b, d, c]);}
+                else {indices.extend([a, c, b, b, c, d]);}
+            }
+        }
+        RayTracingMesh {
+            mesh: Mesh::new(vertices, indices),
+            uvs: Vec::new(),
+            material,
+            transform: Transform::default(),
+        }
+    }
+
+    /// a light of 32 triangles above the sphere
+    fn light_panel() -> RayTracingMesh<PositionVertex> {
+        square(4, 4.0, 3.0, true, LightMaterial {emission: [1.0, 1.0, 1.0, 2.0]}.into())
+    }
+
+    /// the average of every channel of every pixel of an accumulated image
+    fn mean_brightness(accumulated: &[[f32; 4]]) -> f32 {
+        let total: f32 = accumulated.iter().map(|pixel| (pixel[0] + pixel[1] + pixel[2]) / (3.0 * pixel[3])).sum();
+        total / accumulated.len() as f32
+    }
+
+    /// the mean brightness of the test camera's view in bsdf, lights and mis sampling
+    fn sampling_mode_means(
+        settings: impl Fn(SamplingMode) -> RayTracerSettings<PositionVertex>,
+        num_frames: u32,
+    ) -> [f32; 3] {
+        [SamplingMode::Bsdf, SamplingMode::Lights, SamplingMode::Mis]
+            .map(|sampling_mode| mean_brightness(&CpuRayTracer::new([16, 16], &settings(sampling_mode)).accumulate(&test_camera(), num_frames)))
+    }
+
+    /// light sampling has to converge to the same image as bsdf sampling, which has no pdfs to get wrong
+    fn assert_sampling_modes_agree(
+        means: [f32; 3],
+        tolerance: f32,
+    ) {
+        assert!(means[0] > 0.01, "the scene is not lit, {means:?}");
+        assert!(means.iter().all(|mean| (mean / means[0] - 1.0).abs() < tolerance), "the sampling modes disagree, {means:?}");
+    }
+
+    #[test]
+    fn frames_are_deterministic() {
+        let tracer = CpuRayTracer::new([16, 16], &white_furnace(SamplingMode::Mis));
+        assert_eq!(tracer.render_frame(&test_camera(), 3), tracer.render_frame(&test_camera(), 3));
+        assert_ne!(tracer.render_frame(&test_camera(), 3), tracer.render_frame(&test_camera(), 4));
+    }
+
+    #[test]
+    fn white_furnace_matches_the_environment() {
+        let size = [16, 16];
+
+        // every path ends in the environment with nothing absorbed, so bsdf sampling has no noise at all
+        let bsdf = CpuRayTracer::new(size, &white_furnace(SamplingMode::Bsdf)).accumulate(&test_camera(), 4);
+        for pixel in &bsdf {
+            assert!((0..3).all(|i| (pixel[i] / pixel[3] - 1.0).abs() < 0.001), "bsdf sampling gave {pixel:?}");
+        }
+
+        // light sampling is noisy but has the same mean
+        for sampling_mode in [SamplingMode::Lights, SamplingMode::Mis] {
+            let accumulated = CpuRayTracer::new(size, &white_furnace(sampling_mode)).accumulate(&test_camera(), 4);
+            let brightness = mean_brightness(&accumulated);
+            assert!((brightness - 1.0).abs() < 0.03, "{sampling_mode:?} gave a mean of {brightness}");
+        }
+    }
+
+    #[test]
+    fn sampling_modes_agree_on_a_mesh_light() {
+        // with many triangles in the light a pdf that leaves out picking the triangle is far too bright or dark
+        let means = sampling_mode_means(|sampling_mode| test_settings(sampling_mode, Environment::None, vec![grey_sphere(1.0)], vec![light_panel()]), 4);
+        assert_sampling_modes_agree(means, 0.05);
+    }
+
+    #[test]
+    fn sampling_modes_agree_in_a_grey_furnace() {
+        // half the paths off the sphere are ended by russian roulette, which must not change the light already found
+        let means = sampling_mode_means(|sampling_mode| furnace(sampling_mode, 0.5), 4);
+        assert_sampling_modes_agree(means, 0.03);
+    }
+
+    #[test]
+    fn sampling_modes_agree_over_many_bounces() {
+        // the bottom of the sphere is only lit by light off the floor, which is lit by the panel
+        let floor = square(1, 20.0, -1.0, false, LambertianMaterial {colour: [0.5; 3]}.into());
+        let means = sampling_mode_means(|sampling_mode| {
+            test_settings(sampling_mode, Environment::None, vec![grey_sphere(0.5)], vec![light_panel(), floor.clone()])
+        }, 16);
+        assert_sampling_modes_agree(means, 0.05);
+    }
+}
@@ -19,9 +19,29 @@ mod diffuse_shader {
 }
 
 
+/// The cameras of two frames in a row, for moving the accumulated image to where it is seen from the new camera
+#[derive(Debug, Clone, Copy)]
+pub struct ReprojectionCameras {
+    pub cam_pos: [f32; 4],
+    pub cam_alignment_mat: [[f32; 4]; 4],
+    pub prev_cam_pos: [f32; 4],
+    pub prev_cam_alignment_mat: [[f32; 4]; 4],
+    pub sensor_offset: [f32; 2],
+    /// tan of half the vertical field of view
+    pub half_height: f32,
+}
+
+
 pub struct DiffusePipeline {
-    image: DeviceImageView,
+    /// the accumulated image is written to one while the other holds the last frame's, they swap every frame
+    images: [DeviceImageView; 2],
+    current: usize,
     image_size: [u32; 2],
+    /// the next frame starts accumulating again
+    reset: bool,
+    /// the cameras for the next frame if it should reuse the reprojected history
+    reprojection: Option<Subbuffer<[diffuse_shader::Reprojection]>>,
+    null_reprojection: Subbuffer<[diffuse_shader::Reprojection]>,
 
     compute_queue: Arc<Queue>,
     compute_pipeline: Arc<ComputePipeline>,
@@ -47,16 +67,27 @@ impl DiffusePipeline {
             |_| {},
         ).unwrap();
 
-        let image = StorageImage::general_purpose_image_view(
+        let images = [0, 1].map(|_| StorageImage::general_purpose_image_view(
             context.memory_allocator(),
             context.compute_queue().clone(),
             image_size,
-            Format::R8G8B8A8_UNORM,
-            ImageUsage::SAMPLED | ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
-        ).unwrap();
+            Format::R32G32B32A32_SFLOAT,
+            ImageUsage::SAMPLED | ImageUsage::STORAGE | ImageUsage::TRANSFER_DST | ImageUsage::TRANSFER_SRC,
+        ).unwrap());
 
         DiffusePipeline {
-            image: image,
+            images,
+            current: 0,
+            reset: false,
+            reprojection: None,
+            null_reprojection: create_reprojection_buffer(context, ReprojectionCameras {
+                cam_pos: [0.0; 4],
+                cam_alignment_mat: [[0.0; 4]; 4],
+                prev_cam_pos: [0.0; 4],
+                prev_cam_alignment_mat: [[0.0; 4]; 4],
+                sensor_offset: [0.0; 2],
+                half_height: 1.0,
+            }),
             compute_queue: context.graphics_queue().clone(),
             compute_pipeline: pipeline,
             image_size,
@@ -66,8 +97,27 @@ impl DiffusePipeline {
     }
 
 
+    /// the image the last frame was accumulated into
     pub fn image(&self) -> DeviceImageView {
-        self.image.clone()
+        self.images[self.current].clone()
+    }
+
+    /// throw away everything accumulated so far, the next frame is the first of a new image
+    pub fn reset(&mut self) {
+        self.reset = true;
+        self.reprojection = None;
+    }
+
+    /// keep the accumulated image for the next frame by moving it to where the new camera sees it, with no more
+    /// than MAX_REPROJECTED_HISTORY frames of it kept. it needs the distance to the first hit in the alpha of the
+    /// next raytraced image, so that pipeline must have write_depth set
+    pub fn reproject(
+        &mut self,
+        context: &VulkanoContext,
+        cameras: ReprojectionCameras,
+    ) {
+        if self.reset {return;}
+        self.reprojection = Some(create_reprojection_buffer(context, cameras));
     }
 
     pub fn next_frame(
@@ -88,7 +138,11 @@ impl DiffusePipeline {
             (self.image_size[1] - 1) / 32 + 1,
         ];
 
+        // the last frame's image becomes the history read by this one
+        self.current = 1 - self.current;
         self.dispatch(&mut builder, next_image, frame_num, group_numbers);
+        self.reset = false;
+        self.reprojection = None;
 
         let command_buffer = builder.build().unwrap();
         let after_future = before_future
@@ -116,15 +170,19 @@ impl DiffusePipeline {
             &self.descriptor_set_allocator,
             desc_layout.clone(),
             [
-                WriteDescriptorSet::image_view(0, self.image.clone()),
-                WriteDescriptorSet::image_view(1, image)
+                WriteDescriptorSet::image_view(0, self.images[self.current].clone()),
+                WriteDescriptorSet::image_view(1, image),
+                WriteDescriptorSet::image_view(2, self.images[1 - self.current].clone()),
+                WriteDescriptorSet::buffer(3, self.reprojection.clone().unwrap_or(self.null_reprojection.clone()))
             ]
         ).unwrap();
 
         let push_constants = diffuse_shader::PushConstants {
             frame: frame_num,
             image_width: self.image_size[0],
-            image_height: self.image_size[1]
+            image_height: self.image_size[1],
+            reset: self.reset as u32,
+            reproject: self.reprojection.is_some() as u32
         };
 
         builder
@@ -136,4 +194,22 @@ impl DiffusePipeline {
     }
 
 
-}
\ No newline at end of file
+}
+
+/// frames of history kept while the camera moves, so lighting seen from the old view fades out quickly
+pub const MAX_REPROJECTED_HISTORY: u32 = 16;
+
+fn create_reprojection_buffer(
+    context: &VulkanoContext,
+    cameras: ReprojectionCameras,
+) -> Subbuffer<[diffuse_shader::Reprojection]> {
+    create_shader_data_buffer(vec![diffuse_shader::Reprojection {
+        cam_pos: cameras.cam_pos,
+        cam_alignment_mat: cameras.cam_alignment_mat,
+        prev_cam_pos: cameras.prev_cam_pos,
+        prev_cam_alignment_mat: cameras.prev_cam_alignment_mat,
+        sensor_offset: cameras.sensor_offset,
+        half_height: cameras.half_height,
+        max_history: MAX_REPROJECTED_HISTORY,
+    }], context, BufferType::Storage)
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::path::Path;
+
+
+/// An equirectangular environment map used as the light arriving from infinitely far away.
+/// The top row of the image is straight up and the middle column faces along +x before rotation
+#[derive(Clone)]
+pub struct EnvironmentMap {
+    pub size: [u32; 2],
+    /// linear radiance, row by row from the top
+    pub pixels: Vec<[f32; 4]>,
+    /// rotation around the up axis in radians
+    pub rotation: f32,
+    /// multiplier for every pixel
+    pub intensity: f32,
+    /// cumulative distributions for importance sampling, see build_cdf
+    pub cdf: Vec<f32>,
+}
+
+impl fmt::Debug for EnvironmentMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnvironmentMap")
+            .field("size", &self.size)
+            .field("rotation", &self.rotation)
+            .field("intensity", &self.intensity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EnvironmentMap {
+    /// loads an hdr or exr image, the rotation is in degrees
+    pub fn load(
+        path: impl AsRef<Path>,
+        rotation: f32,
+        intensity: f32,
+    ) -> image::ImageResult<Self> {
+        let image = image::open(path)?.into_rgba32f();
+        let pixels: Vec<[f32; 4]> = image.pixels().map(|pixel| pixel.0).collect();
+        Ok(EnvironmentMap::from_pixels([image.width(), image.height()], pixels, rotation, intensity))
+    }
+
+    /// makes a map from linear radiance pixels, row by row from the top. the rotation is in degrees
+    pub fn from_pixels(
+        size: [u32; 2],
+        pixels: Vec<[f32; 4]>,
+        rotation: f32,
+        intensity: f32,
+    ) -> Self {
+        let cdf = build_cdf(&pixels, size);
+        EnvironmentMap {
+            size,
+            pixels,
+            rotation: rotation.to_radians(),
+            intensity,
+            cdf,
+        }
+    }
+}
+
+
+/// the distributions used to pick a pixel in proportion to its luminance times the solid angle it covers.
+/// the first height values are the cumulative distribution over rows, then each row has a cumulative
+/// distribution over its pixels. rows or images with no light fall back to uniform distributions
+fn build_cdf(
+    pixels: &[[f32; 4]],
+    size: [u32; 2],
+) -> Vec<f32> {
+    let [width, height] = [size[0] as usize, size[1] as usize];
+    let mut row_cdf = Vec::with_capacity(height);
+    let mut pixel_cdf = Vec::with_capacity(width * height);
+
+    let mut total = 0.0;
+    for y in 0..height {
+        // rows near the poles cover less of the sphere
+        let sin_theta = (std::f32::consts::PI * (y as f32 + 0.5) / height as f32).sin();
+        let row = &pixels[y * width..(y + 1) * width];
+        let mut row_total = 0.0;
+        let row_start = pixel_cdf.len();
+        for pixel in row {
+            row_total += (0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2]).max(0.0) * sin_theta;
+            pixel_cdf.push(row_total);
+        }
+        normalise(&mut pixel_cdf[row_start..], row_total);
+
+        total += row_total;
+        row_cdf.push(total);
+    }
+    normalise(&mut row_cdf, total);
+
+    row_cdf.extend(pixel_cdf);
+    row_cdf
+}
+
+/// divides a running total by its final value, or replaces it with a uniform distribution if the total is 0
+fn normalise(
+    cdf: &mut [f32],
+    total: f32,
+) {
+    let len = cdf.len() as f32;
+    for (i, value) in cdf.iter_mut().enumerate() {
+        *value = if total > 0.0 {*value / total} else {(i + 1) as f32 / len};
+    }
+    // rounding can leave the last value just under 1
+    if let Some(last) = cdf.last_mut() {
+        *last = 1.0;
+    }
+}
+
+
+/// Where light that misses every object comes from
+#[derive(Clone, Debug)]
+pub enum Environment {
+    /// black, only emissive objects light the scene
+    None,
+    /// white at the horizon to blue at the top
+    Gradient,
+    Map(EnvironmentMap),
+    Sky(Sky),
+}
+
+impl Environment {
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            Environment::None => 0,
+            Environment::Gradient => 1,
+            Environment::Map(_) => 2,
+            Environment::Sky(_) => 3,
+        }
+    }
+}
+
+
+/// angular radius of the sun seen from the earth, in radians
+const SUN_ANGULAR_RADIUS: f32 = 0.00465;
+/// the preetham model gives luminance in kcd/m2, this brings a clear midday zenith to around 1
+const SKY_LUMINANCE_SCALE: f32 = 0.1;
+
+/// The Preetham analytic daylight model with a sun disk that light sampling can pick directions from.
+/// Everything the shader needs is worked out once here, see "A Practical Analytic Model for Daylight" (Preetham et al. 1999)
+#[derive(Clone, Debug)]
+pub struct Sky {
+    /// direction towards the centre of the sun
+    pub sun_dir: [f32; 3],
+    pub cos_sun_radius: f32,
+    /// linear rgb radiance of every point on the sun disk
+    pub sun_radiance: [f32; 3],
+    /// perez coefficients A to E, each for Y, x and y
+    pub perez: [[f32; 3]; 5],
+    /// zenith Yxy divided by the perez function at the zenith, so multiplying by the perez function gives the sky colour
+    pub zenith: [f32; 3],
+}
+
+impl Sky {
+    /// angles are in degrees, the azimuth goes from +x towards +z. turbidity is the haziness of the air, 2 is a
+    /// clear day and 10 is hazy. the sun intensity is the irradiance on a surface facing the sun
+    pub fn new(
+        sun_elevation: f32,
+        sun_azimuth: f32,
+        turbidity: f32,
+        sky_intensity: f32,
+        sun_intensity: f32,
+    ) -> Self {
+        let (elevation, azimuth) = (sun_elevation.to_radians(), sun_azimuth.to_radians());
+        let sun_dir = [elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin()];
+        let sun_zenith_angle = std::f32::consts::FRAC_PI_2 - elevation;
+        let t = turbidity;
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608],
+            [-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092],
+            [-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102],
+            [0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537],
+            [-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * sun_zenith_angle);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192) * SKY_LUMINANCE_SCALE * sky_intensity;
+        let angles = [sun_zenith_angle.powi(3), sun_zenith_angle.powi(2), sun_zenith_angle, 1.0];
+        let zenith_chromaticity = |coefficients: [[f32; 4]; 3]| -> f32 {
+            let [t2, t1, t0] = coefficients.map(|row| row.iter().zip(angles).map(|(c, a)| c * a).sum::<f32>());
+            t * t * t2 + t * t1 + t0
+        };
+        let zenith_x = zenith_chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = zenith_chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let at_zenith = [0, 1, 2].map(|i| perez_function(&perez, i, 1.0, sun_zenith_angle));
+        let zenith = [zenith_luminance / at_zenith[0], zenith_x / at_zenith[1], zenith_y / at_zenith[2]];
+
+        Sky {
+            sun_dir,
+            cos_sun_radius: SUN_ANGULAR_RADIUS.cos(),
+            sun_radiance: sun_radiance(elevation, turbidity, sun_intensity),
+            perez,
+            zenith,
+        }
+    }
+}
+
+/// relative brightness of the sky in a direction at theta from the zenith and gamma from the sun, for channel i of Yxy
+fn perez_function(
+    perez: &[[f32; 3]; 5],
+    i: usize,
+    cos_theta: f32,
+    gamma: f32,
+) -> f32 {
+    let [a, b, c, d, e] = perez.map(|coefficient| coefficient[i]);
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+}
+
+/// the sun dims and reddens as its light passes through more air, approximated with rayleigh scattering
+/// for the red, green and blue wavelengths and haze scattering that grows with the turbidity
+fn sun_radiance(
+    elevation: f32,
+    turbidity: f32,
+    sun_intensity: f32,
+) -> [f32; 3] {
+    if elevation <= 0.0 {return [0.0; 3];}
+
+    // kasten and young's air mass, 1 when the sun is overhead
+    let elevation_degrees = elevation.to_degrees();
+    let air_mass = 1.0 / (elevation.sin() + 0.50572 * (elevation_degrees + 6.07995).powf(-1.6364));
+    // optical depths of a clear atmosphere at 680, 550 and 440nm
+    let rayleigh = [0.045, 0.097, 0.236];
+    let haze = 0.08 * (turbidity - 1.0).max(0.0);
+
+    let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - SUN_ANGULAR_RADIUS.cos());
+    rayleigh.map(|r| (-air_mass * (r + haze)).exp() * sun_intensity / solid_angle)
+}
@@ -0,0 +1,117 @@
+use std::path::Path;
+use std::sync::Arc;
+use graphics::*;
+use graphics::all_vulkano_utils::renderer::DeviceImageView;
+use graphics::all_vulkano::{
+    buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage},
+    memory::allocator::{AllocationCreateInfo, MemoryUsage},
+    command_buffer::{allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage, CopyImageToBufferInfo},
+    image::ImageAccess,
+    sync::{self, GpuFuture},
+};
+use super::post_process::{display_colour, ToneMapOperator};
+
+
+/// saves a display ready rgba8 image, the format is chosen from the file extension
+pub fn save_image(
+    context: &VulkanoContext,
+    command_buffer_allocator: &Arc<StandardCommandBufferAllocator>,
+    view: DeviceImageView,
+    path: impl AsRef<Path>,
+) -> image::ImageResult<()> {
+    let [width, height] = view.image().dimensions().width_height();
+    let pixels: Vec<u8> = read_image(context, command_buffer_allocator, view);
+    image::save_buffer(path, &pixels, width, height, image::ColorType::Rgba8)
+}
+
+/// saves the average of an accumulated image as linear float radiance, used for exr files
+pub fn save_hdr_image(
+    context: &VulkanoContext,
+    command_buffer_allocator: &Arc<StandardCommandBufferAllocator>,
+    view: DeviceImageView,
+    path: impl AsRef<Path>,
+) -> image::ImageResult<()> {
+    let [width, height] = view.image().dimensions().width_height();
+
+    // rgb holds the sum of all frames and a holds the number of frames
+    let accumulated: Vec<f32> = read_image(context, command_buffer_allocator, view);
+    write_hdr(&accumulated, [width, height], path)
+}
+
+/// saves an accumulated image made on the cpu, exr files keep the hdr values and every other format is tone mapped
+pub fn save_accumulated_image(
+    accumulated: &[[f32; 4]],
+    image_size: [u32; 2],
+    tone_map: ToneMapOperator,
+    exposure: f32,
+    path: impl AsRef<Path>,
+) -> image::ImageResult<()> {
+    let path = path.as_ref();
+    if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("exr")) {
+        return write_hdr(&accumulated.concat(), image_size, path);
+    }
+
+    let pixels: Vec<u8> = accumulated.iter().flat_map(|sum| display_colour(*sum, tone_map, exposure)).collect();
+    image::save_buffer(path, &pixels, image_size[0], image_size[1], image::ColorType::Rgba8)
+}
+
+/// averages the accumulated rgba values and saves them as a float image
+fn write_hdr(
+    accumulated: &[f32],
+    image_size: [u32; 2],
+    path: impl AsRef<Path>,
+) -> image::ImageResult<()> {
+    let average: Vec<f32> = accumulated
+        .chunks(4)
+        .flat_map(|pixel| {
+            let count = pixel[3].max(1.0);
+            [pixel[0] / count, pixel[1] / count, pixel[2] / count, 1.0]
+        })
+        .collect();
+
+    let hdr_image = image::Rgba32FImage::from_raw(image_size[0], image_size[1], average).unwrap();
+    image::DynamicImage::ImageRgba32F(hdr_image).save(path)
+}
+
+/// copies a four channel image back from the gpu, P is the type of a single channel
+fn read_image<P: BufferContents + Copy + Default>(
+    context: &VulkanoContext,
+    command_buffer_allocator: &Arc<StandardCommandBufferAllocator>,
+    view: DeviceImageView,
+) -> Vec<P> {
+    let [width, height] = view.image().dimensions().width_height();
+
+    let buffer = Buffer::from_iter(
+        context.memory_allocator(),
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::Download,
+            ..Default::default()
+        },
+        (0..width * height * 4).map(|_| P::default()),
+    ).unwrap();
+
+    let mut builder = AutoCommandBufferBuilder::primary(
+        command_buffer_allocator,
+        context.graphics_queue().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    ).unwrap();
+
+    builder
+        .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(view.image().clone(), buffer.clone()))
+        .unwrap();
+
+    let command_buffer = builder.build().unwrap();
+    sync::now(context.device().clone())
+        .then_execute(context.graphics_queue().clone(), command_buffer)
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    buffer.read().unwrap().to_vec()
+}
@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::cpu_tracer::CpuRayTracer;
+use super::post_process::display_colour;
+use super::scene::Scene;
+
+
+/// the bundled scenes that are checked, by reference image name and scene file
+const GOLDEN_SCENES: [(&str, &str); 4] = [
+    ("spheres", "scenes/spheres.toml"),
+    ("box", "scenes/box.toml"),
+    ("cube", "scenes/cube.toml"),
+    ("island", "scenes/island.toml"),
+];
+const GOLDEN_DIR: &str = "assets/golden";
+/// where the rendered and diff images of failed checks are written
+const FAILURE_DIR: &str = "target/golden";
+
+const IMAGE_SIZE: [u32; 2] = [160, 90];
+const NUM_FRAMES: u32 = 4;
+/// largest allowed root mean square error over all channels, in the 0 to 1 range
+const MAX_RMSE: f32 = 0.02;
+/// differences are scaled up in the diff images so small errors are visible
+const DIFF_SCALE: f32 = 4.0;
+
+
+/// renders every golden scene at low resolution with the cpu tracer and compares it to the reference image.
+/// rendering is deterministic as every pixel is seeded from its frame and position, so any difference past
+/// floating point noise means the output has changed. returns true if every scene matched
+pub fn check_golden_images() -> bool {
+    let mut passed = true;
+    for (name, scene_path) in GOLDEN_SCENES {
+        match check_golden_scene(name, scene_path) {
+            Ok(rmse) => println!("{name}: ok (rmse {rmse:.4})"),
+            Err(e) => {
+                eprintln!("{name}: {e}");
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+/// compares one scene to its reference image, giving the rmse if it matched. on a mismatch the rendered
+/// and diff images are saved and the error says where
+fn check_golden_scene(name: &str, scene_path: &str) -> Result<f32, String> {
+    let rendered = render_golden_scene(scene_path).map_err(|e| format!("could not render {scene_path}: {e}"))?;
+
+    let reference_path = Path::new(GOLDEN_DIR).join(format!("{name}.png"));
+    let reference = image::open(&reference_path)
+        .map_err(|e| format!("could not open {}: {e}, create it with --bless-golden", reference_path.display()))?
+        .to_rgba8()
+        .into_raw();
+    if reference.len() != rendered.len() {
+        return Err(format!("{} is not {}x{}, recreate it with --bless-golden", reference_path.display(), IMAGE_SIZE[0], IMAGE_SIZE[1]));
+    }
+
+    let rmse = rmse(&rendered, &reference);
+    if rmse <= MAX_RMSE {
+        return Ok(rmse);
+    }
+
+    Err(match save_failure(name, &rendered, &reference) {
+        Ok((rendered_path, diff_path)) => format!(
+            "FAILED (rmse {rmse:.4} > {MAX_RMSE}), rendered image saved to {} and diff to {}",
+            rendered_path.display(), diff_path.display()
+        ),
+        Err(e) => format!("FAILED (rmse {rmse:.4} > {MAX_RMSE}), could not save the diff: {e}"),
+    })
+}
+
+/// renders every golden scene and saves it as the new reference image
+pub fn bless_golden_images() -> bool {
+    if let Err(e) = fs::create_dir_all(GOLDEN_DIR) {
+        eprintln!("Could not create {GOLDEN_DIR}: {e}");
+        return false;
+    }
+
+    let mut passed = true;
+    for (name, scene_path) in GOLDEN_SCENES {
+        let path = Path::new(GOLDEN_DIR).join(format!("{name}.png"));
+        let result = render_golden_scene(scene_path)
+            .map_err(|e| e.to_string())
+            .and_then(|rendered| save_rgba(&rendered, &path).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => println!("{name}: saved {}", path.display()),
+            Err(e) => {
+                eprintln!("{name}: {e}");
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+
+/// renders a scene to display ready rgba8 pixels
+fn render_golden_scene(scene_path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let scene = Scene::load(scene_path)?;
+    let tracer = CpuRayTracer::new(IMAGE_SIZE, &scene.settings);
+    let accumulated = tracer.accumulate(&scene.camera, NUM_FRAMES);
+    Ok(accumulated
+        .iter()
+        .flat_map(|sum| display_colour(*sum, scene.settings.tone_map, scene.settings.exposure))
+        .collect())
+}
+
+fn rmse(a: &[u8], b: &[u8]) -> f32 {
+    let sum: f32 = a.iter().zip(b).map(|(a, b)| {
+        let diff = (*a as f32 - *b as f32) / 255.0;
+        diff * diff
+    }).sum();
+    (sum / a.len() as f32).sqrt()
+}
+
+fn save_failure(name: &str, rendered: &[u8], reference: &[u8]) -> image::ImageResult<(PathBuf, PathBuf)> {
+    fs::create_dir_all(FAILURE_DIR)?;
+
+    let diff: Vec<u8> = rendered.chunks(4).zip(reference.chunks(4)).flat_map(|(a, b)| {
+        let [r, g, b] = [0, 1, 2].map(|i| ((a[i] as f32 - b[i] as f32).abs() * DIFF_SCALE).min(255.0) as u8);
+        [r, g, b, 255]
+    }).collect();
+
+    let rendered_path = Path::new(FAILURE_DIR).join(format!("{name}_rendered.png"));
+    let diff_path = Path::new(FAILURE_DIR).join(format!("{name}_diff.png"));
+    save_rgba(rendered, &rendered_path)?;
+    save_rgba(&diff, &diff_path)?;
+    Ok((rendered_path, diff_path))
+}
+
+fn save_rgba(pixels: &[u8], path: &Path) -> image::ImageResult<()> {
+    image::save_buffer(path, pixels, IMAGE_SIZE[0], IMAGE_SIZE[1], image::ColorType::Rgba8)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the scene tests are ignored until the reference images are blessed and committed,
+    // as they can only fail without them. run them with cargo test -- --ignored
+    fn assert_matches_golden(name: &str) {
+        let (name, scene_path) = GOLDEN_SCENES.into_iter().find(|(golden, _)| *golden == name).unwrap();
+        if let Err(e) = check_golden_scene(name, scene_path) {
+            panic!("{name}: {e}");
+        }
+    }
+
+    #[test]
+    fn rmse_of_matching_and_opposite_images() {
+        assert_eq!(rmse(&[0, 128, 255, 255], &[0, 128, 255, 255]), 0.0);
+        assert_eq!(rmse(&[0, 0, 255, 255], &[255, 255, 0, 0]), 1.0);
+    }
+
+    #[test]
+    #[ignore = "assets/golden has no reference images yet, bless them with --bless-golden"]
+    fn spheres_matches_golden() {
+        assert_matches_golden("spheres");
+    }
+
+    #[test]
+    #[ignore = "assets/golden has no reference images yet, bless them with --bless-golden"]
+    fn box_matches_golden() {
+        assert_matches_golden("box");
+    }
+
+    #[test]
+    #[ignore = "assets/golden has no reference images yet, bless them with --bless-golden"]
+    fn cube_matches_golden() {
+        assert_matches_golden("cube");
+    }
+
+    #[test]
+    #[ignore = "assets/golden has no reference images yet, bless them with --bless-golden"]
+    fn island_matches_golden() {
+        assert_matches_golden("island");
+    }
+}
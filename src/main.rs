@@ -1,347 +1,263 @@
+use std::fs;
+use std::path::Path;
 use std::time::Instant;
 use graphics::*;
+use graphics::all_vulkano::buffer::BufferContents;
+mod animation;
+mod cli;
+mod bvh;
+mod cpu_tracer;
 mod diffuse;
+mod environment;
+mod export;
+mod golden;
+mod obj;
 mod raytrace_pipeline;
 mod raytracing_app;
 mod texture_draw_pipeline;
+mod textures;
 mod materials;
 mod objects;
+mod post_process;
+mod scene;
 use raytracing_app::*;
-use materials::*;
-use objects::*;
+use cli::{Args, Command, GoldenMode, RenderMode, COMMANDS, USAGE, read_commands};
+use scene::Scene;
+use cpu_tracer::CpuRayTracer;
+use export::save_accumulated_image;
+use animation::{CameraPath, frame_path, first_unfinished_frame};
 
-const IMAGE_SIZE: [u32; 2] = [1080, 720];
-const TARGET_FPS: f32 = 60.0;
-const TARGET_FRAME_TIME: f32 = 1.0 / TARGET_FPS;
-const NUM_RENDERS: usize = 5000; // set to 0 for infinite renders
-const REALTIME: bool = true;
 
 fn main() {
-    let mut event_loop = EventLoop::new();
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return;
+        }
+    };
+    if args.help {
+        println!("{USAGE}");
+        return;
+    }
+
+    if let Some(mode) = args.golden {
+        let passed = match mode {
+            GoldenMode::Check => golden::check_golden_images(),
+            GoldenMode::Bless => golden::bless_golden_images(),
+        };
+        if !passed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut scene = match Scene::load(&args.scene) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    if let Err(e) = args.apply(&mut scene.settings) {
+        eprintln!("{e}");
+        return;
+    }
+
+    if let Some(camera_path) = &args.camera_path {
+        render_camera_path(scene, &args, camera_path);
+        return;
+    }
+    if args.cpu {
+        render_cpu(scene, &args);
+        return;
+    }
+    if args.headless {
+        render_headless(scene, &args);
+        return;
+    }
 
+    let mut event_loop = EventLoop::new();
+    let mut app = scene.into_app();
+    if args.controllable {
+        app.camera.controllable();
+    }
 
-    // let mut app = load_spheres_scene();
-    // let mut app = load_box_scene();
-    // let mut app = load_cube_scene();
-    let mut app = load_island_scene();
-    // app.camera.controllable();
-    
-  
-    app.open(&event_loop, IMAGE_SIZE);
-    
+    app.open(&event_loop, args.image_size);
+    print_stats(&app, &args);
 
 
-    if !REALTIME {
-        compute_n_then_render(&mut app, NUM_RENDERS.max(1));
-    } 
+    let num_renders = match args.mode {
+        RenderMode::Batch {num_frames} => {
+            compute_n_then_render(&mut app, num_frames);
+            save_output(&app, &args);
+            0
+        }
+        RenderMode::Realtime {num_frames} => num_frames
+    };
 
+    let commands = read_commands();
+    let target_frame_time = 1.0 / args.target_fps;
     let mut last_frame_time = Instant::now();
     let mut num_rendered = 0;
     let start_time = Instant::now();
     loop {
         if !handle_events(&mut app, &mut event_loop) {break;}
 
-        if !REALTIME{continue;}
+        if let RenderMode::Batch {..} = args.mode {continue;}
+
+        for command in commands.try_iter() {
+            match command {
+                Ok(command) => run_command(&mut app, command),
+                Err(e) => eprintln!("{e}"),
+            }
+        }
 
         let frame_time = last_frame_time.elapsed().as_secs_f32();
-        if frame_time >= TARGET_FRAME_TIME && (NUM_RENDERS == 0 || num_rendered < NUM_RENDERS) {
+        if frame_time >= target_frame_time && (num_renders == 0 || num_rendered < num_renders) {
             last_frame_time = Instant::now();
-            
+
             compute_then_render(&mut app, frame_time);
             num_rendered += 1;
-            if NUM_RENDERS != 0 && num_rendered == NUM_RENDERS {println!("Finished rendering {} frames in {} seconds", NUM_RENDERS, start_time.elapsed().as_secs_f32())}
+            if num_renders != 0 && num_rendered == num_renders {
+                println!("Finished rendering {} frames in {} seconds", num_renders, start_time.elapsed().as_secs_f32());
+                save_output(&app, &args);
+            }
             // println!("{:?}, {:?}", camera.position, camera.direction);
             // if last_frame_time.elapsed().as_secs_f32() > TARGET_FRAME_TIME {println!("Slow frame")}
         }
 
     }
 
+    // an unlimited realtime render is saved as it is when the window closes
+    if let RenderMode::Realtime {num_frames: 0} = args.mode {
+        save_output(&app, &args);
+    }
 }
 
-#[allow(dead_code)]
-fn load_spheres_scene() -> RayTracingApp<PositionVertex>{
-    let spheres = vec![
-        Sphere {
-            centre: [0.0, -100.0, 0.0], 
-            radius: 100.0, 
-            material: LambertianMaterial {
-                colour: [0.5, 0.5, 0.5].into(),
-            }.into()
-        },
-        Sphere {
-            centre: [2.5, 0.75, 0.0], 
-            radius: 1.0, 
-            material: MetalMaterial {
-                colour: [0.2, 0.2, 1.0].into(),
-                smoothness: 1.0,
-                fuzz: 0.1
-            }.into()
-        },
-        Sphere {
-            centre: [-2.5, 0.75, 0.0], 
-            radius: 1.0, 
-            material: MetalMaterial {
-                colour: [1.0, 0.2, 0.2].into(),
-                smoothness: 1.0,
-                fuzz: 0.1,
-            }.into()
-        },
-        Sphere {
-            centre: [0.0, 1.0, 0.0], 
-            radius: 1.0,
-            material: MetalMaterial {
-                colour: [0.2, 1.0, 0.2].into(),
-                smoothness: 1.0,
-                fuzz: 0.1,
-            }.into()
-        },
+fn render_headless(scene: Scene, args: &Args) {
+    let (RenderMode::Batch {num_frames}, Some(path)) = (args.mode, &args.output) else {return;};
 
-        Sphere {
-            centre: [500.0, 100.0, 500.0],
-            radius: 250.0,
-            material: InvisLightMaterial {
-                emission: [0.6, 0.6, 1.0, 25.0]
-            }.into()
-        },
-    ];
-
-    let view_dir = [-0.35, -0.35, 0.87];
-    // println!("{:?}", maths::Vector3::direction_to_euler_angles(view_dir));
-    let cam = Camera::new(Some([2.0, 2.0, -5.0]), Some(view_dir), Some(10.0), None);
-    let up = cam.up;
-    RayTracingApp::new(
-        cam,
-        RayTracerSettings {
-            num_samples: 25,
-            max_bounces: 50,
-            use_environment_lighting: false,
-            sample_jitter: None,
-            sphere_data: spheres,
-            mesh_data: Vec::new(),
-            camera_focal_length: 1.0,
-            viewport_height: 2.0,
-            up: up.into()
-        }
-    )
+    let mut app = scene.into_headless_app();
+    app.open_headless(args.image_size);
+    print_stats(&app, args);
+
+    let start_time = Instant::now();
+    match compute_n_then_save(&mut app, num_frames, path) {
+        Ok(()) => println!("Rendered {} frames in {} seconds, saved to {}", num_frames, start_time.elapsed().as_secs_f32(), path.display()),
+        Err(e) => eprintln!("Could not save image to {}: {e}", path.display()),
+    }
 }
 
+fn render_camera_path(scene: Scene, args: &Args, camera_path: &Path) {
+    let (RenderMode::Batch {num_frames: frames_per_image}, Some(dir)) = (args.mode, &args.output) else {return;};
 
-#[allow(dead_code)]
-fn load_box_scene() -> RayTracingApp<PositionVertex>{
-    let meshes = load_obj("assets/box.obj");
-    let mesh_data = vec![
-        RayTracingMesh{ // floor
-            mesh: meshes[0].clone(),
-            material: CustomMaterial {
-                colour: [1.0, 1.0, 1.0],
-                smoothness: 0.7,
-                specular_probability: 0.5,
-                ..Default::default()
-            }.into()
-        },
-        RayTracingMesh{ // Left Wall
-            mesh: meshes[4].clone(),
-            material: CustomMaterial {
-                colour: [166.0 / 255.0, 45.0 / 255.0, 23.0 / 255.0],
-                smoothness: 0.7,
-                specular_probability: 0.5,
-                ..Default::default()
-            }.into()
-        },
-        RayTracingMesh{ // Right Wall
-            mesh: meshes[3].clone(),
-            material: CustomMaterial {
-                colour: [19.0 / 255.0, 133.0 / 255.0, 34.0 / 255.0],
-                smoothness: 0.7,
-                specular_probability: 0.5,
-                ..Default::default()
-            }.into()
-        },
-        RayTracingMesh{ // Back Wall
-            mesh: meshes[1].clone(),
-            material: CustomMaterial {
-                colour: [1.0; 3],
-                smoothness: 0.7,
-                specular_probability: 0.5,
-                ..Default::default()
-            }.into()
-        },
-        RayTracingMesh{ // ceiling
-            mesh: meshes[5].clone(),
-            material: CustomMaterial {
-                colour: [1.0; 3],
-                smoothness: 0.7,
-                specular_probability: 0.5,
-                ..Default::default()
-            }.into()
-        },
-        RayTracingMesh{ // Front Wall
-            mesh: meshes[2].clone(),
-            material: CustomMaterial {
-                colour: [1.0; 3],
-                smoothness: 0.7,
-                specular_probability: 0.5,
-                ..Default::default()
-            }.into()
-        },
-        RayTracingMesh{ // light
-            mesh: meshes[6].clone(),
-            material: InvisLightMaterial {
-                emission: [1.0, 1.0, 1.0, 5.0]
-            }.into()
+    let path = match CameraPath::load(camera_path) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
         }
-    ];
-
-    let sphere_data = vec![
-        // Sphere{
-        //     centre: [-0.5, 0.5, 0.0].into(),
-        //     radius: 0.5,
-        //     material: MetalMaterial {
-        //         smoothness: 1.0,
-        //         fuzz: 0.0,
-        //         colour: [1.0, 1.0, 1.0].into(),
-        //     }.into()
-        // },
-        Sphere{
-            centre: [0.0, 0.5, 0.0].into(),
-            radius: 0.5,
-            material: MetalMaterial {
-                smoothness: 1.0,
-                fuzz: 0.0,
-                colour: [1.0, 1.0, 1.0].into(),
-            }.into()
-        },
-    ];
-
-    let cam = Camera::new(Some([1.5, 1.0, 0.0]), Some([-1.0, 0.0, 0.0]), None, None);
-    let up = cam.up;
-    RayTracingApp::new(
-        cam,
-        RayTracerSettings {
-            num_samples: 5,
-            max_bounces: 50,
-            use_environment_lighting: false,
-            sample_jitter: Some(0.005),
-            sphere_data: sphere_data,
-            mesh_data: mesh_data,
-            camera_focal_length: 1.0,
-            viewport_height: 2.0,
-            up: up.into()
+    };
+    if let Err(e) = path.check_fov(scene.settings.projection) {
+        eprintln!("{e}");
+        return;
+    }
+    if let Err(e) = fs::create_dir_all(dir) {
+        eprintln!("Could not create output directory {}: {e}", dir.display());
+        return;
+    }
+
+    let num_images = path.num_frames();
+    let first_image = if args.resume {first_unfinished_frame(dir, num_images)} else {0};
+    if first_image > 0 {
+        println!("Resuming from frame {first_image} of {num_images}");
+    }
+
+    let default_fov = scene.settings.fov;
+    let mut app = scene.into_headless_app();
+    app.open_headless(args.image_size);
+    print_stats(&app, args);
+
+    let start_time = Instant::now();
+    for image in first_image..num_images {
+        let (camera, fov) = path.camera_at(path.frame_time(image), default_fov);
+        app.camera = camera;
+        // also starts the accumulated image again
+        app.set_fov(fov);
+
+        // saved under another name until it is finished, so resuming never skips a half written frame
+        let image_path = frame_path(dir, image);
+        let partial_path = image_path.with_extension("partial.png");
+        let saved = compute_n_then_save(&mut app, frames_per_image, &partial_path)
+            .map_err(|e| e.to_string())
+            .and_then(|()| fs::rename(&partial_path, &image_path).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            eprintln!("Could not save frame {image} to {}: {e}", image_path.display());
+            return;
         }
-    )
+        println!("Rendered frame {} of {num_images}", image + 1);
+    }
+
+    println!("Rendered {} frames in {} seconds, saved to {}", num_images - first_image, start_time.elapsed().as_secs_f32(), dir.display());
+}
+
+fn render_cpu(scene: Scene, args: &Args) {
+    let (RenderMode::Batch {num_frames}, Some(path)) = (args.mode, &args.output) else {return;};
+
+    let start_time = Instant::now();
+    let tracer = CpuRayTracer::new(args.image_size, &scene.settings);
+    let accumulated = tracer.accumulate(&scene.camera, num_frames as u32);
 
+    match save_accumulated_image(&accumulated, args.image_size, scene.settings.tone_map, scene.settings.exposure, path) {
+        Ok(()) => println!("Rendered {} frames on the cpu in {} seconds, saved to {}", num_frames, start_time.elapsed().as_secs_f32(), path.display()),
+        Err(e) => eprintln!("Could not save image to {}: {e}", path.display()),
+    }
 }
 
-#[allow(dead_code)]
-fn load_cube_scene() -> RayTracingApp<PositionVertex>{
-    let meshes = load_obj("assets/Cube.obj");
-    let mesh_data = vec![
-        RayTracingMesh{
-            mesh: meshes[0].clone(),
-            material: MetalMaterial{
-                colour: [0.7, 0.7, 0.7],
-                smoothness: 1.0,
-                fuzz: 0.0
-            }.into()
+/// applies a command typed into the terminal
+fn run_command<T: graphics::Position + BufferContents + Copy + Clone>(app: &mut RayTracingApp<T>, command: Command) {
+    match command {
+        Command::Exposure(stops) => app.set_exposure(stops),
+        Command::ChangeExposure(stops) => app.set_exposure(app.settings().exposure + stops),
+        Command::ToneMap(tone_map) => app.set_tone_map(tone_map),
+        Command::NextToneMap => app.set_tone_map(app.settings().tone_map.next_operator()),
+        Command::Aperture(radius) => app.set_focus(radius, app.settings().focus_distance),
+        Command::Focus(distance) => app.set_focus(app.settings().aperture_radius, distance),
+        Command::Autofocus => if app.autofocus().is_none() {
+            println!("Nothing in the centre of the image to focus on");
         },
-    ];
-
-    let sphere_data = vec![
-        Sphere{
-            centre: [0.0, 0.0, 0.0].into(),
-            radius: 1.0,
-            material: MetalMaterial {
-                smoothness: 1.0,
-                fuzz: 0.0,
-                colour: [1.0, 1.0, 1.0].into(),
-            }.into()
+        // the allowed range depends on the projection, the same check as the --fov option
+        Command::Fov(fov) => match app.settings().projection.check_fov(fov) {
+            Ok(()) => app.set_fov(fov),
+            Err(e) => {
+                println!("{e}");
+                return;
+            }
         },
-    ];
-
-
-    let cam = Camera::new(Some([5.0, 2.0, 0.0]), Some([-1.0, -0.2, 0.0]), None, None);
-    let up = cam.up;
-    RayTracingApp::new(
-        cam,
-        RayTracerSettings {
-            num_samples: 10,
-            max_bounces: 50,
-            use_environment_lighting: true,
-            sample_jitter: None,
-            sphere_data: sphere_data,
-            mesh_data: mesh_data,
-            camera_focal_length: 1.0,
-            viewport_height: 2.0,
-            up: up.into()
+        Command::Help => {
+            println!("{COMMANDS}");
+            return;
         }
-    )
+    }
+    let settings = app.settings();
+    println!(
+        "Exposure {} stops, tone map {:?}, aperture {}, focus distance {}, fov {}",
+        settings.exposure, settings.tone_map, settings.aperture_radius, settings.focus_distance, settings.fov
+    );
 }
 
+fn print_stats<T: graphics::Position + BufferContents + Copy + Clone>(app: &RayTracingApp<T>, args: &Args) {
+    if let (true, Some((raytrace_pipeline, _, _, _))) = (args.verbose, &app.pipeline) {
+        println!("{}", raytrace_pipeline.stats);
+    }
+}
 
-#[allow(dead_code)]
-fn load_island_scene() -> RayTracingApp<PositionVertex> {
-    let meshes = load_obj("assets/island.obj");
-    let mesh_data = vec![
-        // Tree
-        RayTracingMesh{
-            mesh: meshes[0].clone(),
-            material: LambertianMaterial{
-                colour: [0.40, 0.26, 0.16],
-                // smoothness: 1.0,
-                // fuzz: 0.0
-            }.into()
-        },
-        // Island
-        RayTracingMesh {
-            mesh: meshes[1].clone(),
-            material: LambertianMaterial{
-                colour: [0.46, 0.46, 0.46]
-            }.into()
-        },
-        // leaves
-        RayTracingMesh {
-            mesh: meshes[2].clone(),
-            material: LambertianMaterial{
-                colour: [0.14, 0.46, 0.18]
-            }.into()
-        },
-        // glowing water
-        RayTracingMesh {
-            mesh: meshes[3].clone(),
-            material: LambertianMaterial {
-                colour: [0.21, 0.63, 0.82]
-            }.into()
-        }
-    ];
-
-
-    let sphere_data = vec![
-        // Sphere {
-        //     centre: [500.0, 100.0, 500.0],
-        //     radius: 250.0,
-        //     material: InvisLightMaterial {
-        //         emission: [0.6, 0.6, 1.0, 25.0]
-        //     }.into()
-        // },
-    ];
-
-
-
-    let cam = Camera::new(Some([-5.0, 10.0, -20.0]), Some([0.2, -0.4, 1.0]), None, None);
-    let up = cam.up;
-    RayTracingApp::new(
-        cam,
-        RayTracerSettings {
-            num_samples: 10,
-            max_bounces: 50,
-            use_environment_lighting: true,
-            sample_jitter: None,
-            sphere_data: sphere_data,
-            mesh_data: mesh_data,
-            camera_focal_length: 1.0,
-            viewport_height: 2.0,
-            up: up.into()
+fn save_output<T: graphics::Position + BufferContents + Copy + Clone>(app: &RayTracingApp<T>, args: &Args) {
+    if let Some(path) = &args.output {
+        match app.save_image(path) {
+            Ok(()) => println!("Saved image to {}", path.display()),
+            Err(e) => eprintln!("Could not save image to {}: {e}", path.display()),
         }
-    )
-
-}
\ No newline at end of file
+    }
+}
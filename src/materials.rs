@@ -15,7 +15,8 @@ impl Into<raytrace_shader::RayTracingMaterial> for CustomMaterial {
         raytrace_shader::RayTracingMaterial {
             colour: [self.colour[0], self.colour[1], self.colour[2], 0.0],
             emission: [self.emission_colour[0], self.emission_colour[1], self.emission_colour[2], self.emission_strength],
-            settings: [self.specular_probability, self.smoothness, self.fuzz, 0.0]
+            settings: [self.specular_probability, self.smoothness, self.fuzz, 0.0],
+            textures: [-1; 4]
         }
     }
 }
@@ -44,7 +45,8 @@ impl Into<raytrace_shader::RayTracingMaterial> for LambertianMaterial {
         raytrace_shader::RayTracingMaterial {
             colour: [self.colour[0], self.colour[1], self.colour[2], 0.0],
             emission: [0.0; 4],
-            settings: [1.0, 0.0, 0.0, 0.0]
+            settings: [1.0, 0.0, 0.0, 0.0],
+            textures: [-1; 4]
         }
     }
 }
@@ -60,7 +62,60 @@ impl Into<raytrace_shader::RayTracingMaterial> for MetalMaterial {
         raytrace_shader::RayTracingMaterial {
             colour: [self.colour[0], self.colour[1], self.colour[2], 0.0],
             emission: [0.0; 4],
-            settings: [1.0, self.smoothness, self.fuzz, 0.0]
+            settings: [1.0, self.smoothness, self.fuzz, 0.0],
+            textures: [-1; 4]
+        }
+    }
+}
+
+/// glass or water like material that reflects and refracts, the tint is applied every time light passes through a surface
+pub struct DielectricMaterial {
+    pub tint: [f32; 3],
+    pub index_of_refraction: f32,
+}
+
+impl Into<raytrace_shader::RayTracingMaterial> for DielectricMaterial {
+    fn into(self) -> raytrace_shader::RayTracingMaterial {
+        raytrace_shader::RayTracingMaterial {
+            colour: [self.tint[0], self.tint[1], self.tint[2], 0.0],
+            emission: [0.0; 4],
+            settings: [self.index_of_refraction, 0.0, 0.0, 2.0],
+            textures: [-1; 4]
+        }
+    }
+}
+
+/// metallic roughness material using the ggx microfacet brdf, matching what most modelling tools export
+pub struct PbrMaterial {
+    pub base_colour: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    /// reflectance of non metals, 0.5 gives the usual 4%
+    pub specular: f32,
+    pub emission_colour: [f32; 3],
+    pub emission_strength: f32,
+}
+
+impl Into<raytrace_shader::RayTracingMaterial> for PbrMaterial {
+    fn into(self) -> raytrace_shader::RayTracingMaterial {
+        raytrace_shader::RayTracingMaterial {
+            colour: [self.base_colour[0], self.base_colour[1], self.base_colour[2], 0.0],
+            emission: [self.emission_colour[0], self.emission_colour[1], self.emission_colour[2], self.emission_strength],
+            settings: [self.metallic, self.roughness, self.specular, 3.0],
+            textures: [-1; 4]
+        }
+    }
+}
+
+impl Default for PbrMaterial {
+    fn default() -> Self {
+        PbrMaterial {
+            base_colour: [0.5; 3],
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            emission_colour: [0.0; 3],
+            emission_strength: 0.0,
         }
     }
 }
@@ -74,7 +129,8 @@ impl Into<raytrace_shader::RayTracingMaterial> for LightMaterial {
         raytrace_shader::RayTracingMaterial {
             colour: [1.0; 4],
             emission: self.emission,
-            settings: [1.0, 1.0, 0.0, 0.0]
+            settings: [1.0, 1.0, 0.0, 0.0],
+            textures: [-1; 4]
         }
     }
 }
@@ -88,7 +144,8 @@ impl Into<raytrace_shader::RayTracingMaterial> for InvisLightMaterial {
         raytrace_shader::RayTracingMaterial {
             colour: [1.0; 4],
             emission: self.emission,
-            settings: [0.0, 1.0, 0.0, 1.0]
+            settings: [0.0, 1.0, 0.0, 1.0],
+            textures: [-1; 4]
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use graphics::{Mesh, PositionVertex, Normal};
+
+
+/// The objects of an obj file and the materials from the mtl files it uses
+pub struct ObjFile {
+    pub objects: Vec<ObjObject>,
+    pub materials: BTreeMap<String, MtlMaterial>,
+}
+
+/// One object of an obj file, split into a part for each material its faces use
+#[derive(Clone)]
+pub struct ObjObject {
+    pub parts: Vec<ObjPart>,
+}
+
+/// The faces of an object that share a material, the texture coordinates line up with the mesh's vertices
+#[derive(Clone)]
+pub struct ObjPart {
+    pub mesh: Mesh<PositionVertex>,
+    /// texture coordinates with v going down the image, zero for vertices without one
+    pub uvs: Vec<[f32; 2]>,
+    /// the name given by `usemtl`, None for faces before any `usemtl` line
+    pub material: Option<String>,
+}
+
+/// A material from an mtl file, values that aren't given are None.
+/// texture paths have already been joined onto the mtl file's directory
+#[derive(Clone, Debug, Default)]
+pub struct MtlMaterial {
+    /// Kd
+    pub diffuse: Option<[f32; 3]>,
+    /// Ks
+    pub specular: Option<[f32; 3]>,
+    /// Ns, from 0 to 1000
+    pub specular_exponent: Option<f32>,
+    /// Ke
+    pub emission: Option<[f32; 3]>,
+    /// Ni
+    pub index_of_refraction: Option<f32>,
+    /// d, or 1 - Tr
+    pub dissolve: Option<f32>,
+    /// Tf
+    pub transmission: Option<[f32; 3]>,
+    pub illum: Option<u32>,
+    /// map_Kd
+    pub diffuse_map: Option<PathBuf>,
+    /// map_Ke
+    pub emission_map: Option<PathBuf>,
+    /// map_Bump, bump or norm, read as a tangent space normal map
+    pub normal_map: Option<PathBuf>,
+}
+
+/// the position, texture coordinate and normal indices of a face corner, 0 when missing
+type Corner = (usize, usize, usize);
+
+#[derive(Default)]
+struct PartBuilder {
+    corners: HashMap<Corner, u32>,
+    vertices: Vec<PositionVertex>,
+    uvs: Vec<[f32; 2]>,
+    normals: Vec<Normal>,
+    has_normals: bool,
+    indices: Vec<u32>,
+}
+
+impl PartBuilder {
+    /// the index of the vertex made from a corner, corners that share all three indices share a vertex
+    fn vertex(
+        &mut self,
+        corner: Corner,
+        positions: &[[f32; 3]],
+        tex_coords: &[[f32; 2]],
+        normals: &[[f32; 3]],
+    ) -> io::Result<u32> {
+        if let Some(index) = self.corners.get(&corner) {
+            return Ok(*index);
+        }
+
+        let (position, tex_coord, normal) = corner;
+        let position = *positions.get(position - 1).ok_or(invalid(format!("face uses missing vertex {position}")))?;
+        let uv = match tex_coord {
+            0 => [0.0; 2],
+            i => tex_coords.get(i - 1).map(|[u, v]| [*u, 1.0 - v]).ok_or(invalid(format!("face uses missing texture coordinate {i}")))?,
+        };
+        let normal = match normal {
+            0 => [0.0; 3],
+            i => *normals.get(i - 1).ok_or(invalid(format!("face uses missing normal {i}")))?,
+        };
+        self.has_normals |= corner.2 != 0;
+
+        let index = self.vertices.len() as u32;
+        self.vertices.push(PositionVertex {position});
+        self.uvs.push(uv);
+        self.normals.push(Normal {normal});
+        self.corners.insert(corner, index);
+        Ok(index)
+    }
+
+    fn build(self, material: Option<String>) -> ObjPart {
+        let mut mesh = Mesh::new(self.vertices, self.indices);
+        // vertices without a normal get a zero one, which is replaced by the face normal when rendering
+        if self.has_normals {
+            mesh.set_normals(self.normals);
+        }
+        ObjPart {mesh, uvs: self.uvs, material}
+    }
+}
+
+/// the parts of the object being read, in the order their materials were first used
+#[derive(Default)]
+struct ObjectBuilder {
+    parts: Vec<(Option<String>, PartBuilder)>,
+    current: usize,
+}
+
+impl ObjectBuilder {
+    fn use_material(&mut self, material: Option<String>) {
+        self.current = match self.parts.iter().position(|(name, _)| *name == material) {
+            Some(index) => index,
+            None => {
+                self.parts.push((material, PartBuilder::default()));
+                self.parts.len() - 1
+            }
+        };
+    }
+
+    fn current_part(&mut self) -> &mut PartBuilder {
+        if self.parts.is_empty() {
+            self.use_material(None);
+        }
+        &mut self.parts[self.current].1
+    }
+
+    /// None if the object has no faces
+    fn build(self) -> Option<ObjObject> {
+        let parts: Vec<ObjPart> = self.parts.into_iter()
+            .filter(|(_, part)| !part.indices.is_empty())
+            .map(|(material, part)| part.build(material))
+            .collect();
+        if parts.is_empty() {None} else {Some(ObjObject {parts})}
+    }
+}
+
+
+/// loads every object of an obj file with its normals, texture coordinates and materials.
+/// faces with more than three corners are split into fans and a new object is started at every `o` line,
+/// objects without any faces are left out. mtl files are found relative to the obj file
+pub fn load_obj(path: impl AsRef<Path>) -> io::Result<ObjFile> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut tex_coords: Vec<[f32; 2]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+    let mut objects = Vec::new();
+    let mut materials = BTreeMap::new();
+    let mut current = ObjectBuilder::default();
+
+    for (line_number, line) in text.lines().enumerate() {
+        let mut parts = line.split_whitespace();
+        let Some(keyword) = parts.next() else {continue};
+        let values: Vec<&str> = parts.collect();
+        let at_line = |e: io::Error| invalid(format!("line {}: {e}", line_number + 1));
+
+        match keyword {
+            "v" => positions.push(parse_floats(&values).map_err(at_line)?),
+            "vn" => normals.push(parse_floats(&values).map_err(at_line)?),
+            // the optional third coordinate is for 3d textures
+            "vt" => tex_coords.push(parse_floats(&values).map_err(at_line)?),
+            "o" => {
+                let finished = std::mem::take(&mut current);
+                objects.extend(finished.build());
+            }
+            "usemtl" => current.use_material(Some(values.join(" "))),
+            "mtllib" => {
+                for file in values {
+                    let mtl_path = directory.join(file);
+                    let file_materials = load_mtl(&mtl_path).map_err(|e| at_line(invalid(format!("{}: {e}", mtl_path.display()))))?;
+                    materials.extend(file_materials);
+                }
+            }
+            "f" => {
+                if values.len() < 3 {
+                    return Err(at_line(invalid("faces need at least three corners".to_string())));
+                }
+                let counts = [positions.len(), tex_coords.len(), normals.len()];
+                let part = current.current_part();
+                let corners = values.iter().map(|value| {
+                    let corner = parse_corner(value, counts).map_err(at_line)?;
+                    part.vertex(corner, &positions, &tex_coords, &normals).map_err(at_line)
+                }).collect::<io::Result<Vec<u32>>>()?;
+                for i in 1..corners.len() - 1 {
+                    part.indices.extend([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    objects.extend(current.build());
+    Ok(ObjFile {objects, materials})
+}
+
+/// loads every material of an mtl file by name
+pub fn load_mtl(path: impl AsRef<Path>) -> io::Result<BTreeMap<String, MtlMaterial>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let mut materials = BTreeMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (line_number, line) in text.lines().enumerate() {
+        let mut parts = line.split_whitespace();
+        let Some(keyword) = parts.next() else {continue};
+        let values: Vec<&str> = parts.collect();
+        let at_line = |e: io::Error| invalid(format!("line {}: {e}", line_number + 1));
+
+        if keyword == "newmtl" {
+            materials.extend(current.take());
+            current = Some((values.join(" "), MtlMaterial::default()));
+            continue;
+        }
+        if keyword.starts_with('#') {continue;}
+        let Some((_, material)) = current.as_mut() else {
+            return Err(at_line(invalid(format!("{keyword} comes before any newmtl"))));
+        };
+
+        // texture options like -bm 1.0 come before the file name
+        let texture = || values.last().map(|file| directory.join(file)).ok_or(at_line(invalid(format!("{keyword} has no file"))));
+        match keyword {
+            "Kd" => material.diffuse = Some(parse_colour(&values).map_err(at_line)?),
+            "Ks" => material.specular = Some(parse_colour(&values).map_err(at_line)?),
+            "Ke" => material.emission = Some(parse_colour(&values).map_err(at_line)?),
+            "Tf" => material.transmission = Some(parse_colour(&values).map_err(at_line)?),
+            "Ns" => material.specular_exponent = Some(parse_floats::<1>(&values).map_err(at_line)?[0]),
+            "Ni" => material.index_of_refraction = Some(parse_floats::<1>(&values).map_err(at_line)?[0]),
+            "d" => material.dissolve = Some(parse_floats::<1>(&values).map_err(at_line)?[0]),
+            "Tr" => material.dissolve = Some(1.0 - parse_floats::<1>(&values).map_err(at_line)?[0]),
+            "illum" => material.illum = Some(values.first().and_then(|value| value.parse().ok()).ok_or(at_line(invalid("illum needs a whole number".to_string())))?),
+            "map_Kd" => material.diffuse_map = Some(texture()?),
+            "map_Ke" => material.emission_map = Some(texture()?),
+            "map_Bump" | "map_bump" | "bump" | "norm" => material.normal_map = Some(texture()?),
+            _ => {}
+        }
+    }
+
+    materials.extend(current);
+    Ok(materials)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_floats<const N: usize>(values: &[&str]) -> io::Result<[f32; N]> {
+    if values.len() < N {
+        return Err(invalid(format!("expected {N} numbers")));
+    }
+    let mut floats = [0.0; N];
+    for (float, value) in floats.iter_mut().zip(values) {
+        *float = value.parse().map_err(|_| invalid(format!("{value} is not a number")))?;
+    }
+    Ok(floats)
+}
+
+/// an rgb colour, a single value is used for all three channels
+fn parse_colour(values: &[&str]) -> io::Result<[f32; 3]> {
+    if values.len() < 3 {
+        let [value] = parse_floats(values)?;
+        return Ok([value; 3]);
+    }
+    parse_floats(values)
+}
+
+/// a face corner written as v, v/vt, v//vn or v/vt/vn. negative indices count back from the latest
+/// element, the results are 1 based with 0 for a missing index
+fn parse_corner(
+    value: &str,
+    counts: [usize; 3],
+) -> io::Result<Corner> {
+    let mut indices = [0; 3];
+    for (slot, part) in value.split('/').enumerate() {
+        if slot >= 3 {
+            return Err(invalid(format!("{value} has too many indices")));
+        }
+        if part.is_empty() {continue;}
+
+        let index: i64 = part.parse().map_err(|_| invalid(format!("{value} is not a face corner")))?;
+        indices[slot] = match index {
+            0 => return Err(invalid(format!("{value} uses index 0, obj indices start at 1"))),
+            i if i < 0 && -i as usize <= counts[slot] => counts[slot] + 1 - (-i) as usize,
+            i if i < 0 => return Err(invalid(format!("{value} counts back past the start of the file"))),
+            i => i as usize,
+        };
+    }
+    if indices[0] == 0 {
+        return Err(invalid(format!("{value} has no vertex")));
+    }
+    Ok((indices[0], indices[1], indices[2]))
+}
@@ -30,11 +30,84 @@ pub fn get_null_sphere() -> Sphere {
 }
 
 
+/// Places an object in the world by scaling it, then rotating it and then moving it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: [f32; 3],
+    /// degrees around the x, y and z axes, applied in that order
+    pub rotation: [f32; 3],
+    /// every component must be positive, mirroring would turn the triangles inside out
+    pub scale: [f32; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+        }
+    }
+}
+
+impl Transform {
+    /// columns of the rotation matrix
+    fn rotation_matrix(&self) -> [[f32; 3]; 3] {
+        let [(sin_x, cos_x), (sin_y, cos_y), (sin_z, cos_z)] = self.rotation.map(|angle| angle.to_radians().sin_cos());
+        [
+            [cos_y * cos_z, cos_y * sin_z, -sin_y],
+            [sin_x * sin_y * cos_z - cos_x * sin_z, sin_x * sin_y * sin_z + cos_x * cos_z, sin_x * cos_y],
+            [cos_x * sin_y * cos_z + sin_x * sin_z, cos_x * sin_y * sin_z - sin_x * cos_z, cos_x * cos_y],
+        ]
+    }
+
+    /// the object to world matrix, each inner array is a column like a glsl mat4
+    pub fn matrix(&self) -> [[f32; 4]; 4] {
+        let rotation = self.rotation_matrix();
+        let [x, y, z] = [0, 1, 2].map(|i| rotation[i].map(|value| value * self.scale[i]));
+        let [t_x, t_y, t_z] = self.translation;
+        [
+            [x[0], x[1], x[2], 0.0],
+            [y[0], y[1], y[2], 0.0],
+            [z[0], z[1], z[2], 0.0],
+            [t_x, t_y, t_z, 1.0],
+        ]
+    }
+
+    /// the world to object matrix, undoing the translation, then the rotation and then the scale
+    pub fn inverse_matrix(&self) -> [[f32; 4]; 4] {
+        let rotation = self.rotation_matrix();
+        // the inverse rotation is the transpose
+        let [x, y, z] = [0, 1, 2].map(|column| [0, 1, 2].map(|row| rotation[row][column] / self.scale[row]));
+        let [t_x, t_y, t_z] = [0, 1, 2].map(|row| -(x[row] * self.translation[0] + y[row] * self.translation[1] + z[row] * self.translation[2]));
+        [
+            [x[0], x[1], x[2], 0.0],
+            [y[0], y[1], y[2], 0.0],
+            [z[0], z[1], z[2], 0.0],
+            [t_x, t_y, t_z, 1.0],
+        ]
+    }
+}
+
+
 /// Mesh Representation
 #[derive(Debug, Clone)]
 pub struct RayTracingMesh<T: graphics::Position + BufferContents + Copy + Clone> {
     pub mesh: Mesh<T>,
-    pub material: raytrace_shader::RayTracingMaterial
+    /// texture coordinates for each vertex, meshes without them can leave this empty
+    pub uvs: Vec<[f32; 2]>,
+    pub material: raytrace_shader::RayTracingMaterial,
+    pub transform: Transform,
+}
+
+/// Another copy of a mesh that shares its triangles, placed with its own transform
+#[derive(Debug, Clone)]
+pub struct MeshInstance {
+    /// index of the mesh in the settings' mesh_data
+    pub mesh: usize,
+    pub transform: Transform,
+    /// the mesh's own material is used when this is None
+    pub material: Option<raytrace_shader::RayTracingMaterial>,
 }
 
 pub fn get_null_mesh() -> RayTracingMesh<PositionVertex> {
@@ -42,6 +115,8 @@ pub fn get_null_mesh() -> RayTracingMesh<PositionVertex> {
     mesh.set_normals(vec![Normal{normal: [1.0; 3]}]);
     RayTracingMesh {
         mesh: mesh,
-        material: LambertianMaterial{colour: [1.0; 3]}.into()
+        uvs: Vec::new(),
+        material: LambertianMaterial{colour: [1.0; 3]}.into(),
+        transform: Transform::default(),
     }
 }
\ No newline at end of file
@@ -0,0 +1,288 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use graphics::*;
+use graphics::all_vulkano_utils::renderer::DeviceImageView;
+use graphics::all_vulkano::{
+    pipeline::{PipelineBindPoint, Pipeline},
+    device::Queue,
+    command_buffer::{allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer, },
+    descriptor_set::{allocator::StandardDescriptorSetAllocator, PersistentDescriptorSet, WriteDescriptorSet},
+    image::{StorageImage, ImageUsage},
+    sync::GpuFuture
+};
+
+
+mod post_process_shader {
+    graphics::shader!{
+        ty: "compute",
+        path: "assets/post_process.glsl"
+    }
+}
+
+
+/// Curve used to bring hdr radiance into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    /// values above 1 are clipped
+    Clamp,
+    Reinhard,
+    /// reinhard that maps white_point to 1 instead of infinity
+    ExtendedReinhard {white_point: f32},
+    /// fitted ACES filmic curve
+    Aces,
+    /// approximation of the AgX view transform
+    AgX,
+}
+
+impl FromStr for ToneMapOperator {
+    type Err = String;
+
+    /// parses an operator name, extended reinhard starts with a white point of 4
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "clamp" => Ok(ToneMapOperator::Clamp),
+            "reinhard" => Ok(ToneMapOperator::Reinhard),
+            "extended_reinhard" => Ok(ToneMapOperator::ExtendedReinhard {white_point: 4.0}),
+            "aces" => Ok(ToneMapOperator::Aces),
+            "agx" => Ok(ToneMapOperator::AgX),
+            _ => Err(format!("unknown tone mapping operator {name}, expected one of clamp, reinhard, extended_reinhard, aces or agx"))
+        }
+    }
+}
+
+impl ToneMapOperator {
+    fn shader_id(&self) -> u32 {
+        match self {
+            ToneMapOperator::Clamp => 0,
+            ToneMapOperator::Reinhard => 1,
+            ToneMapOperator::ExtendedReinhard {..} => 2,
+            ToneMapOperator::Aces => 3,
+            ToneMapOperator::AgX => 4,
+        }
+    }
+
+    /// the operator listed after this one, going back to the first after the last
+    pub fn next_operator(&self) -> Self {
+        match self {
+            ToneMapOperator::Clamp => ToneMapOperator::Reinhard,
+            ToneMapOperator::Reinhard => ToneMapOperator::ExtendedReinhard {white_point: 4.0},
+            ToneMapOperator::ExtendedReinhard {..} => ToneMapOperator::Aces,
+            ToneMapOperator::Aces => ToneMapOperator::AgX,
+            ToneMapOperator::AgX => ToneMapOperator::Clamp,
+        }
+    }
+
+    /// applies the operator to a linear colour, a port of the shader version
+    pub fn apply(&self, col: [f32; 3]) -> [f32; 3] {
+        match self {
+            ToneMapOperator::Clamp => col,
+            ToneMapOperator::Reinhard => col.map(|c| c / (1.0 + c)),
+            ToneMapOperator::ExtendedReinhard {white_point} => {
+                let white_point = white_point.max(0.0001);
+                col.map(|c| c * (1.0 + c / (white_point * white_point)) / (1.0 + c))
+            }
+            ToneMapOperator::Aces => aces(col),
+            ToneMapOperator::AgX => agx(col),
+        }
+    }
+}
+
+
+/// the display colour of an accumulated pixel, the same as the post process shader gives
+pub fn display_colour(
+    sum: [f32; 4],
+    tone_map: ToneMapOperator,
+    exposure: f32,
+) -> [u8; 4] {
+    let scale = exposure.exp2() / sum[3].max(1.0);
+    let col = tone_map.apply([sum[0] * scale, sum[1] * scale, sum[2] * scale]);
+    let [r, g, b] = col.map(|c| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8);
+    [r, g, b, 255]
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c < 0.0031308 {c * 12.92} else {1.055 * c.powf(1.0 / 2.4) - 0.055}
+}
+
+/// multiplies by a matrix given as columns, like a glsl mat3
+fn mul_columns(mat: [[f32; 3]; 3], col: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| mat[0][i] * col[0] + mat[1][i] * col[1] + mat[2][i] * col[2])
+}
+
+fn aces(col: [f32; 3]) -> [f32; 3] {
+    const ACES_INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.07600, 0.02840],
+        [0.35458, 0.90834, 0.13383],
+        [0.04823, 0.01566, 0.83777],
+    ];
+    const ACES_OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.10208, -0.00327],
+        [-0.53108, 1.10813, -0.07276],
+        [-0.07367, -0.00605, 1.07602],
+    ];
+
+    let col = mul_columns(ACES_INPUT, col).map(|c| {
+        let a = c * (c + 0.0245786) - 0.000090537;
+        let b = c * (0.983729 * c + 0.4329510) + 0.238081;
+        a / b
+    });
+    mul_columns(ACES_OUTPUT, col)
+}
+
+fn agx(col: [f32; 3]) -> [f32; 3] {
+    const AGX_INPUT: [[f32; 3]; 3] = [
+        [0.842479062253094, 0.0423282422610123, 0.0423756549057051],
+        [0.0784335999999992, 0.878468636469772, 0.0784336],
+        [0.0792237451477643, 0.0791661274605434, 0.879142973793104],
+    ];
+    const AGX_OUTPUT: [[f32; 3]; 3] = [
+        [1.19687900512017, -0.0528968517574562, -0.0529716355144438],
+        [-0.0980208811401368, 1.15190312990417, -0.0980434501171241],
+        [-0.0990297440797205, -0.0989611768448433, 1.15107367264116],
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let col = mul_columns(AGX_INPUT, col.map(|c| c.max(1e-10))).map(|c| {
+        let c = (c.log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+
+        // sigmoid contrast curve
+        let x2 = c * c;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * c + 31.96 * x4 - 6.868 * x2 * c + 0.4298 * x2 + 0.1191 * c - 0.00232
+    });
+
+    mul_columns(AGX_OUTPUT, col).map(|c| c.max(0.0).powf(2.2))
+}
+
+
+/// Exposure, tone mapping and srgb encoding of the accumulated image
+pub struct PostProcessPipeline {
+    image: DeviceImageView,
+    image_size: [u32; 2],
+    pub tone_map: ToneMapOperator,
+    pub exposure: f32,
+
+    compute_queue: Arc<Queue>,
+    compute_pipeline: Arc<ComputePipeline>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+
+impl PostProcessPipeline {
+
+    pub fn new(
+        context: &VulkanoContext,
+        image_size: [u32; 2],
+        tone_map: ToneMapOperator,
+        exposure: f32,
+        command_buffer_allocator: &Arc<StandardCommandBufferAllocator>,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+    ) -> Self {
+
+        let pipeline = ComputePipeline::new(
+            context.device().clone(),
+            post_process_shader::load(context.device().clone()).unwrap().entry_point("main").unwrap(),
+            &(),
+            None,
+            |_| {},
+        ).unwrap();
+
+        let image = StorageImage::general_purpose_image_view(
+            context.memory_allocator(),
+            context.compute_queue().clone(),
+            image_size,
+            Format::R8G8B8A8_UNORM,
+            ImageUsage::SAMPLED | ImageUsage::STORAGE | ImageUsage::TRANSFER_SRC,
+        ).unwrap();
+
+        PostProcessPipeline {
+            image: image,
+            image_size,
+            tone_map,
+            exposure,
+            compute_queue: context.graphics_queue().clone(),
+            compute_pipeline: pipeline,
+            command_buffer_allocator: command_buffer_allocator.clone(),
+            descriptor_set_allocator: descriptor_set_allocator.clone()
+        }
+    }
+
+
+    /// returns the display ready image
+    pub fn image(&self) -> DeviceImageView {
+        self.image.clone()
+    }
+
+    /// tone maps the accumulated image into the display image
+    pub fn apply(
+        &self,
+        accumulated_image: DeviceImageView,
+        before_future: Box<dyn GpuFuture>,
+    ) -> Box<dyn GpuFuture> {
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            &self.command_buffer_allocator,
+            self.compute_queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit
+        ).unwrap();
+
+        let group_numbers = [
+            (self.image_size[0] - 1) / 32 + 1,
+            (self.image_size[1] - 1) / 32 + 1,
+        ];
+
+        self.dispatch(&mut builder, accumulated_image, group_numbers);
+
+        let command_buffer = builder.build().unwrap();
+        let after_future = before_future
+            .then_execute(self.compute_queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap();
+
+        after_future.boxed()
+    }
+
+    fn dispatch(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<
+        PrimaryAutoCommandBuffer,
+        Arc<StandardCommandBufferAllocator>>,
+        accumulated_image: DeviceImageView,
+        group_numbers: [u32; 2]
+    ) {
+
+        let pipeline_layout = self.compute_pipeline.layout();
+        let desc_layout = pipeline_layout.set_layouts().get(0).unwrap();
+        let set = PersistentDescriptorSet::new(
+            &self.descriptor_set_allocator,
+            desc_layout.clone(),
+            [
+                WriteDescriptorSet::image_view(0, accumulated_image),
+                WriteDescriptorSet::image_view(1, self.image.clone())
+            ]
+        ).unwrap();
+
+        let white_point = match self.tone_map {
+            ToneMapOperator::ExtendedReinhard {white_point} => white_point.max(0.0001),
+            _ => 1.0
+        };
+
+        let push_constants = post_process_shader::PushConstants {
+            exposure: self.exposure,
+            white_point: white_point,
+            tone_map: self.tone_map.shader_id(),
+            image_width: self.image_size[0],
+            image_height: self.image_size[1]
+        };
+
+        builder
+            .bind_pipeline_compute(self.compute_pipeline.clone())
+            .bind_descriptor_sets(PipelineBindPoint::Compute, pipeline_layout.clone(), 0, set)
+            .push_constants(pipeline_layout.clone(), 0, push_constants)
+            .dispatch([group_numbers[0], group_numbers[1], 1])
+            .unwrap();
+    }
+}
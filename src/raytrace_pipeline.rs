@@ -1,21 +1,29 @@
 use maths::{Vector3, Matrix3, Vector4};
 use std::sync::Arc;
 use std::collections::BTreeMap;
+use std::fmt;
 use std::mem::size_of;
+use std::str::FromStr;
+use std::time::Instant;
 use graphics::*;
 use graphics::all_vulkano_utils::renderer::DeviceImageView;
 use graphics::all_vulkano::{
     pipeline::{PipelineBindPoint, PipelineLayout, layout::{PipelineLayoutCreateInfo, PushConstantRange}, Pipeline},
     device::Queue,
     command_buffer::{allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer},
+    memory::allocator::StandardMemoryAllocator,
     descriptor_set::{allocator::StandardDescriptorSetAllocator, PersistentDescriptorSet, WriteDescriptorSet, layout::{DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorType, DescriptorSetLayoutCreateInfo}},
-    image::{StorageImage, ImageUsage},
-    sync::GpuFuture,
+    image::{StorageImage, ImmutableImage, ImageUsage, ImageDimensions, MipmapsCount, view::ImageView},
+    sampler::{Sampler, SamplerAddressMode, SamplerCreateInfo, Filter},
+    sync::{self, GpuFuture},
     buffer::BufferContents,
     shader::ShaderStages,
 };
 use super::raytracing_app::RayTracerSettings;
+use super::environment::{Environment, EnvironmentMap};
+use super::textures::TextureAtlas;
 use super::objects::*;
+use super::bvh::{Aabb, Bvh};
 
 
 pub mod raytrace_shader {
@@ -27,6 +35,130 @@ pub mod raytrace_shader {
 }
 
 
+/// How the light reaching each bounce is estimated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplingMode {
+    /// only follow the material, emitters are found when a bounce happens to hit them
+    Bsdf,
+    /// sample the emitters directly wherever the material allows it and ignore emitters hit by bounces
+    Lights,
+    /// do both and weight them with the power heuristic, the lowest noise in most scenes
+    Mis,
+}
+
+impl FromStr for SamplingMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "bsdf" => Ok(SamplingMode::Bsdf),
+            "light" | "lights" => Ok(SamplingMode::Lights),
+            "mis" => Ok(SamplingMode::Mis),
+            _ => Err(format!("unknown sampling mode {name}, expected one of bsdf, light or mis"))
+        }
+    }
+}
+
+impl SamplingMode {
+    fn shader_id(&self) -> u32 {
+        match self {
+            SamplingMode::Bsdf => 0,
+            SamplingMode::Lights => 1,
+            SamplingMode::Mis => 2,
+        }
+    }
+}
+
+
+/// How points on the image are turned into rays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// a pinhole camera looking through the fov
+    Perspective,
+    /// parallel rays from a rectangle ortho_height tall, for diagrams without perspective
+    Orthographic,
+    /// equidistant, the angle from the camera direction grows evenly towards the edges and the fov can be up to 360 degrees
+    Fisheye,
+    /// longitude across and latitude down, an image twice as wide as it is tall covers every direction
+    Equirectangular,
+}
+
+impl FromStr for Projection {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "perspective" => Ok(Projection::Perspective),
+            "orthographic" | "ortho" => Ok(Projection::Orthographic),
+            "fisheye" => Ok(Projection::Fisheye),
+            "equirectangular" | "panorama" => Ok(Projection::Equirectangular),
+            _ => Err(format!("unknown projection {name}, expected one of perspective, orthographic, fisheye or equirectangular"))
+        }
+    }
+}
+
+impl Projection {
+    fn shader_id(&self) -> u32 {
+        match self {
+            Projection::Perspective => 0,
+            Projection::Orthographic => 1,
+            Projection::Fisheye => 2,
+            Projection::Equirectangular => 3,
+        }
+    }
+
+    /// half the height of the image plane in the units get_sample_centre works in
+    pub fn half_height(
+        &self,
+        fov: f32,
+        ortho_height: f32,
+    ) -> f32 {
+        match self {
+            Projection::Perspective => (fov.to_radians() * 0.5).tan(),
+            Projection::Orthographic => ortho_height * 0.5,
+            Projection::Fisheye => fov.to_radians() * 0.5,
+            Projection::Equirectangular => std::f32::consts::FRAC_PI_2,
+        }
+    }
+
+    /// checks the vertical fov can be used with this projection
+    pub fn check_fov(&self, fov: f32) -> Result<(), String> {
+        match self {
+            Projection::Perspective if fov <= 0.0 || fov >= 180.0 => Err(format!("a perspective fov must be between 0 and 180 degrees, not {fov}")),
+            Projection::Fisheye if fov <= 0.0 || fov > 360.0 => Err(format!("a fisheye fov must be between 0 and 360 degrees, not {fov}")),
+            _ => Ok(()),
+        }
+    }
+}
+
+
+/// How much scene data was uploaded to the gpu and how long it took to build
+#[derive(Debug, Clone, Copy)]
+pub struct SceneStats {
+    pub num_spheres: u32,
+    pub num_meshes: u32,
+    pub num_instances: usize,
+    pub num_triangles: u64,
+    pub num_lights: u32,
+    pub mesh_bvh_nodes: u64,
+    pub top_level_bvh_nodes: u64,
+    pub build_seconds: f32,
+}
+
+impl fmt::Display for SceneStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f, "Scene has {} spheres and {} meshes ({} of them instances) with {} unique triangles, {} objects emit light",
+            self.num_spheres, self.num_meshes, self.num_instances, self.num_triangles, self.num_lights
+        )?;
+        write!(
+            f, "Built {} mesh bvh nodes and {} top level bvh nodes in {} seconds",
+            self.mesh_bvh_nodes, self.top_level_bvh_nodes, self.build_seconds
+        )
+    }
+}
+
+
 /// The raytracing pipeline
 pub struct RayTracePipeline {
     compute_queue: Arc<Queue>,
@@ -36,13 +168,24 @@ pub struct RayTracePipeline {
     image: DeviceImageView,
     image_size: [u32; 2],
 
-    ray_data: (Subbuffer<[raytrace_shader::Ray]>, u32),
     sphere_data: (Subbuffer<[raytrace_shader::Sphere]>, u32),
-    sample_jitter: f32,
-    num_samples: u32,
-    max_bounces: u32,
-    use_environment_lighting: bool,
-    mesh_data: (Subbuffer<[raytrace_shader::Triangle]>, Subbuffer<[raytrace_shader::Mesh]>, u32)
+    settings_data: Subbuffer<[raytrace_shader::RenderSettings]>,
+    mesh_data: (Subbuffer<[raytrace_shader::Triangle]>, Subbuffer<[raytrace_shader::Mesh]>, Subbuffer<[raytrace_shader::BvhNode]>, u32),
+    top_level_data: (Subbuffer<[raytrace_shader::BvhNode]>, Subbuffer<[u32]>),
+    light_data: (Subbuffer<[raytrace_shader::Light]>, u32),
+    environment_data: (Arc<ImageView<ImmutableImage>>, Arc<Sampler>, Subbuffer<[f32]>),
+    texture_data: (Subbuffer<[raytrace_shader::Texture]>, Subbuffer<[u32]>),
+    pub stats: SceneStats,
+
+    pub aperture_radius: f32,
+    pub focus_distance: f32,
+    /// vertical field of view in degrees
+    pub fov: f32,
+    pub sensor_offset: [f32; 2],
+    pub projection: Projection,
+    pub ortho_height: f32,
+    /// put the distance to the first hit through each pixel centre in the alpha of the image, which reprojection needs
+    pub write_depth: bool,
 }
 
 
@@ -64,18 +207,50 @@ impl RayTracePipeline {
             None,
         ).unwrap();
         
-        let image = StorageImage::general_purpose_image_view(
-            context.memory_allocator(),
-            context.compute_queue().clone(),
-            image_size,
-            Format::R8G8B8A8_UNORM,
-            ImageUsage::SAMPLED | ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
-        ).unwrap();
+        let image = create_image(context, image_size);
+
+        let start_time = Instant::now();
+        let sphere_data = create_sphere_subbuffer(context, &settings.sphere_data);
+        let (mesh_data, (meshes, first_tris)) = create_mesh_subbuffer(context, &settings.mesh_data, &settings.instances);
+        let mesh_bounds: Vec<Aabb> = meshes.iter().map(|mesh| Aabb {min: mesh.min_point, max: mesh.max_point}).collect();
+        let top_level_data = create_top_level_subbuffer(context, &settings.sphere_data, &mesh_bounds);
+        let light_data = create_light_subbuffer(context, &settings.sphere_data, &meshes, &first_tris);
+        let environment_map = if let Environment::Map(map) = &settings.environment {Some(map)} else {None};
+        let environment_data = create_environment_data(context, command_buffer_allocator, environment_map);
+        let texture_data = create_texture_subbuffers(context, &settings.textures);
+
+        let stats = SceneStats {
+            num_spheres: sphere_data.1,
+            num_meshes: mesh_data.3,
+            num_instances: settings.instances.len(),
+            // empty scenes upload a placeholder mesh
+            num_triangles: if mesh_data.3 == 0 {0} else {mesh_data.0.len()},
+            num_lights: light_data.1,
+            mesh_bvh_nodes: mesh_data.2.len(),
+            top_level_bvh_nodes: top_level_data.0.len(),
+            build_seconds: start_time.elapsed().as_secs_f32(),
+        };
 
-        let (ray_data, num_rays, jitter) = create_ray_subbuffer(context, image_size, settings.camera_focal_length, settings.viewport_height, settings.up);
-        let sphere_data = create_sphere_subbuffer(context, settings.sphere_data);
-        let mesh_data = create_mesh_subbuffer(context, &settings.mesh_data);
-        
+        let (sun_dir, sun_radiance, sky_perez, sky_zenith) = sky_settings(&settings.environment);
+        let settings_data = create_shader_data_buffer(vec![raytrace_shader::RenderSettings {
+            num_spheres: sphere_data.1 as i32,
+            num_meshes: mesh_data.3 as i32,
+            num_lights: light_data.1 as i32,
+            num_samples: settings.num_samples.max(1) as i32,
+            // the shader uses half a pixel when this is negative, so the default follows the image size and fov
+            jitter_size: settings.sample_jitter.unwrap_or(-1.0),
+            max_bounces: settings.max_bounces as i32,
+            environment: settings.environment.shader_id(),
+            sampling_mode: settings.sampling_mode.shader_id(),
+            env_width: environment_map.map_or(0, |map| map.size[0]),
+            env_height: environment_map.map_or(0, |map| map.size[1]),
+            env_rotation: environment_map.map_or(0.0, |map| map.rotation),
+            env_intensity: environment_map.map_or(0.0, |map| map.intensity),
+            sun_dir,
+            sun_radiance,
+            sky_perez,
+            sky_zenith,
+        }], context, BufferType::Storage);
 
         RayTracePipeline {
             compute_queue: context.graphics_queue().clone(),
@@ -85,17 +260,35 @@ impl RayTracePipeline {
             image: image,
             image_size: image_size,
 
-            num_samples: settings.num_samples.max(1),
-            max_bounces: settings.max_bounces.max(0),
-            use_environment_lighting: settings.use_environment_lighting,
-            sample_jitter: settings.sample_jitter.unwrap_or(jitter),
-
-            ray_data: (ray_data, num_rays),
             sphere_data: sphere_data,
+            settings_data: settings_data,
             mesh_data: mesh_data,
+            top_level_data: top_level_data,
+            light_data: light_data,
+            environment_data: environment_data,
+            texture_data: texture_data,
+            stats,
+
+            aperture_radius: settings.aperture_radius,
+            focus_distance: settings.focus_distance,
+            fov: settings.fov,
+            sensor_offset: settings.sensor_offset,
+            projection: settings.projection,
+            ortho_height: settings.ortho_height,
+            write_depth: settings.temporal_reprojection && settings.projection == Projection::Perspective,
         }
     }
 
+    /// replaces the image with one of a new size, the scene data is kept
+    pub fn resize(
+        &mut self,
+        context: &VulkanoContext,
+        image_size: [u32; 2],
+    ) {
+        self.image = create_image(context, image_size);
+        self.image_size = image_size;
+    }
+
     /// return the pipeline layout, maually adjusted
     fn get_pipeline_layout(
         context: &VulkanoContext
@@ -104,10 +297,18 @@ impl RayTracePipeline {
         let mut bindings = BTreeMap::new();
 
         bindings.insert(0, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageImage));
-        bindings.insert(1, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
         bindings.insert(2, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
         bindings.insert(3, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
         bindings.insert(4, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(5, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(6, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(7, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(8, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(9, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(10, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::CombinedImageSampler));
+        bindings.insert(11, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(12, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
+        bindings.insert(13, DescriptorSetLayoutBinding::descriptor_type(DescriptorType::StorageBuffer));
 
         for binding in bindings.iter_mut() {
             binding.1.stages = ShaderStages::COMPUTE;
@@ -125,17 +326,16 @@ impl RayTracePipeline {
         let push_const_size = 
             size_of::<f32>() * 4 + // cam poss
             size_of::<f32>() * 16 + // cam allignment mat
-            size_of::<i32>() + // num_rays;
-            size_of::<i32>() + // num_spheres;
-            size_of::<i32>() + // num_meshes
-            size_of::<i32>() + // num_samples;
-            size_of::<f32>() + // jitter_size;
-            size_of::<i32>() + // max_bounces;
-            size_of::<u32>() + // use_environment_light
             size_of::<u32>() + // rng_offset
             size_of::<u32>() + // init
             size_of::<u32>() + // width
-            size_of::<u32>() // height
+            size_of::<u32>() + // height
+            size_of::<f32>() + // aperture_radius
+            size_of::<f32>() + // focus_distance
+            size_of::<f32>() * 2 + // sensor_offset
+            size_of::<f32>() + // half_height
+            size_of::<u32>() + // projection
+            size_of::<u32>() // write_depth
         ;
 
 
@@ -157,6 +357,10 @@ impl RayTracePipeline {
         self.image.clone()
     }
 
+    pub fn image_size(&self) -> [u32; 2] {
+        self.image_size
+    }
+
 
     /// next pass of raytracing
     pub fn compute(
@@ -229,10 +433,18 @@ impl RayTracePipeline {
             desc_layout.clone(),
             [
                 WriteDescriptorSet::image_view(0, self.image.clone()),
-                WriteDescriptorSet::buffer(1, self.ray_data.0.clone()),
                 WriteDescriptorSet::buffer(2, self.sphere_data.0.clone()),
                 WriteDescriptorSet::buffer(3, self.mesh_data.0.clone()),
-                WriteDescriptorSet::buffer(4, self.mesh_data.1.clone())
+                WriteDescriptorSet::buffer(4, self.mesh_data.1.clone()),
+                WriteDescriptorSet::buffer(5, self.mesh_data.2.clone()),
+                WriteDescriptorSet::buffer(6, self.top_level_data.0.clone()),
+                WriteDescriptorSet::buffer(7, self.top_level_data.1.clone()),
+                WriteDescriptorSet::buffer(8, self.light_data.0.clone()),
+                WriteDescriptorSet::buffer(9, self.settings_data.clone()),
+                WriteDescriptorSet::image_view_sampler(10, self.environment_data.0.clone(), self.environment_data.1.clone()),
+                WriteDescriptorSet::buffer(11, self.environment_data.2.clone()),
+                WriteDescriptorSet::buffer(12, self.texture_data.0.clone()),
+                WriteDescriptorSet::buffer(13, self.texture_data.1.clone())
             ],
         )
         .unwrap();
@@ -242,18 +454,17 @@ impl RayTracePipeline {
 
         let push_constants = raytrace_shader::PushConstants {
             cam_pos: camera.position.extend().into(),
-            cam_alignment_mat: self.get_view_matrix(camera),
-            num_rays: self.ray_data.1 as i32,
-            num_spheres: self.sphere_data.1 as i32,
-            num_meshes: self.mesh_data.2 as i32,
-            num_samples: self.num_samples as i32,
-            jitter_size: self.sample_jitter,
-            max_bounces: self.max_bounces as i32,
-            use_environment_light: self.use_environment_lighting as u32,
+            cam_alignment_mat: get_view_matrix(camera),
             rng_offset: rng_offset,
             init: init as u32,
             width: self.image_size[0],
-            height: self.image_size[1]
+            height: self.image_size[1],
+            aperture_radius: self.aperture_radius,
+            focus_distance: self.focus_distance,
+            sensor_offset: self.sensor_offset,
+            half_height: self.projection.half_height(self.fov, self.ortho_height),
+            projection: self.projection.shader_id(),
+            write_depth: self.write_depth as u32,
         };
 
 
@@ -264,84 +475,70 @@ impl RayTracePipeline {
             .dispatch([to_process_x, to_process_y, 1])
             .unwrap();
     }
+}
 
-    /// https://math.stackexchange.com/questions/2546457/plane-vector-rotation
-    fn get_view_matrix(
-        &self,
-        camera: &Camera,
-    ) -> [[f32; 4]; 4]{
-        let new_x = camera.direction.normalised();
-        let new_z = -camera.direction.cross(camera.up).normalised();
-        let new_y = -new_x.cross(new_z).normalised();
-
-        
-        let allignment_mat = Matrix3::from_columns(new_x, new_y, new_z).transposed();
-        [
-            allignment_mat.x.extend().into(),
-            allignment_mat.y.extend().into(),
-            allignment_mat.z.extend().into(),
-            Vector4::W.into(),
-        ]
-    }
+/// matrix rotating the rays from around (1, 0, 0) to the camera direction
+/// https://math.stackexchange.com/questions/2546457/plane-vector-rotation
+pub fn get_view_matrix(
+    camera: &Camera,
+) -> [[f32; 4]; 4]{
+    let new_x = camera.direction.normalised();
+    let new_z = -camera.direction.cross(camera.up).normalised();
+    let new_y = -new_x.cross(new_z).normalised();
+
+    
+    let allignment_mat = Matrix3::from_columns(new_x, new_y, new_z).transposed();
+    [
+        allignment_mat.x.extend().into(),
+        allignment_mat.y.extend().into(),
+        allignment_mat.z.extend().into(),
+        Vector4::W.into(),
+    ]
 }
 
-// creates the rays sent to the shader, they are centered around (1, 0, 0) and get transformed there
-fn create_ray_subbuffer(
-    context: &VulkanoContext,
+/// the centre of a pixel on the image plane one unit in front of a camera looking along (1, 0, 0) with y up,
+/// the same as get_sample_centre in the shader. half_height comes from Projection::half_height and the sensor
+/// offset shifts the image by a fraction of its width and height
+pub fn get_sample_centre(
+    pixel: [u32; 2],
     image_size: [u32; 2],
-    camera_focal_length: f32,
-    viewport_height: f32,
-    up: impl Into<Vector3>,
-) -> (Subbuffer<[raytrace_shader::Ray]>, u32, f32) {
-
-    // zero length protection
-    if image_size[0] == 0 || image_size[1] == 0 {
-        let null_ray = vec![raytrace_shader::Ray {
-            sample_centre: [0.0, 0.0, 0.0, 0.0],
-        }];
-        return (create_shader_data_buffer(null_ray, context, BufferType::Storage), 0, 0.0);
-    }
-
-
-
-    let viewport_width = viewport_height * (image_size[0] as f32 / image_size[1] as f32);
-
-    let viewport_x = up.into().cross(Vector3::X).normalised();
-    let viewport_y = viewport_x.cross(Vector3::X).normalised();
-    let viewport_upper_left = Vector3::ZERO + Vector3::X * camera_focal_length - (viewport_x * viewport_width + viewport_y * viewport_height) * 0.5;
-
-    let pixel_x: Vector3 = viewport_x * viewport_width / image_size[0] as f32;
-    let pixel_y: Vector3 = viewport_y * viewport_height / image_size[1] as f32;
-
-    let first_ray = viewport_upper_left + (pixel_x + pixel_y) * 0.5;
-    let mut ray_centres: Vec<[f32; 4]> = Vec::new();
-
-    for y in 0..image_size[1] {
-        for x in 0..image_size[0] {
-            let ray_pos = first_ray + pixel_x * x as f32 + pixel_y * y as f32;
-            ray_centres.push(
-                ray_pos.extend().into()
-            );
-        }
-    }
-
+    half_height: f32,
+    sensor_offset: [f32; 2],
+) -> [f32; 3] {
+    let aspect = image_size[0] as f32 / image_size[1] as f32;
+    let u = ((pixel[0] as f32 + 0.5) / image_size[0] as f32 * 2.0 - 1.0 + sensor_offset[0] * 2.0) * aspect * half_height;
+    let v = ((pixel[1] as f32 + 0.5) / image_size[1] as f32 * 2.0 - 1.0 - sensor_offset[1] * 2.0) * half_height;
+    // image x goes along -z and image y goes down along -y
+    [1.0, -v, -u]
+}
 
-    let mut rays: Vec<raytrace_shader::Ray> = Vec::new();
-    for centre in ray_centres {
-        rays.push(raytrace_shader::Ray {
-            sample_centre: centre,
-        });
-    }
+/// half the size of a pixel on the image plane
+pub fn default_jitter(
+    image_size: [u32; 2],
+    half_height: f32,
+) -> f32 {
+    half_height / image_size[1] as f32
+}
 
-    let num_rays = rays.len() as u32;
-    (create_shader_data_buffer(rays, context, BufferType::Storage), num_rays, pixel_x.magnitude().max(pixel_y.magnitude()) * 0.5)
+/// the image each pass of raytracing is written to
+fn create_image(
+    context: &VulkanoContext,
+    image_size: [u32; 2],
+) -> DeviceImageView {
+    StorageImage::general_purpose_image_view(
+        context.memory_allocator(),
+        context.compute_queue().clone(),
+        image_size,
+        Format::R32G32B32A32_SFLOAT,
+        ImageUsage::SAMPLED | ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
+    ).unwrap()
 }
 
 
 /// transformes list of spheres to subbuffer of raytrace spheres
 fn create_sphere_subbuffer(
     context: &VulkanoContext,
-    sphere_data: Vec<Sphere>
+    sphere_data: &Vec<Sphere>
 ) -> (Subbuffer<[raytrace_shader::Sphere]>, u32) {
 
     // zero length protection
@@ -359,35 +556,204 @@ fn create_sphere_subbuffer(
     (create_shader_data_buffer(spheres, context, BufferType::Storage), num_spheres)
 }
 
-/// transformes list of meshes to subbuffer of raytrace meshes
+/// transformes list of meshes and their instances to subbuffer of raytrace meshes,
+/// also returns the raytrace meshes and the first triangle of each
 fn create_mesh_subbuffer<T: graphics::Position + BufferContents + Copy + Clone>(
     context: &VulkanoContext,
     meshes: &Vec<RayTracingMesh<T>>,
-) -> (Subbuffer<[raytrace_shader::Triangle]>, Subbuffer<[raytrace_shader::Mesh]>, u32) {
+    instances: &[MeshInstance],
+) -> ((Subbuffer<[raytrace_shader::Triangle]>, Subbuffer<[raytrace_shader::Mesh]>, Subbuffer<[raytrace_shader::BvhNode]>, u32), (Vec<raytrace_shader::Mesh>, Vec<u32>)) {
 
-    // zero length protection
-    let (tris, mesh_data) = if meshes.len() == 0 {transform_meshes(&vec![get_null_mesh()])} else {transform_meshes(meshes)};
+    // zero length protection, instances can't exist without a mesh to copy
+    let (tris, mesh_data, nodes, first_tris) = if meshes.len() == 0 {transform_meshes(&vec![get_null_mesh()], &[])} else {transform_meshes(meshes, instances)};
+    let num_meshes = if meshes.len() == 0 {0} else {mesh_data.len()};
+    let raytrace_meshes = mesh_data.iter().take(num_meshes).cloned().collect();
+    let first_tris = first_tris.into_iter().take(num_meshes).collect();
 
     let tri_buffer = create_shader_data_buffer(tris, context, BufferType::Storage);
     let mesh_buffer = create_shader_data_buffer(mesh_data, context, BufferType::Storage);
-    (tri_buffer, mesh_buffer, meshes.len() as u32)
+    let node_buffer = create_shader_data_buffer(nodes, context, BufferType::Storage);
+    ((tri_buffer, mesh_buffer, node_buffer, num_meshes as u32), (raytrace_meshes, first_tris))
+}
+
+/// builds the top level bvh over every sphere and mesh
+fn create_top_level_subbuffer(
+    context: &VulkanoContext,
+    sphere_data: &Vec<Sphere>,
+    mesh_bounds: &Vec<Aabb>,
+) -> (Subbuffer<[raytrace_shader::BvhNode]>, Subbuffer<[u32]>) {
+    let top_level = build_top_level(sphere_data, mesh_bounds);
+
+    // zero length protection
+    let objects = if top_level.order.len() == 0 {vec![0]} else {top_level.order};
+
+    let node_buffer = create_shader_data_buffer(top_level.nodes, context, BufferType::Storage);
+    let object_buffer = create_shader_data_buffer(objects, context, BufferType::Storage);
+    (node_buffer, object_buffer)
+}
+
+/// creates the list of emissive objects that are sampled directly
+fn create_light_subbuffer(
+    context: &VulkanoContext,
+    sphere_data: &Vec<Sphere>,
+    meshes: &Vec<raytrace_shader::Mesh>,
+    first_tris: &Vec<u32>,
+) -> (Subbuffer<[raytrace_shader::Light]>, u32) {
+    let lights = build_lights(sphere_data, meshes, first_tris);
+    let num_lights = lights.len() as u32;
+
+    // zero length protection
+    let lights = if lights.len() == 0 {vec![raytrace_shader::Light {object: 0, first_tri: 0, num_tris: 0}]} else {lights};
+    (create_shader_data_buffer(lights, context, BufferType::Storage), num_lights)
+}
+
+/// the sun direction and radiance and the perez coefficients and zenith colour of the sky, all 0 without a sky
+fn sky_settings(environment: &Environment) -> ([f32; 4], [f32; 4], [[f32; 4]; 5], [f32; 4]) {
+    let Environment::Sky(sky) = environment else {
+        return ([0.0, 1.0, 0.0, 1.0], [0.0; 4], [[0.0; 4]; 5], [0.0; 4]);
+    };
+
+    let [x, y, z] = sky.sun_dir;
+    let [r, g, b] = sky.sun_radiance;
+    (
+        [x, y, z, sky.cos_sun_radius],
+        [r, g, b, 0.0],
+        sky.perez.map(|[c_y, c_x, c_z]| [c_y, c_x, c_z, 0.0]),
+        [sky.zenith[0], sky.zenith[1], sky.zenith[2], 0.0],
+    )
+}
+
+/// uploads the environment map and its cdf, a single black pixel stands in when there is no map
+fn create_environment_data(
+    context: &VulkanoContext,
+    command_buffer_allocator: &Arc<StandardCommandBufferAllocator>,
+    environment_map: Option<&EnvironmentMap>,
+) -> (Arc<ImageView<ImmutableImage>>, Arc<Sampler>, Subbuffer<[f32]>) {
+    let (size, pixels, cdf) = match environment_map {
+        Some(map) => (map.size, map.pixels.clone(), map.cdf.clone()),
+        None => ([1, 1], vec![[0.0; 4]], vec![1.0, 1.0]),
+    };
+
+    let mut builder = AutoCommandBufferBuilder::primary(
+        command_buffer_allocator,
+        context.graphics_queue().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    ).unwrap();
+
+    let allocator: &StandardMemoryAllocator = context.memory_allocator();
+    let image = ImmutableImage::from_iter(
+        allocator,
+        pixels,
+        ImageDimensions::Dim2d {width: size[0], height: size[1], array_layers: 1},
+        MipmapsCount::One,
+        Format::R32G32B32A32_SFLOAT,
+        &mut builder,
+    ).unwrap();
+
+    let command_buffer = builder.build().unwrap();
+    sync::now(context.device().clone())
+        .then_execute(context.graphics_queue().clone(), command_buffer)
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    // the shader reads exact pixels with texelFetch, so filtering is never used
+    let sampler = Sampler::new(
+        context.device().clone(),
+        SamplerCreateInfo {
+            mag_filter: Filter::Nearest,
+            min_filter: Filter::Nearest,
+            address_mode: [SamplerAddressMode::Repeat, SamplerAddressMode::ClampToEdge, SamplerAddressMode::ClampToEdge],
+            ..Default::default()
+        },
+    ).unwrap();
+
+    (ImageView::new_default(image).unwrap(), sampler, create_shader_data_buffer(cdf, context, BufferType::Storage))
+}
+
+/// uploads the texture descriptions and the texels they point into
+fn create_texture_subbuffers(
+    context: &VulkanoContext,
+    textures: &TextureAtlas,
+) -> (Subbuffer<[raytrace_shader::Texture]>, Subbuffer<[u32]>) {
+
+    // zero length protection, no material refers to the placeholder
+    if textures.is_empty() {
+        let null_texture = vec![raytrace_shader::Texture {offset: 0, width: 1, height: 1}];
+        return (create_shader_data_buffer(null_texture, context, BufferType::Storage), create_shader_data_buffer(vec![0], context, BufferType::Storage));
+    }
+
+    let texture_buffer = create_shader_data_buffer(textures.textures.clone(), context, BufferType::Storage);
+    let texel_buffer = create_shader_data_buffer(textures.texels.clone(), context, BufferType::Storage);
+    (texture_buffer, texel_buffer)
+}
+
+/// every sphere and mesh with an emissive material, objects are numbered like the top level bvh.
+/// first_tris holds where each mesh's triangles start, as returned by transform_meshes
+pub fn build_lights(
+    sphere_data: &[Sphere],
+    meshes: &[raytrace_shader::Mesh],
+    first_tris: &[u32],
+) -> Vec<raytrace_shader::Light> {
+    let mut lights = Vec::new();
+    for (i, sphere) in sphere_data.iter().enumerate() {
+        if sphere.material.emission[3] > 0.0 {
+            lights.push(raytrace_shader::Light {object: i as u32, first_tri: 0, num_tris: 0});
+        }
+    }
+
+    for (i, (mesh, first_tri)) in meshes.iter().zip(first_tris).enumerate() {
+        if mesh.material.emission[3] > 0.0 && mesh.len > 0 {
+            lights.push(raytrace_shader::Light {object: (sphere_data.len() + i) as u32, first_tri: *first_tri, num_tris: mesh.len});
+        }
+    }
+    lights
 }
 
-/// transform meshes into triangles and mesh info
-fn transform_meshes<T: graphics::Position + BufferContents + Copy + Clone>(
+/// builds a bvh over the bounds of the spheres and meshes, objects are numbered with spheres first and then meshes
+pub fn build_top_level(
+    sphere_data: &[Sphere],
+    mesh_bounds: &[Aabb],
+) -> Bvh {
+    let mut bounds: Vec<Aabb> = sphere_data.iter().map(|sphere| {
+        let [x, y, z] = sphere.centre;
+        let r = sphere.radius;
+        Aabb {
+            min: [x - r, y - r, z - r],
+            max: [x + r, y + r, z + r],
+        }
+    }).collect();
+    bounds.extend_from_slice(mesh_bounds);
+
+    Bvh::build(&bounds, 0, 0)
+}
+
+/// transform meshes into object space triangles, mesh info and a bvh for each mesh, followed by mesh info for
+/// each instance that points at the triangles and bvh of the mesh it copies. also returns the first triangle of every mesh info.
+/// the triangles of each mesh are reordered to match the leaves of its bvh
+pub fn transform_meshes<T: graphics::Position + BufferContents + Copy + Clone>(
     meshes: &Vec<RayTracingMesh<T>>,
-) -> (Vec<raytrace_shader::Triangle>, Vec<raytrace_shader::Mesh>){
+    instances: &[MeshInstance],
+) -> (Vec<raytrace_shader::Triangle>, Vec<raytrace_shader::Mesh>, Vec<raytrace_shader::BvhNode>, Vec<u32>){
 
-    let mut tri_count = 0;
     let mut tris: Vec<raytrace_shader::Triangle> = Vec::new();
     let mut mesh_data: Vec<raytrace_shader::Mesh> = Vec::new();
+    let mut nodes: Vec<raytrace_shader::BvhNode> = Vec::new();
+    let mut first_tris: Vec<u32> = Vec::new();
+    // object space bounds of each mesh
+    let mut object_bounds: Vec<Aabb> = Vec::new();
     for mesh in meshes.iter() {
 
         let mat = mesh.material.clone();
+        let transform = mesh.transform;
+        let uvs = if mesh.uvs.len() == mesh.mesh.vertices.len() {mesh.uvs.clone()} else {vec![[0.0; 2]; mesh.mesh.vertices.len()]};
         let mesh = mesh.mesh.clone();
-        let (mut min_x, mut min_y, mut min_z) = (f32::MAX, f32::MAX, f32::MAX);
-        let (mut max_x, mut max_y, mut max_z) = (f32::MIN, f32::MIN, f32::MIN);
-        
+        let mut mesh_tris: Vec<raytrace_shader::Triangle> = Vec::new();
+        let mut tri_bounds: Vec<Aabb> = Vec::new();
+        let vertex_normals = get_vertex_normals(&mesh);
+
 
         for i in (0..mesh.indices.len()).step_by(3) {
             let a: Vector3 = mesh.vertices[mesh.indices[i + 0] as usize].pos().into();
@@ -396,33 +762,121 @@ fn transform_meshes<T: graphics::Position + BufferContents + Copy + Clone>(
             let edge_one = b - a;
             let edge_two = c - a;
             let norm = edge_one.cross(edge_two);
+            let [normal_a, normal_b, normal_c] = [0, 1, 2].map(|j| vertex_normals[mesh.indices[i + j] as usize].extend().into());
+            let [uv_a, uv_b, uv_c] = [0, 1, 2].map(|j| uvs[mesh.indices[i + j] as usize]);
 
-            min_x = min_x.min(a.x.min(b.x.min(c.x)));
-            min_y = min_y.min(a.y.min(b.y.min(c.y)));
-            min_z = min_z.min(a.z.min(b.z.min(c.z)));
+            tri_bounds.push(Aabb::from_points(&[a.into(), b.into(), c.into()]));
 
-            max_x = max_x.max(a.x.max(b.x.max(c.x)));
-            max_y = max_y.max(a.y.max(b.y.max(c.y)));
-            max_z = max_z.max(a.z.max(b.z.max(c.z)));
-
-            tris.push(raytrace_shader::Triangle {
+            mesh_tris.push(raytrace_shader::Triangle {
                 a: a.extend().into(),
                 edge_one: edge_one.extend().into(),
                 edge_two: edge_two.extend().into(),
-                normal: norm.extend().into()
+                normal: norm.extend().into(),
+                normal_a,
+                normal_b,
+                normal_c,
+                uv_ab: [uv_a[0], uv_a[1], uv_b[0], uv_b[1]],
+                uv_c: [uv_c[0], uv_c[1], 0.0, 0.0],
+                tangent: get_tangent(edge_one, edge_two, norm, [uv_a, uv_b, uv_c]),
             })
         }
 
-        let num_tris = mesh.indices.len() as u32 / 3;
-        mesh_data.push(raytrace_shader::Mesh {
-            first_index: tri_count,
-            len: num_tris,
-            material: mat,
-            min_point: [min_x, min_y, min_z],
-            max_point: [max_x, max_y, max_z]
-        });
-        tri_count += num_tris;
+        let bvh = Bvh::build(&tri_bounds, nodes.len() as u32, tris.len() as u32);
+        let root = &bvh.nodes[0];
+        let bounds = Aabb {min: root.min_point, max: root.max_point};
+        mesh_data.push(transformed_mesh(nodes.len() as u32, mesh_tris.len() as u32, mat, &transform, &bounds));
+        first_tris.push(tris.len() as u32);
+        object_bounds.push(bounds);
+
+        for index in bvh.order.iter() {
+            tris.push(mesh_tris[*index as usize].clone());
+        }
+        nodes.extend(bvh.nodes);
+    }
+
+    for instance in instances.iter() {
+        let original = &mesh_data[instance.mesh];
+        let material = instance.material.clone().unwrap_or(original.material.clone());
+        mesh_data.push(transformed_mesh(original.root_node, original.len, material, &instance.transform, &object_bounds[instance.mesh]));
+        first_tris.push(first_tris[instance.mesh]);
+    }
+
+    (tris, mesh_data, nodes, first_tris)
+}
+
+/// mesh info placed in the world by the transform, the bounds are the mesh's object space bounds
+fn transformed_mesh(
+    root_node: u32,
+    len: u32,
+    material: raytrace_shader::RayTracingMaterial,
+    transform: &Transform,
+    bounds: &Aabb,
+) -> raytrace_shader::Mesh {
+    let object_to_world = transform.matrix();
+    // the world space box around every corner of the object space box
+    let corners: Vec<[f32; 3]> = (0..8).map(|i| {
+        let corner = [0, 1, 2].map(|axis| if i & (1 << axis) == 0 {bounds.min[axis]} else {bounds.max[axis]});
+        [0, 1, 2].map(|row| (0..3).map(|column| object_to_world[column][row] * corner[column]).sum::<f32>() + object_to_world[3][row])
+    }).collect();
+    let world_bounds = Aabb::from_points(&corners);
+
+    raytrace_shader::Mesh {
+        root_node,
+        len,
+        material,
+        min_point: world_bounds.min,
+        max_point: world_bounds.max,
+        object_to_world,
+        world_to_object: transform.inverse_matrix(),
+    }
+}
+
+/// the direction texture u increases in across a triangle, with the sign that turns normal x tangent into the
+/// direction v increases in as w. v is flipped back to point up the image as tangent space normal maps expect.
+/// zero when the texture coordinates don't span an area
+fn get_tangent(
+    edge_one: Vector3,
+    edge_two: Vector3,
+    normal: Vector3,
+    uvs: [[f32; 2]; 3],
+) -> [f32; 4] {
+    let [a, b, c] = uvs;
+    let (du_one, dv_one) = (b[0] - a[0], a[1] - b[1]);
+    let (du_two, dv_two) = (c[0] - a[0], a[1] - c[1]);
+    let det = du_one * dv_two - du_two * dv_one;
+    if det == 0.0 {return [0.0; 4];}
+
+    let tangent = (edge_one * dv_two - edge_two * dv_one) * (1.0 / det);
+    let bitangent: [f32; 3] = ((edge_two * du_one - edge_one * du_two) * (1.0 / det)).into();
+    let normal_cross_tangent: [f32; 3] = normal.cross(tangent).into();
+    let handedness: f32 = (0..3).map(|i| normal_cross_tangent[i] * bitangent[i]).sum();
+
+    let [x, y, z]: [f32; 3] = tangent.into();
+    [x, y, z, if handedness < 0.0 {-1.0} else {1.0}]
+}
+
+/// a unit normal for every vertex, the ones read from the obj file if it has them and otherwise
+/// the average of the faces around each vertex, weighted by area as the cross product is twice the area.
+/// vertices only on degenerate faces get a zero normal, which the shader replaces with the face normal
+fn get_vertex_normals<T: graphics::Position + BufferContents + Copy + Clone>(
+    mesh: &Mesh<T>,
+) -> Vec<Vector3> {
+    let unit_or_zero = |normal: Vector3| if normal.magnitude() > 0.0 {normal.normalised()} else {Vector3::ZERO};
+
+    if mesh.normals.len() == mesh.vertices.len() {
+        return mesh.normals.iter().map(|normal| unit_or_zero(normal.normal.into())).collect();
     }
 
-    (tris, mesh_data)
-}
\ No newline at end of file
+    let mut normals = vec![Vector3::ZERO; mesh.vertices.len()];
+    for i in (0..mesh.indices.len()).step_by(3) {
+        let a: Vector3 = mesh.vertices[mesh.indices[i + 0] as usize].pos().into();
+        let b: Vector3 = mesh.vertices[mesh.indices[i + 1] as usize].pos().into();
+        let c: Vector3 = mesh.vertices[mesh.indices[i + 2] as usize].pos().into();
+        let face_normal = (b - a).cross(c - a);
+        for j in 0..3 {
+            let index = mesh.indices[i + j] as usize;
+            normals[index] = normals[index] + face_normal;
+        }
+    }
+    normals.into_iter().map(unit_or_zero).collect()
+}
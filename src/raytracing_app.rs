@@ -1,42 +1,75 @@
+use std::path::Path;
 use std::sync::Arc;
 use graphics::*;
 use graphics::all_vulkano::{
     format::Format,
-    buffer::BufferContents
+    buffer::BufferContents,
+    device::{DeviceExtensions, physical::PhysicalDevice},
+    sync::{self, GpuFuture},
 };
 use graphics::all_vulkano_utils::{window::{VulkanoWindows, WindowDescriptor}, context::VulkanoConfig};
 use super::{
-    diffuse::DiffusePipeline,
-    raytrace_pipeline::RayTracePipeline,
+    diffuse::{DiffusePipeline, ReprojectionCameras},
+    export::{save_image, save_hdr_image},
+    post_process::{PostProcessPipeline, ToneMapOperator},
+    environment::Environment,
+    textures::TextureAtlas,
+    raytrace_pipeline::{RayTracePipeline, SamplingMode, Projection, get_view_matrix},
     texture_draw_pipeline::RenderPassOverFrame,
+    cpu_tracer::CpuRayTracer,
 };
 use super::objects::*;
 
 /// Settings to be passed into the raytrace pipeline on creation
 #[derive(Clone, Debug)]
 pub struct RayTracerSettings<T: graphics::Position + BufferContents + Copy + Clone> {
+    /// on the image plane one unit in front of the camera, None for half a pixel
     pub sample_jitter: Option<f32>,
     pub num_samples: u32,
     pub max_bounces: u32,
-    pub use_environment_lighting: bool,
+    pub sampling_mode: SamplingMode,
+    pub environment: Environment,
     
     pub sphere_data: Vec<Sphere>,
     pub mesh_data: Vec<RayTracingMesh<T>>,
-
-    pub camera_focal_length: f32,
-    pub viewport_height: f32,
-    pub up: [f32; 3],
+    /// more copies of the meshes in mesh_data
+    pub instances: Vec<MeshInstance>,
+    /// the images materials refer to by index
+    pub textures: TextureAtlas,
+
+    /// vertical field of view in degrees, the horizontal one follows from the aspect of the image
+    pub fov: f32,
+    /// shifts the image by a fraction of its width and height without turning the camera
+    pub sensor_offset: [f32; 2],
+    pub projection: Projection,
+    /// height of the view in world units for the orthographic projection
+    pub ortho_height: f32,
+    /// radius of the lens, 0 for a pinhole camera where everything is in focus
+    pub aperture_radius: f32,
+    /// distance along the camera direction to the plane that is in focus
+    pub focus_distance: f32,
+
+    pub tone_map: ToneMapOperator,
+    pub exposure: f32,
+    /// keep the accumulated image while the camera turns a little by moving it to where the new view sees it,
+    /// instead of starting again. only used with the perspective projection
+    pub temporal_reprojection: bool,
 }
 
+/// the largest turn of the camera between two frames that temporal reprojection reuses the image for
+const MAX_REPROJECTED_TURN_DEGREES: f32 = 10.0;
+
 
 pub struct RayTracingApp<T: graphics::Position + BufferContents + Copy + Clone> {
     pub context: VulkanoContext,
     pub windows: VulkanoWindows,
     pub command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
     pub descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
-    pub pipeline: Option<(RayTracePipeline, DiffusePipeline, RenderPassOverFrame)>,
+    pub pipeline: Option<(RayTracePipeline, DiffusePipeline, PostProcessPipeline, RenderPassOverFrame)>,
     frame: u32,
     pub camera: Camera,
+    /// the camera position and alignment matrix of the last frame, to tell when the camera moves
+    last_view: Option<([f32; 4], [[f32; 4]; 4])>,
     settings: RayTracerSettings<T>
 }
 
@@ -47,8 +80,30 @@ impl<T: graphics::Position + BufferContents + Copy + Clone> RayTracingApp<T> {
         camera: Camera,
         settings: RayTracerSettings<T>
     ) -> Self {
+        RayTracingApp::with_config(camera, settings, VulkanoConfig::default())
+    }
+
+    /// create a new raytracing app that renders without a window,
+    /// any device can be used including software implementations like lavapipe
+    pub fn new_headless(
+        camera: Camera,
+        settings: RayTracerSettings<T>
+    ) -> Self {
+        let config = VulkanoConfig {
+            device_extensions: DeviceExtensions::empty(),
+            device_filter_fn: Arc::new(|_: &PhysicalDevice| true),
+            ..Default::default()
+        };
+        RayTracingApp::with_config(camera, settings, config)
+    }
 
-        let context = VulkanoContext::new(VulkanoConfig::default());
+    fn with_config(
+        camera: Camera,
+        settings: RayTracerSettings<T>,
+        config: VulkanoConfig,
+    ) -> Self {
+
+        let context = VulkanoContext::new(config);
         let command_allocator = Arc::new(StandardCommandBufferAllocator::new(
             context.device().clone(),
             Default::default()
@@ -65,6 +120,7 @@ impl<T: graphics::Position + BufferContents + Copy + Clone> RayTracingApp<T> {
             pipeline: None,
             frame: 0,
             camera,
+            last_view: None,
             settings
         }
     }
@@ -88,25 +144,7 @@ impl<T: graphics::Position + BufferContents + Copy + Clone> RayTracingApp<T> {
             |_| {}
         );
 
-        let raytrace_pipeline = RayTracePipeline::new(
-            &self.context,
-            &self.command_buffer_allocator,
-            &self.descriptor_set_allocator,
-            image_size,
-            self.settings.clone()
-        );
-        let mut diffuse_pipeline = DiffusePipeline::new(
-            &self.context,
-            image_size,
-            &self.command_buffer_allocator,
-            &self.descriptor_set_allocator
-        );
-        let render_pass = RenderPassOverFrame::new(
-            &self.context,
-            &self.command_buffer_allocator,
-            &self.descriptor_set_allocator,
-            Format::B8G8R8A8_UNORM
-        );
+        let (raytrace_pipeline, mut diffuse_pipeline, post_process_pipeline, render_pass) = self.create_pipelines(image_size);
 
         let window_renderer = self.windows.get_primary_renderer_mut().unwrap();
         match window_renderer.window_size() {
@@ -127,19 +165,208 @@ impl<T: graphics::Position + BufferContents + Copy + Clone> RayTracingApp<T> {
 
         let after_raytrace_init_future = raytrace_pipeline.init(before_init_future);
         let after_diffuse_future = diffuse_pipeline.next_frame(self.frame, raytrace_pipeline.image(), after_raytrace_init_future);
+        let after_post_process_future = post_process_pipeline.apply(diffuse_pipeline.image(), after_diffuse_future);
 
-        let image = diffuse_pipeline.image();
+        let image = post_process_pipeline.image();
         let target_image = window_renderer.swapchain_image_view();
 
-        let after_render = render_pass.render(after_diffuse_future, image, target_image);
+        let after_render = render_pass.render(after_post_process_future, image, target_image);
 
         window_renderer.present(after_render, true);
 
-        self.pipeline = Some((raytrace_pipeline, diffuse_pipeline, render_pass));
+        self.pipeline = Some((raytrace_pipeline, diffuse_pipeline, post_process_pipeline, render_pass));
         self.frame += 1;
     }
 
-    
+    /// intitialise all pipelines without opening a window
+    pub fn open_headless(
+        &mut self,
+        image_size: [u32; 2]
+    ) {
+        let (raytrace_pipeline, mut diffuse_pipeline, post_process_pipeline, render_pass) = self.create_pipelines(image_size);
+
+        let before_init_future = sync::now(self.context.device().clone()).boxed();
+        let after_raytrace_init_future = raytrace_pipeline.init(before_init_future);
+        diffuse_pipeline.next_frame(self.frame, raytrace_pipeline.image(), after_raytrace_init_future)
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        self.pipeline = Some((raytrace_pipeline, diffuse_pipeline, post_process_pipeline, render_pass));
+        self.frame += 1;
+    }
+
+    fn create_pipelines(
+        &self,
+        image_size: [u32; 2]
+    ) -> (RayTracePipeline, DiffusePipeline, PostProcessPipeline, RenderPassOverFrame) {
+        let raytrace_pipeline = RayTracePipeline::new(
+            &self.context,
+            &self.command_buffer_allocator,
+            &self.descriptor_set_allocator,
+            image_size,
+            self.settings.clone()
+        );
+        let diffuse_pipeline = DiffusePipeline::new(
+            &self.context,
+            image_size,
+            &self.command_buffer_allocator,
+            &self.descriptor_set_allocator
+        );
+        let post_process_pipeline = PostProcessPipeline::new(
+            &self.context,
+            image_size,
+            self.settings.tone_map,
+            self.settings.exposure,
+            &self.command_buffer_allocator,
+            &self.descriptor_set_allocator
+        );
+        let render_pass = RenderPassOverFrame::new(
+            &self.context,
+            &self.command_buffer_allocator,
+            &self.descriptor_set_allocator,
+            Format::B8G8R8A8_UNORM
+        );
+        (raytrace_pipeline, diffuse_pipeline, post_process_pipeline, render_pass)
+    }
+
+    /// the settings being rendered with, including any changed since the app was made
+    pub fn settings(&self) -> &RayTracerSettings<T> {
+        &self.settings
+    }
+
+    /// set the exposure in stops, takes effect on the next tone mapping pass
+    pub fn set_exposure(
+        &mut self,
+        exposure: f32
+    ) {
+        self.settings.exposure = exposure;
+        if let Some((_, _, post_process_pipeline, _)) = self.pipeline.as_mut() {
+            post_process_pipeline.exposure = exposure;
+        }
+    }
+
+    /// set the tone mapping operator, takes effect on the next tone mapping pass
+    pub fn set_tone_map(
+        &mut self,
+        tone_map: ToneMapOperator
+    ) {
+        self.settings.tone_map = tone_map;
+        if let Some((_, _, post_process_pipeline, _)) = self.pipeline.as_mut() {
+            post_process_pipeline.tone_map = tone_map;
+        }
+    }
+
+    /// set the lens of the camera, takes effect on the next frame and starts the image again
+    pub fn set_focus(
+        &mut self,
+        aperture_radius: f32,
+        focus_distance: f32
+    ) {
+        self.settings.aperture_radius = aperture_radius;
+        self.settings.focus_distance = focus_distance;
+        if let Some((raytrace_pipeline, _, _, _)) = self.pipeline.as_mut() {
+            raytrace_pipeline.aperture_radius = aperture_radius;
+            raytrace_pipeline.focus_distance = focus_distance;
+        }
+        self.reset_accumulation();
+    }
+
+    /// focuses the lens on whatever is in the centre of the image, found with a ray cast on the cpu.
+    /// the focus distance is left as it is when the centre of the image looks at the environment
+    pub fn autofocus(&mut self) -> Option<f32> {
+        let focus_distance = CpuRayTracer::new([1, 1], &self.settings).focus_distance(&self.camera)?;
+        self.set_focus(self.settings.aperture_radius, focus_distance);
+        Some(focus_distance)
+    }
+
+    /// set the vertical field of view in degrees, takes effect on the next frame and starts the image again
+    pub fn set_fov(
+        &mut self,
+        fov: f32
+    ) {
+        self.settings.fov = fov;
+        if let Some((raytrace_pipeline, _, _, _)) = self.pipeline.as_mut() {
+            raytrace_pipeline.fov = fov;
+        }
+        self.reset_accumulation();
+    }
+
+    /// throw away the accumulated image so the next frame starts a new one,
+    /// for anything that changes what the scene looks like
+    pub fn reset_accumulation(&mut self) {
+        if let Some((_, diffuse_pipeline, _, _)) = self.pipeline.as_mut() {
+            diffuse_pipeline.reset();
+        }
+    }
+
+    /// reprojects the accumulated image after small turns when temporal reprojection is on and starts again otherwise
+    fn camera_moved(
+        &mut self,
+        last_view: ([f32; 4], [[f32; 4]; 4]),
+        view: ([f32; 4], [[f32; 4]; 4])
+    ) {
+        let Some((_, diffuse_pipeline, _, _)) = self.pipeline.as_mut() else {return;};
+
+        // the first column of the alignment matrix is where the camera looks
+        let [last_forward, forward] = [last_view.1[0], view.1[0]];
+        let cos_turn = (0..3).map(|i| last_forward[i] * forward[i]).sum::<f32>();
+        let small_turn = cos_turn >= MAX_REPROJECTED_TURN_DEGREES.to_radians().cos();
+
+        if self.settings.temporal_reprojection && self.settings.projection == Projection::Perspective && small_turn {
+            diffuse_pipeline.reproject(&self.context, ReprojectionCameras {
+                cam_pos: view.0,
+                cam_alignment_mat: view.1,
+                prev_cam_pos: last_view.0,
+                prev_cam_alignment_mat: last_view.1,
+                sensor_offset: self.settings.sensor_offset,
+                half_height: self.settings.projection.half_height(self.settings.fov, self.settings.ortho_height),
+            });
+        } else {
+            diffuse_pipeline.reset();
+        }
+    }
+
+    /// resize every image to match a new window size and start accumulating again,
+    /// the scene data on the gpu is kept
+    pub fn resize(
+        &mut self,
+        image_size: [u32; 2]
+    ) {
+        let Some((raytrace_pipeline, diffuse_pipeline, post_process_pipeline, _)) = self.pipeline.as_mut() else {return;};
+        raytrace_pipeline.resize(&self.context, image_size);
+        *diffuse_pipeline = DiffusePipeline::new(
+            &self.context,
+            image_size,
+            &self.command_buffer_allocator,
+            &self.descriptor_set_allocator
+        );
+        *post_process_pipeline = PostProcessPipeline::new(
+            &self.context,
+            image_size,
+            self.settings.tone_map,
+            self.settings.exposure,
+            &self.command_buffer_allocator,
+            &self.descriptor_set_allocator
+        );
+        // frame 0 clears the new accumulation image
+        self.frame = 0;
+    }
+
+    /// save the image to a file, exr files get the untonemapped hdr image and every other format the display image
+    pub fn save_image(
+        &self,
+        path: impl AsRef<Path>
+    ) -> image::ImageResult<()> {
+        let (_, diffuse_pipeline, post_process_pipeline, _) = self.pipeline.as_ref().unwrap();
+        let path = path.as_ref();
+        if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("exr")) {
+            save_hdr_image(&self.context, &self.command_buffer_allocator, diffuse_pipeline.image(), path)
+        } else {
+            save_image(&self.context, &self.command_buffer_allocator, post_process_pipeline.image(), path)
+        }
+    }
 }
 
 
@@ -168,7 +395,20 @@ pub fn compute_then_render<T: graphics::Position + BufferContents + Copy + Clone
 
     app.camera.do_move(frame_time);
 
-    let (raytrace_pipeline, diffuse_pipeline, render_pipeline) = app.pipeline.as_mut().unwrap();
+    let window_size = window_renderer.window_size().map(|size| size as u32);
+    if window_size != app.pipeline.as_ref().unwrap().0.image_size() {
+        app.resize(window_size);
+    }
+
+    // frames from the old view would smear into the new one
+    let view: ([f32; 4], [[f32; 4]; 4]) = (app.camera.position.extend().into(), get_view_matrix(&app.camera));
+    if let Some(last_view) = app.last_view.filter(|last_view| *last_view != view) {
+        app.camera_moved(last_view, view);
+    }
+    app.last_view = Some(view);
+
+    let window_renderer = app.windows.get_primary_renderer_mut().unwrap();
+    let (raytrace_pipeline, diffuse_pipeline, post_process_pipeline, render_pipeline) = app.pipeline.as_mut().unwrap();
 
     let before_pipeline_future = match window_renderer.acquire() {
         Err(e) => {
@@ -184,10 +424,13 @@ pub fn compute_then_render<T: graphics::Position + BufferContents + Copy + Clone
     let after_diffuse = diffuse_pipeline.next_frame(app.frame, raytrace_image, after_raytrace);
     let diffuse_image = diffuse_pipeline.image();
 
+    let after_post_process = post_process_pipeline.apply(diffuse_image, after_diffuse);
+    let display_image = post_process_pipeline.image();
+
     let target_image = window_renderer.swapchain_image_view();
 
     let after_render = render_pipeline
-        .render(after_diffuse, diffuse_image, target_image);
+        .render(after_post_process, display_image, target_image);
 
     window_renderer.present(after_render, true);
     app.frame += 1;
@@ -198,7 +441,7 @@ pub fn compute_n_then_render<T: graphics::Position + BufferContents + Copy + Clo
     num_renders: usize
 ) {
     let window_renderer = app.windows.get_primary_renderer_mut().unwrap();
-    let (raytrace_pipeline, diffuse_pipeline, render_pipeline) = app.pipeline.as_mut().unwrap();
+    let (raytrace_pipeline, diffuse_pipeline, post_process_pipeline, render_pipeline) = app.pipeline.as_mut().unwrap();
 
 
     let mut last_future = match window_renderer.acquire() {
@@ -218,10 +461,44 @@ pub fn compute_n_then_render<T: graphics::Position + BufferContents + Copy + Clo
 
     let diffuse_image = diffuse_pipeline.image();
 
+    let after_post_process = post_process_pipeline.apply(diffuse_image, last_future);
+    let display_image = post_process_pipeline.image();
+
     let target_image = window_renderer.swapchain_image_view();
 
     let after_render = render_pipeline
-        .render(last_future, diffuse_image, target_image);
+        .render(after_post_process, display_image, target_image);
 
     window_renderer.present(after_render, true);
-}
\ No newline at end of file
+}
+
+/// computes n frames without a window and saves the result, the app must have been opened with open_headless
+pub fn compute_n_then_save<T: graphics::Position + BufferContents + Copy + Clone>(
+    app: &mut RayTracingApp<T>,
+    num_renders: usize,
+    path: impl AsRef<Path>
+) -> image::ImageResult<()> {
+    let (raytrace_pipeline, diffuse_pipeline, post_process_pipeline, _) = app.pipeline.as_mut().unwrap();
+
+    for _ in 0..num_renders {
+        let before_future = sync::now(app.context.device().clone()).boxed();
+        let after_raytrace = raytrace_pipeline.compute(before_future, &app.camera, app.frame);
+        let raytrace_image = raytrace_pipeline.image();
+
+        // wait on every frame so that no more than one frame of work is queued on slow software devices
+        diffuse_pipeline.next_frame(app.frame, raytrace_image, after_raytrace)
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+        app.frame += 1;
+    }
+
+    post_process_pipeline.apply(diffuse_pipeline.image(), sync::now(app.context.device().clone()).boxed())
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    app.save_image(path)
+}
@@ -0,0 +1,685 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use graphics::{Camera, PositionVertex};
+use super::raytrace_pipeline::{raytrace_shader, SamplingMode, Projection};
+use super::raytracing_app::{RayTracerSettings, RayTracingApp};
+use super::post_process::ToneMapOperator;
+use super::environment::{Environment, EnvironmentMap, Sky};
+use super::obj::{load_obj, ObjFile, MtlMaterial};
+use super::textures::TextureAtlas;
+use super::cpu_tracer::CpuRayTracer;
+use super::materials::*;
+use super::objects::*;
+
+
+/// Errors produced while loading a scene file
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    MissingMeshFile(PathBuf),
+    MeshFile(PathBuf, std::io::Error),
+    EnvironmentMap(PathBuf, image::ImageError),
+    Texture(PathBuf, image::ImageError),
+    UnknownMaterial {object: String, material: String},
+    ObjectOutOfRange {file: String, object: usize, num_objects: usize},
+    Invalid(String),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(path, e) => write!(f, "could not read scene file {}: {e}", path.display()),
+            SceneError::Parse(path, e) => write!(f, "could not parse scene file {}: {e}", path.display()),
+            SceneError::MissingMeshFile(path) => write!(f, "mesh file {} does not exist", path.display()),
+            SceneError::MeshFile(path, e) => write!(f, "could not load mesh file {}: {e}", path.display()),
+            SceneError::EnvironmentMap(path, e) => write!(f, "could not load environment map {}: {e}", path.display()),
+            SceneError::Texture(path, e) => write!(f, "could not load texture {}: {e}", path.display()),
+            SceneError::UnknownMaterial {object, material} => write!(f, "{object} uses undefined material \"{material}\""),
+            SceneError::ObjectOutOfRange {file, object, num_objects} => write!(f, "{file} has {num_objects} objects, object {object} does not exist"),
+            SceneError::Invalid(msg) => write!(f, "invalid scene: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+
+/// A scene loaded from a file, ready to be turned into a raytracing app
+pub struct Scene {
+    pub camera: Camera,
+    pub settings: RayTracerSettings<PositionVertex>,
+}
+
+impl Scene {
+    /// load and validate a scene from a toml file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| SceneError::Io(path.to_path_buf(), e))?;
+        let file: SceneFile = toml::from_str(&text).map_err(|e| SceneError::Parse(path.to_path_buf(), e))?;
+        let autofocus = file.settings.focus_distance.is_none() && file.settings.aperture_radius > 0.0;
+        let mut scene = file.build(path.parent().unwrap_or(Path::new("")))?;
+        if autofocus {
+            scene.autofocus();
+        }
+        Ok(scene)
+    }
+
+    /// focuses the camera on whatever is in the centre of the image, found with a ray cast on the cpu.
+    /// the focus distance is left as it is when the centre of the image looks at the environment
+    pub fn autofocus(&mut self) -> Option<f32> {
+        let focus_distance = CpuRayTracer::new([1, 1], &self.settings).focus_distance(&self.camera)?;
+        self.settings.focus_distance = focus_distance;
+        Some(focus_distance)
+    }
+
+    /// create the raytracing app for this scene
+    pub fn into_app(self) -> RayTracingApp<PositionVertex> {
+        RayTracingApp::new(self.camera, self.settings)
+    }
+
+    /// create a raytracing app for this scene that renders without a window
+    pub fn into_headless_app(self) -> RayTracingApp<PositionVertex> {
+        RayTracingApp::new_headless(self.camera, self.settings)
+    }
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    camera: CameraDescription,
+    #[serde(default)]
+    settings: SettingsDescription,
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialDescription>,
+    #[serde(default)]
+    spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    meshes: Vec<MeshDescription>,
+    #[serde(default)]
+    instances: Vec<InstanceDescription>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDescription {
+    position: [f32; 3],
+    direction: [f32; 3],
+    speed: Option<f32>,
+    /// vertical, in degrees
+    #[serde(default = "default_fov")]
+    fov: f32,
+    /// fractions of the image width and height
+    #[serde(default)]
+    sensor_offset: [f32; 2],
+    /// perspective, orthographic, fisheye or equirectangular
+    #[serde(default = "default_projection")]
+    projection: String,
+    /// world units, only used by the orthographic projection
+    #[serde(default = "default_ortho_height")]
+    ortho_height: f32,
+}
+
+fn default_fov() -> f32 {
+    90.0
+}
+
+fn default_projection() -> String {
+    "perspective".to_string()
+}
+
+fn default_ortho_height() -> f32 {
+    2.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct SettingsDescription {
+    num_samples: u32,
+    max_bounces: u32,
+    sample_jitter: Option<f32>,
+    sampling: String,
+    /// none, gradient, map or sky
+    environment: String,
+    environment_map: Option<PathBuf>,
+    /// degrees around the up axis
+    environment_rotation: f32,
+    environment_intensity: f32,
+    sky: SkyDescription,
+    /// 0 for a pinhole camera
+    aperture_radius: f32,
+    /// the scene autofocuses on the centre of the image when this isn't given
+    focus_distance: Option<f32>,
+    tone_map: String,
+    white_point: Option<f32>,
+    exposure: f32,
+    /// reuse the accumulated image while the camera moves a little
+    temporal_reprojection: bool,
+}
+
+impl Default for SettingsDescription {
+    fn default() -> Self {
+        SettingsDescription {
+            num_samples: 10,
+            max_bounces: 50,
+            sample_jitter: None,
+            sampling: "mis".to_string(),
+            environment: "none".to_string(),
+            environment_map: None,
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
+            sky: SkyDescription::default(),
+            aperture_radius: 0.0,
+            focus_distance: None,
+            tone_map: "clamp".to_string(),
+            white_point: None,
+            exposure: 0.0,
+            temporal_reprojection: false,
+        }
+    }
+}
+
+impl SettingsDescription {
+    fn build_environment(&self, directory: &Path) -> Result<Environment, SceneError> {
+        if self.environment_map.is_some() && self.environment != "map" {
+            return Err(SceneError::Invalid("environment_map is only used with environment = \"map\"".to_string()));
+        }
+
+        match self.environment.as_str() {
+            "none" => Ok(Environment::None),
+            "gradient" => Ok(Environment::Gradient),
+            "map" => {
+                let path = self.environment_map.as_ref().ok_or(SceneError::Invalid("environment = \"map\" needs an environment_map".to_string()))?;
+                if self.environment_intensity < 0.0 {
+                    return Err(SceneError::Invalid("environment_intensity cannot be negative".to_string()));
+                }
+                let path = directory.join(path);
+                let map = EnvironmentMap::load(&path, self.environment_rotation, self.environment_intensity)
+                    .map_err(|e| SceneError::EnvironmentMap(path, e))?;
+                Ok(Environment::Map(map))
+            }
+            "sky" => {
+                let sky = &self.sky;
+                if !(0.0..=90.0).contains(&sky.sun_elevation) {
+                    return Err(SceneError::Invalid("sun_elevation must be between 0 and 90 degrees".to_string()));
+                }
+                if !(1.7..=10.0).contains(&sky.turbidity) {
+                    return Err(SceneError::Invalid("turbidity must be between 1.7 and 10".to_string()));
+                }
+                if sky.sky_intensity < 0.0 || sky.sun_intensity < 0.0 {
+                    return Err(SceneError::Invalid("sky_intensity and sun_intensity cannot be negative".to_string()));
+                }
+                Ok(Environment::Sky(Sky::new(sky.sun_elevation, sky.sun_azimuth, sky.turbidity, sky.sky_intensity, sky.sun_intensity)))
+            }
+            name => Err(SceneError::Invalid(format!("unknown environment {name}, expected one of none, gradient, map or sky")))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct SkyDescription {
+    /// degrees above the horizon
+    sun_elevation: f32,
+    /// degrees from +x towards +z
+    sun_azimuth: f32,
+    turbidity: f32,
+    sky_intensity: f32,
+    sun_intensity: f32,
+}
+
+impl Default for SkyDescription {
+    fn default() -> Self {
+        SkyDescription {
+            sun_elevation: 45.0,
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+            sky_intensity: 1.0,
+            sun_intensity: 10.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDescription {
+    Custom {
+        #[serde(default = "default_colour")]
+        colour: [f32; 3],
+        #[serde(default)]
+        emission_colour: [f32; 3],
+        #[serde(default)]
+        emission_strength: f32,
+        #[serde(default)]
+        smoothness: f32,
+        #[serde(default)]
+        fuzz: f32,
+        #[serde(default)]
+        specular_probability: f32,
+        #[serde(default)]
+        textures: TexturesDescription,
+    },
+    Lambertian {
+        colour: [f32; 3],
+        #[serde(default)]
+        textures: TexturesDescription,
+    },
+    Metal {
+        colour: [f32; 3],
+        smoothness: f32,
+        #[serde(default)]
+        fuzz: f32,
+        #[serde(default)]
+        textures: TexturesDescription,
+    },
+    Pbr {
+        #[serde(default = "default_colour")]
+        base_colour: [f32; 3],
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_roughness")]
+        roughness: f32,
+        #[serde(default = "default_specular")]
+        specular: f32,
+        #[serde(default)]
+        emission_colour: [f32; 3],
+        #[serde(default)]
+        emission_strength: f32,
+        #[serde(default)]
+        textures: TexturesDescription,
+    },
+    Dielectric {
+        #[serde(default = "default_tint")]
+        tint: [f32; 3],
+        index_of_refraction: f32,
+    },
+    Light {
+        emission: [f32; 4],
+    },
+    InvisLight {
+        emission: [f32; 4],
+    },
+}
+
+/// images multiplied into a material's values. albedo and emission images are read as srgb, the others as linear
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TexturesDescription {
+    albedo: Option<PathBuf>,
+    /// green scales roughness or smoothness and blue scales metallic, like gltf metallic roughness images
+    roughness: Option<PathBuf>,
+    emission: Option<PathBuf>,
+    /// tangent space with green pointing up the image, only used on meshes
+    normal: Option<PathBuf>,
+}
+
+impl TexturesDescription {
+    /// loads the images into the atlas, giving their indices in the order the shader expects.
+    /// relative paths are joined onto directory
+    fn load(&self, atlas: &mut TextureAtlas, directory: &Path) -> Result<[i32; 4], SceneError> {
+        let mut indices = [-1; 4];
+        for (index, path) in indices.iter_mut().zip([&self.albedo, &self.roughness, &self.emission, &self.normal]) {
+            if let Some(path) = path {
+                let path = directory.join(path);
+                *index = atlas.load(&path).map_err(|e| SceneError::Texture(path, e))?;
+            }
+        }
+        Ok(indices)
+    }
+}
+
+fn default_colour() -> [f32; 3] {
+    CustomMaterial::default().colour
+}
+
+fn default_roughness() -> f32 {
+    PbrMaterial::default().roughness
+}
+
+fn default_specular() -> f32 {
+    PbrMaterial::default().specular
+}
+
+fn default_tint() -> [f32; 3] {
+    [1.0; 3]
+}
+
+impl MaterialDescription {
+    fn validate(&self, name: &str) -> Result<(), SceneError> {
+        let (colour, factors): ([f32; 3], Vec<(&str, f32)>) = match self {
+            MaterialDescription::Custom {colour, smoothness, fuzz, specular_probability, ..} => {
+                (*colour, vec![("smoothness", *smoothness), ("fuzz", *fuzz), ("specular_probability", *specular_probability)])
+            }
+            MaterialDescription::Lambertian {colour, ..} => (*colour, Vec::new()),
+            MaterialDescription::Metal {colour, smoothness, fuzz, ..} => (*colour, vec![("smoothness", *smoothness), ("fuzz", *fuzz)]),
+            MaterialDescription::Pbr {base_colour, metallic, roughness, specular, ..} => {
+                (*base_colour, vec![("metallic", *metallic), ("roughness", *roughness), ("specular", *specular)])
+            }
+            MaterialDescription::Dielectric {tint, index_of_refraction} => {
+                if *index_of_refraction <= 0.0 {
+                    return Err(SceneError::Invalid(format!("material \"{name}\" has index_of_refraction {index_of_refraction}, expected a positive value")));
+                }
+                (*tint, Vec::new())
+            }
+            MaterialDescription::Light {..} | MaterialDescription::InvisLight {..} => ([0.0; 3], Vec::new()),
+        };
+        if colour.iter().any(|c| *c < 0.0) {
+            return Err(SceneError::Invalid(format!("material \"{name}\" has a negative colour")));
+        }
+        for (field, value) in factors {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(SceneError::Invalid(format!("material \"{name}\" has {field} {value}, expected a value between 0 and 1")));
+            }
+        }
+
+        // only textures for values the material has
+        if let Some(textures) = self.textures() {
+            let has_roughness = !matches!(self, MaterialDescription::Lambertian {..});
+            let has_emission = matches!(self, MaterialDescription::Custom {..} | MaterialDescription::Pbr {..});
+            for (texture, allowed, field) in [(&textures.roughness, has_roughness, "roughness"), (&textures.emission, has_emission, "emission")] {
+                if texture.is_some() && !allowed {
+                    return Err(SceneError::Invalid(format!("material \"{name}\" cannot have a {field} texture")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn textures(&self) -> Option<&TexturesDescription> {
+        match self {
+            MaterialDescription::Custom {textures, ..} | MaterialDescription::Lambertian {textures, ..}
+            | MaterialDescription::Metal {textures, ..} | MaterialDescription::Pbr {textures, ..} => Some(textures),
+            _ => None,
+        }
+    }
+
+    /// the closest material to one from an mtl file, assuming blender's export of its principled bsdf.
+    /// transparent illumination models and see through materials become dielectrics and the rest custom materials,
+    /// with illumination models 0 and 1 diffuse only and 3 and up mirror like
+    fn from_mtl(mtl: &MtlMaterial) -> Self {
+        let clamp_colour = |colour: [f32; 3]| colour.map(|c| c.clamp(0.0, 1.0));
+        let colour = clamp_colour(mtl.diffuse.unwrap_or(default_colour()));
+        let illum = mtl.illum.unwrap_or(2);
+
+        if matches!(illum, 4 | 6 | 7 | 9) || mtl.dissolve.is_some_and(|dissolve| dissolve < 1.0) {
+            return MaterialDescription::Dielectric {
+                tint: clamp_colour(mtl.transmission.unwrap_or(colour)),
+                index_of_refraction: mtl.index_of_refraction.filter(|ior| *ior > 0.0).unwrap_or(1.5),
+            };
+        }
+
+        // blender writes the exponent as (1 - roughness)^2 * 1000
+        let smoothness = (mtl.specular_exponent.unwrap_or(0.0) / 1000.0).clamp(0.0, 1.0).sqrt();
+        let specular = mtl.specular.map_or(0.0, |colour| colour.into_iter().fold(0.0, f32::max)).clamp(0.0, 1.0);
+        let specular_probability = match illum {
+            0 | 1 => 0.0,
+            2 => specular,
+            _ => 1.0,
+        };
+        // the emission colour is stored with its strength multiplied in
+        let emission = mtl.emission.unwrap_or([0.0; 3]).map(|c| c.max(0.0));
+        let emission_strength = emission.into_iter().fold(0.0, f32::max);
+
+        MaterialDescription::Custom {
+            colour,
+            emission_colour: if emission_strength > 0.0 {emission.map(|c| c / emission_strength)} else {[0.0; 3]},
+            emission_strength,
+            smoothness,
+            fuzz: 0.0,
+            specular_probability,
+            textures: TexturesDescription {
+                albedo: mtl.diffuse_map.clone(),
+                roughness: None,
+                emission: mtl.emission_map.clone(),
+                normal: mtl.normal_map.clone(),
+            },
+        }
+    }
+
+    /// builds the material, loading its textures into the atlas from paths relative to directory
+    fn build(&self, atlas: &mut TextureAtlas, directory: &Path) -> Result<raytrace_shader::RayTracingMaterial, SceneError> {
+        let mut material: raytrace_shader::RayTracingMaterial = match *self {
+            MaterialDescription::Custom {colour, emission_colour, emission_strength, smoothness, fuzz, specular_probability, ..} => CustomMaterial {
+                colour,
+                emission_colour,
+                emission_strength,
+                smoothness,
+                fuzz,
+                specular_probability,
+            }.into(),
+            MaterialDescription::Lambertian {colour, ..} => LambertianMaterial {colour}.into(),
+            MaterialDescription::Metal {colour, smoothness, fuzz, ..} => MetalMaterial {colour, smoothness, fuzz}.into(),
+            MaterialDescription::Pbr {base_colour, metallic, roughness, specular, emission_colour, emission_strength, ..} => PbrMaterial {
+                base_colour,
+                metallic,
+                roughness,
+                specular,
+                emission_colour,
+                emission_strength,
+            }.into(),
+            MaterialDescription::Dielectric {tint, index_of_refraction} => DielectricMaterial {tint, index_of_refraction}.into(),
+            MaterialDescription::Light {emission} => LightMaterial {emission}.into(),
+            MaterialDescription::InvisLight {emission} => InvisLightMaterial {emission}.into(),
+        };
+
+        if let Some(textures) = self.textures() {
+            material.textures = textures.load(atlas, directory)?;
+        }
+        Ok(material)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereDescription {
+    centre: [f32; 3],
+    radius: f32,
+    material: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDescription {
+    file: String,
+    #[serde(default)]
+    object: usize,
+    /// used for every face of the object, otherwise each face uses its material from the obj file's mtl
+    material: Option<String>,
+    #[serde(default)]
+    translation: [f32; 3],
+    /// degrees around the x, y and z axes
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default = "default_scale")]
+    scale: [f32; 3],
+}
+
+/// another copy of one of the scene's meshes that shares its triangles
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstanceDescription {
+    /// index into the scene's meshes
+    mesh: usize,
+    /// used for every face instead of the materials of the copied mesh
+    material: Option<String>,
+    #[serde(default)]
+    translation: [f32; 3],
+    /// degrees around the x, y and z axes
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default = "default_scale")]
+    scale: [f32; 3],
+}
+
+fn default_scale() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn build_transform(
+    object: String,
+    translation: [f32; 3],
+    rotation: [f32; 3],
+    scale: [f32; 3],
+) -> Result<Transform, SceneError> {
+    if scale.iter().any(|value| *value <= 0.0) {
+        return Err(SceneError::Invalid(format!("{object} has a scale of {scale:?}, every component must be positive")));
+    }
+    Ok(Transform {translation, rotation, scale})
+}
+
+
+impl SceneFile {
+    /// relative asset paths are joined onto directory, the one the scene file is in
+    fn build(self, directory: &Path) -> Result<Scene, SceneError> {
+        if self.settings.num_samples == 0 {
+            return Err(SceneError::Invalid("num_samples must be at least 1".to_string()));
+        }
+        let projection: Projection = self.camera.projection.parse().map_err(SceneError::Invalid)?;
+        projection.check_fov(self.camera.fov).map_err(SceneError::Invalid)?;
+        if self.camera.ortho_height <= 0.0 {
+            return Err(SceneError::Invalid("ortho_height must be positive".to_string()));
+        }
+        if self.settings.aperture_radius < 0.0 {
+            return Err(SceneError::Invalid("aperture_radius cannot be negative".to_string()));
+        }
+        if self.settings.focus_distance.map_or(false, |distance| distance <= 0.0) {
+            return Err(SceneError::Invalid("focus_distance must be positive".to_string()));
+        }
+        let mut tone_map: ToneMapOperator = self.settings.tone_map.parse().map_err(SceneError::Invalid)?;
+        match (&mut tone_map, self.settings.white_point) {
+            (_, Some(value)) if value <= 0.0 => return Err(SceneError::Invalid("white_point must be positive".to_string())),
+            (ToneMapOperator::ExtendedReinhard {white_point}, Some(value)) => *white_point = value,
+            (_, Some(_)) => return Err(SceneError::Invalid("white_point is only used by extended_reinhard".to_string())),
+            (_, None) => {}
+        }
+        let sampling_mode: SamplingMode = self.settings.sampling.parse().map_err(SceneError::Invalid)?;
+        let environment = self.settings.build_environment(directory)?;
+        if self.camera.direction == [0.0; 3] {
+            return Err(SceneError::Invalid("camera direction cannot be zero".to_string()));
+        }
+
+        let mut textures = TextureAtlas::default();
+        let mut materials = BTreeMap::new();
+        for (name, material) in self.materials.iter() {
+            material.validate(name)?;
+            materials.insert(name, material.build(&mut textures, directory)?);
+        }
+        let find_material = |object: String, name: &String| {
+            match materials.get(name) {
+                Some(material) => Ok(material.clone()),
+                None => Err(SceneError::UnknownMaterial {object, material: name.clone()})
+            }
+        };
+
+        let mut sphere_data = Vec::new();
+        for (i, sphere) in self.spheres.iter().enumerate() {
+            if sphere.radius <= 0.0 {
+                return Err(SceneError::Invalid(format!("sphere {i} has a radius of {}", sphere.radius)));
+            }
+            sphere_data.push(Sphere {
+                centre: sphere.centre,
+                radius: sphere.radius,
+                material: find_material(format!("sphere {i}"), &sphere.material)?
+            });
+        }
+
+        let mut obj_files: BTreeMap<&str, ObjFile> = BTreeMap::new();
+        // mtl materials are only built once something uses them, so unused ones can't stop a scene loading
+        let mut mtl_materials: BTreeMap<(&str, String), raytrace_shader::RayTracingMaterial> = BTreeMap::new();
+        let mut mesh_data = Vec::new();
+        // the mesh_data made from each scene mesh, which instances copy
+        let mut mesh_parts = Vec::new();
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            let transform = build_transform(format!("mesh {i}"), mesh.translation, mesh.rotation, mesh.scale)?;
+            if !obj_files.contains_key(mesh.file.as_str()) {
+                let mesh_path = directory.join(&mesh.file);
+                if !mesh_path.is_file() {
+                    return Err(SceneError::MissingMeshFile(mesh_path));
+                }
+                let obj_file = load_obj(&mesh_path).map_err(|e| SceneError::MeshFile(mesh_path, e))?;
+                obj_files.insert(&mesh.file, obj_file);
+            }
+            let obj_file = &obj_files[mesh.file.as_str()];
+            let object = obj_file.objects.get(mesh.object).ok_or(SceneError::ObjectOutOfRange {
+                file: mesh.file.clone(),
+                object: mesh.object,
+                num_objects: obj_file.objects.len()
+            })?;
+
+            // a mesh is made for each material the object uses
+            let first_part = mesh_data.len();
+            for part in object.parts.iter() {
+                let material = match (&mesh.material, &part.material) {
+                    (Some(name), _) => find_material(format!("mesh {i}"), name)?,
+                    (None, Some(name)) => match mtl_materials.get(&(mesh.file.as_str(), name.clone())) {
+                        Some(material) => material.clone(),
+                        None => {
+                            let mtl = obj_file.materials.get(name).ok_or(SceneError::UnknownMaterial {
+                                object: format!("mesh {i}"),
+                                material: name.clone()
+                            })?;
+                            let description = MaterialDescription::from_mtl(mtl);
+                            description.validate(name)?;
+                            // texture paths from the mtl file are already joined onto its directory
+                            let material = description.build(&mut textures, Path::new(""))?;
+                            mtl_materials.insert((mesh.file.as_str(), name.clone()), material.clone());
+                            material
+                        }
+                    },
+                    (None, None) => return Err(SceneError::Invalid(format!("mesh {i} needs a material, either in the scene or from usemtl in {}", mesh.file))),
+                };
+
+                mesh_data.push(RayTracingMesh {
+                    mesh: part.mesh.clone(),
+                    uvs: part.uvs.clone(),
+                    material,
+                    transform,
+                });
+            }
+            mesh_parts.push(first_part..mesh_data.len());
+        }
+
+        let mut instances = Vec::new();
+        for (i, instance) in self.instances.iter().enumerate() {
+            let transform = build_transform(format!("instance {i}"), instance.translation, instance.rotation, instance.scale)?;
+            let parts = mesh_parts.get(instance.mesh).ok_or(SceneError::Invalid(format!(
+                "instance {i} copies mesh {}, but the scene only has {} meshes", instance.mesh, mesh_parts.len()
+            )))?;
+            let material = match &instance.material {
+                Some(name) => Some(find_material(format!("instance {i}"), name)?),
+                None => None,
+            };
+            instances.extend(parts.clone().map(|mesh| MeshInstance {mesh, transform, material: material.clone()}));
+        }
+
+        let camera = Camera::new(Some(self.camera.position), Some(self.camera.direction), self.camera.speed, None);
+        Ok(Scene {
+            camera,
+            settings: RayTracerSettings {
+                num_samples: self.settings.num_samples,
+                max_bounces: self.settings.max_bounces,
+                sample_jitter: self.settings.sample_jitter,
+                sampling_mode,
+                environment,
+                sphere_data,
+                mesh_data,
+                instances,
+                textures,
+                fov: self.camera.fov,
+                sensor_offset: self.camera.sensor_offset,
+                projection,
+                ortho_height: self.camera.ortho_height,
+                aperture_radius: self.settings.aperture_radius,
+                // replaced by autofocusing when the scene is loaded
+                focus_distance: self.settings.focus_distance.unwrap_or(1.0),
+                tone_map,
+                exposure: self.settings.exposure,
+                temporal_reprojection: self.settings.temporal_reprojection,
+            }
+        })
+    }
+}
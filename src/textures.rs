@@ -0,0 +1,50 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use super::raytrace_pipeline::raytrace_shader;
+
+
+/// Every image used by the scene's materials packed one after another into a single buffer of rgba8 texels.
+/// Materials refer to textures by their index, each image is only loaded once however many materials use it
+#[derive(Clone, Default)]
+pub struct TextureAtlas {
+    pub textures: Vec<raytrace_shader::Texture>,
+    /// texels row by row from the top of each image, red in the lowest byte
+    pub texels: Vec<u32>,
+    paths: Vec<PathBuf>,
+}
+
+impl fmt::Debug for TextureAtlas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TextureAtlas")
+            .field("paths", &self.paths)
+            .field("num_texels", &self.texels.len())
+            .finish()
+    }
+}
+
+impl TextureAtlas {
+    /// loads an image into the atlas and returns its index, or the index it already has
+    pub fn load(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> image::ImageResult<i32> {
+        let path = path.as_ref();
+        if let Some(index) = self.paths.iter().position(|loaded| loaded == path) {
+            return Ok(index as i32);
+        }
+
+        let image = image::open(path)?.into_rgba8();
+        self.textures.push(raytrace_shader::Texture {
+            offset: self.texels.len() as u32,
+            width: image.width(),
+            height: image.height(),
+        });
+        self.texels.extend(image.pixels().map(|pixel| u32::from_le_bytes(pixel.0)));
+        self.paths.push(path.to_path_buf());
+        Ok(self.textures.len() as i32 - 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+}